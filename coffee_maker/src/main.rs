@@ -10,6 +10,7 @@ use tracing_subscriber::FmtSubscriber;
 
 const DISPENSERS: usize = 3;
 const DEFAULT_ORDERS: &str = "../assets/orders.csv";
+const STRICT_FLAG: &str = "--strict";
 
 enum Arguments {
     LocalServer = 1,
@@ -20,21 +21,34 @@ enum Arguments {
 // Result with any error
 type Res = Result<(), Box<dyn std::error::Error>>;
 
-fn parse_args() -> (String, String, f64) {
-    let args: Vec<String> = std::env::args().collect();
+/// Parses the positional arguments and the optional `--strict` flag,
+/// which stops taking orders at the first invalid line of the orders file.
+fn parse_args() -> (String, String, f64, OnParseError) {
+    let all_args: Vec<String> = std::env::args().collect();
+    let on_parse_error = if all_args.iter().any(|arg| arg == STRICT_FLAG) {
+        OnParseError::Abort
+    } else {
+        OnParseError::Skip
+    };
+    let args: Vec<String> = all_args
+        .into_iter()
+        .filter(|arg| arg != STRICT_FLAG)
+        .collect();
+
     if args.len() == 2 {
         return (
             parse_addr(args[Arguments::LocalServer as usize].clone()),
             DEFAULT_ORDERS.to_string(),
             DEFAULT_SUCCESS_CHANCE,
+            on_parse_error,
         );
     }
-    let args: Vec<String> = std::env::args().collect();
     if args.len() == 3 {
         return (
             parse_addr(args[Arguments::LocalServer as usize].clone()),
             args[Arguments::Orders as usize].clone(),
             DEFAULT_SUCCESS_CHANCE,
+            on_parse_error,
         );
     }
     if args.len() == 4 {
@@ -44,9 +58,10 @@ fn parse_args() -> (String, String, f64) {
             args[Arguments::SuccessChance as usize]
                 .parse::<f64>()
                 .unwrap(),
+            on_parse_error,
         );
     }
-    error!("Usage: coffee_maker <local_server> [<orders>] [<success_chance>]");
+    error!("Usage: coffee_maker <local_server> [<orders>] [<success_chance>] [--strict]");
     exit(-1);
 }
 
@@ -58,7 +73,7 @@ async fn main() -> Res {
 
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
 
-    let (local_server_addr, orders_path, success_chance) = parse_args();
+    let (local_server_addr, orders_path, success_chance, on_parse_error) = parse_args();
 
     let point_storage = SyncArbiter::start(1, move || {
         PointStorage::new(local_server_addr.clone()).unwrap()
@@ -72,6 +87,7 @@ async fn main() -> Res {
     let order_handler_clone = order_handler.clone();
     let order_taker = SyncArbiter::start(1, move || OrderTaker {
         handler: order_handler_clone.clone(),
+        on_parse_error,
     });
 
     order_taker.send(TakeOrders(orders_path)).await?;
@@ -2,7 +2,7 @@ use std::sync::{Arc, Barrier};
 
 use actix::prelude::*;
 
-use super::Order;
+use super::{Balance, BalanceRead, Order, OrderError, PointResponse};
 
 // Order Taker
 type FilePath = String;
@@ -12,7 +12,7 @@ pub struct TakeOrders(pub FilePath);
 
 // Order Handler
 #[derive(Message)]
-#[rtype(result = "Result<(),String>")]
+#[rtype(result = "Result<(),OrderError>")]
 pub struct HandleOrder(pub Order);
 
 #[derive(Message)]
@@ -20,14 +20,20 @@ pub struct HandleOrder(pub Order);
 pub struct WaitStop(pub Option<Arc<Barrier>>);
 
 // Point Storage
+/// Locks the points of an order.
+/// Returns the reservation needed to free or commit them, if the order uses points.
 #[derive(Message)]
-#[rtype(result = "Result<(),String>")]
+#[rtype(result = "Result<Option<u64>,PointResponse>")]
 pub struct LockOrder(pub Order);
 
 #[derive(Message)]
-#[rtype(result = "Result<(),String>")]
+#[rtype(result = "Result<(),PointResponse>")]
 pub struct FreeOrder(pub Order);
 
 #[derive(Message)]
-#[rtype(result = "Result<(),String>")]
+#[rtype(result = "Result<(),PointResponse>")]
 pub struct CommitOrder(pub Order);
+
+#[derive(Message)]
+#[rtype(result = "Result<Balance,PointResponse>")]
+pub struct QueryBalance(pub u64, pub BalanceRead);
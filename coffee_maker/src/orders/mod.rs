@@ -1,10 +1,12 @@
 mod messages;
+mod order_error;
 mod order_handler;
 mod order_taker;
 mod point_storage;
 
 pub use messages::*;
+pub use order_error::*;
 pub use order_handler::*;
 pub use order_taker::*;
 pub use point_storage::*;
-pub use points::{Message as PointMessage, Order, OrderAction};
+pub use points::{Balance, BalanceRead, Message as PointMessage, Order, Response as PointResponse};
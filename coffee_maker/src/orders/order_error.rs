@@ -0,0 +1,30 @@
+use std::fmt;
+
+use super::PointResponse;
+
+/// Reason why an order could not be completed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OrderError {
+    /// The local server rejected one of the points operations.
+    Rejected(PointResponse),
+    /// The coffee maker failed to prepare the coffee.
+    PreparationFailed,
+    /// The point storage actor could not be reached.
+    Mailbox,
+}
+
+impl fmt::Display for OrderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OrderError::Rejected(response) => write!(f, "{}", response),
+            OrderError::PreparationFailed => write!(f, "Order failed"),
+            OrderError::Mailbox => write!(f, "MailboxError"),
+        }
+    }
+}
+
+impl From<PointResponse> for OrderError {
+    fn from(response: PointResponse) -> Self {
+        OrderError::Rejected(response)
+    }
+}
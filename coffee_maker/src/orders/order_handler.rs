@@ -19,51 +19,63 @@ impl Actor for OrderHandler {
 }
 
 impl OrderHandler {
-    fn process_order(&self) -> Result<(), String> {
+    fn process_order(&self) -> Result<(), OrderError> {
         thread::sleep(Duration::from_millis(ORDER_MILLIS));
         let success = rand::thread_rng().gen_bool(self.success_chance);
         match success {
             true => Ok(()),
-            false => Err(String::from("Order failed")),
+            false => Err(OrderError::PreparationFailed),
         }
     }
 
-    async fn lock_points(&self, order: Order) -> Result<(), String> {
-        self.point_storage
-            .send(LockOrder(order))
+    /// Locks the points of the order.
+    /// Returns the order referencing the reservation of its points, if it got one.
+    async fn lock_points(&self, order: Order) -> Result<Order, OrderError> {
+        let reservation = self
+            .point_storage
+            .send(LockOrder(order.clone()))
             .await
-            .map_err(|_| "MailboxError")??;
-        Ok(())
+            .map_err(|_| OrderError::Mailbox)??;
+        match reservation {
+            Some(reservation) => Ok(order.with_reservation(reservation)),
+            None => Ok(order),
+        }
     }
 
-    async fn free_points(&self, order: Order) -> Result<(), String> {
+    async fn free_points(&self, order: Order) -> Result<(), OrderError> {
         self.point_storage
             .send(FreeOrder(order))
             .await
-            .map_err(|_| "MailboxError")??;
+            .map_err(|_| OrderError::Mailbox)??;
         Ok(())
     }
 
-    async fn commit_points(&self, order: Order) -> Result<(), String> {
+    async fn commit_points(&self, order: Order) -> Result<(), OrderError> {
         self.point_storage
             .send(CommitOrder(order))
             .await
-            .or(Err("MailboxError"))??;
+            .map_err(|_| OrderError::Mailbox)??;
         Ok(())
     }
 
-    async fn handle_order(&mut self, order: Order) -> Result<(), String> {
-        self.lock_points(order.clone()).await.map_err(|e| {
-            warn!("Failed to Lock {:?}", order);
-            e
+    /// Handles an order: locks the points, prepares the coffee and then
+    /// commits or frees the points depending on the outcome.
+    /// The error tells why the order could not be completed.
+    async fn handle_order(&mut self, order: Order) -> Result<(), OrderError> {
+        let order = self.lock_points(order.clone()).await.inspect_err(|e| {
+            warn!(cause = ?e, "Failed to Lock {:?}: {}", order, e);
         })?;
 
-        if self.process_order().is_err() {
-            warn!("Failed {:?}", order);
-            self.free_points(order).await?;
-            Err(String::from("Order failed"))
+        if let Err(e) = self.process_order() {
+            warn!(cause = ?e, "Failed {:?}", order);
+            self.free_points(order.clone()).await.inspect_err(|e| {
+                warn!(cause = ?e, "Failed to Free {:?}: {}", order, e);
+            })?;
+            Err(e)
         } else {
-            self.commit_points(order.clone()).await?;
+            self.commit_points(order.clone()).await.inspect_err(|e| {
+                warn!(cause = ?e, "Failed to Commit {:?}: {}", order, e);
+            })?;
             info!("Succeeded {:?}", order);
             Ok(())
         }
@@ -71,7 +83,7 @@ impl OrderHandler {
 }
 
 impl Handler<HandleOrder> for OrderHandler {
-    type Result = Result<(), String>;
+    type Result = Result<(), OrderError>;
 
     fn handle(&mut self, msg: HandleOrder, _ctx: &mut SyncContext<Self>) -> Self::Result {
         let order = msg.0;
@@ -1,16 +1,22 @@
-use std::{
-    fs::File,
-    io::{BufRead, BufReader},
-    thread,
-    time::Duration,
-};
+use std::{fs::File, io::BufReader, thread, time::Duration};
 
 use super::*;
 use actix::prelude::*;
-use tracing::info;
+use points::{OrderReader, ParseError};
+use tracing::{error, info, warn};
+
+/// What to do when a line of the orders file can not be parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnParseError {
+    /// Report the line and keep taking orders.
+    Skip,
+    /// Stop taking orders.
+    Abort,
+}
 
 pub struct OrderTaker {
     pub handler: Addr<OrderHandler>,
+    pub on_parse_error: OnParseError,
 }
 
 impl Actor for OrderTaker {
@@ -22,17 +28,46 @@ impl Handler<TakeOrders> for OrderTaker {
 
     fn handle(&mut self, msg: TakeOrders, _ctx: &mut SyncContext<Self>) -> Self::Result {
         let file_path = msg.0;
-        let file = File::open(file_path).unwrap();
-        let reader = BufReader::new(file);
-
-        for line in reader.lines() {
-            if let Ok(order) = Order::parse(line.unwrap()) {
-                info!("Order taken: {:?}", order);
-                self.handler.do_send(HandleOrder(order));
-                thread::sleep(Duration::from_secs(1));
+        let file = match File::open(&file_path) {
+            Ok(file) => file,
+            Err(e) => {
+                error!("Could not open orders file {}: {}", file_path, e);
+                return;
+            }
+        };
+        let reader = OrderReader::new(BufReader::new(file));
+
+        let mut taken = 0;
+        let mut rejected: Vec<ParseError> = vec![];
+
+        for order in reader {
+            match order {
+                Ok(order) => {
+                    // The id lets the servers recognize the order if a message is retried
+                    let order = order.with_id(rand::random());
+                    info!("Order taken: {:?}", order);
+                    self.handler.do_send(HandleOrder(order));
+                    taken += 1;
+                    thread::sleep(Duration::from_secs(1));
+                }
+                Err(e) => {
+                    warn!("Rejected line {}: {}", file_path, e);
+                    rejected.push(e);
+                    if self.on_parse_error == OnParseError::Abort {
+                        error!("Aborting, invalid orders file {}", file_path);
+                        break;
+                    }
+                }
             }
         }
 
-        info!("Done taking orders");
+        info!(
+            "Done taking orders: {} taken, {} rejected",
+            taken,
+            rejected.len()
+        );
+        for e in rejected {
+            warn!("  line {}, column {}: {}", e.line, e.column, e.kind);
+        }
     }
 }
@@ -1,17 +1,20 @@
-use std::{
-    io::{Read, Write},
-    net::TcpStream,
-    time::Duration,
-};
+use std::{io::Write, net::TcpStream, time::Duration};
 
 use super::*;
 use actix::prelude::*;
-use points::{CLIENT_CONNECTION, MESSAGE_BUFFER_SIZE};
+use points::{DecodeError, CLIENT_CONNECTION};
+use tracing::{error, warn};
 
 const READ_TIMEOUT: u64 = 1000;
+/// Times a message is sent again when the local server does not answer it.
+const MAX_RETRIES: usize = 3;
 
 pub struct PointStorage {
+    local_server_addr: String,
     local_server: TcpStream,
+    /// Id sent to the local server on every connection,
+    /// so it keeps the points locked by this coffee maker if the connection drops.
+    machine_id: u64,
 }
 
 impl Actor for PointStorage {
@@ -20,6 +23,16 @@ impl Actor for PointStorage {
 
 impl PointStorage {
     pub fn new(local_server_addr: String) -> Result<Self, String> {
+        let machine_id = rand::random();
+        let local_server = Self::connect(&local_server_addr, machine_id)?;
+        Ok(PointStorage {
+            local_server_addr,
+            local_server,
+            machine_id,
+        })
+    }
+
+    fn connect(local_server_addr: &str, machine_id: u64) -> Result<TcpStream, String> {
         let mut local_server =
             TcpStream::connect(local_server_addr).or(Err("Could not connect to local server"))?;
 
@@ -31,58 +44,116 @@ impl PointStorage {
             .write_all(&[CLIENT_CONNECTION])
             .map_err(|_| "Could not write to local server")?;
 
-        Ok(PointStorage { local_server })
+        local_server
+            .write_all(&PointMessage::Identify(machine_id).to_frame())
+            .map_err(|_| "Could not write to local server")?;
+        PointResponse::read_from(&mut local_server)
+            .map_err(|e| format!("Local server did not accept the machine id: {}", e))?;
+
+        Ok(local_server)
     }
 
-    fn write(&mut self, buf: [u8; MESSAGE_BUFFER_SIZE]) -> Result<(), String> {
+    /// Replaces the connection with the local server,
+    /// so a late response to a previous message is never read.
+    fn reconnect(&mut self) -> Result<(), String> {
+        self.local_server = Self::connect(&self.local_server_addr, self.machine_id)?;
+        Ok(())
+    }
+
+    fn write(&mut self, buf: &[u8]) -> Result<(), String> {
         self.local_server
-            .write_all(&buf)
+            .write_all(buf)
             .or(Err("Could not write to local server"))?;
         Ok(())
     }
 
-    fn read(&mut self) -> Result<u8, String> {
-        let mut buf: [u8; 1] = [0];
-        self.local_server
-            .read_exact(&mut buf)
-            .map_err(|_| "Could not read from local server")?;
-        Ok(buf[0])
+    fn read(&mut self) -> Result<PointResponse, DecodeError> {
+        PointResponse::read_from(&mut self.local_server)
     }
 
-    fn send(&mut self, msg: PointMessage) -> Result<(), String> {
-        self.write(msg.into())?;
-        let res = self.read()?;
-        if res == 0 {
-            Err("Local server returned error".to_string())
+    /// Sends a message to the local server and waits for its response.
+    /// The error is the reason reported by the server, or `Offline`/`Timeout`
+    /// if the server could not be reached.
+    /// Messages that are safe to repeat (orders with an id and balance queries)
+    /// are sent again over a new connection when the server does not answer.
+    fn send(&mut self, msg: PointMessage) -> Result<PointResponse, PointResponse> {
+        let can_retry = msg.order_id().is_some() || matches!(msg, PointMessage::QueryBalance(_, _));
+        let mut retries = 0;
+
+        loop {
+            match self.send_once(&msg) {
+                Err(e @ (PointResponse::Timeout | PointResponse::Offline))
+                    if can_retry && retries < MAX_RETRIES =>
+                {
+                    retries += 1;
+                    warn!("{}, retrying {:?} ({}/{})", e, msg, retries, MAX_RETRIES);
+                    if let Err(e) = self.reconnect() {
+                        error!("{}", e);
+                    }
+                }
+                res => return res,
+            }
+        }
+    }
+
+    fn send_once(&mut self, msg: &PointMessage) -> Result<PointResponse, PointResponse> {
+        self.write(&msg.to_frame()).map_err(|e| {
+            error!("{}", e);
+            PointResponse::Offline
+        })?;
+        let res = self.read().map_err(|e| {
+            error!("Could not read from local server: {}", e);
+            match e {
+                DecodeError::Io(_) => PointResponse::Timeout,
+                _ => PointResponse::Malformed,
+            }
+        })?;
+        if res.is_ok() {
+            Ok(res)
         } else {
-            Ok(())
+            Err(res)
         }
     }
 }
 
 impl Handler<LockOrder> for PointStorage {
-    type Result = Result<(), String>;
+    type Result = Result<Option<u64>, PointResponse>;
 
     fn handle(&mut self, msg: LockOrder, _ctx: &mut SyncContext<Self>) -> Self::Result {
         let msg = PointMessage::LockOrder(msg.0);
-        self.send(msg)
+        match self.send(msg)? {
+            PointResponse::Reserved(reservation) => Ok(Some(reservation)),
+            _ => Ok(None),
+        }
     }
 }
 
 impl Handler<FreeOrder> for PointStorage {
-    type Result = Result<(), String>;
+    type Result = Result<(), PointResponse>;
 
     fn handle(&mut self, msg: FreeOrder, _ctx: &mut SyncContext<Self>) -> Self::Result {
         let msg = PointMessage::FreeOrder(msg.0);
-        self.send(msg)
+        self.send(msg).map(|_| ())
     }
 }
 
 impl Handler<CommitOrder> for PointStorage {
-    type Result = Result<(), String>;
+    type Result = Result<(), PointResponse>;
 
     fn handle(&mut self, msg: CommitOrder, _ctx: &mut SyncContext<Self>) -> Self::Result {
         let msg = PointMessage::CommitOrder(msg.0);
-        self.send(msg)
+        self.send(msg).map(|_| ())
+    }
+}
+
+impl Handler<QueryBalance> for PointStorage {
+    type Result = Result<Balance, PointResponse>;
+
+    fn handle(&mut self, msg: QueryBalance, _ctx: &mut SyncContext<Self>) -> Self::Result {
+        let msg = PointMessage::QueryBalance(msg.0, msg.1);
+        match self.send(msg)? {
+            PointResponse::Balance(balance) => Ok(balance),
+            _ => Err(PointResponse::Malformed),
+        }
     }
 }
@@ -0,0 +1,30 @@
+/// Points held by an account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Balance {
+    pub available: u64,
+    pub locked: u64,
+}
+
+/// Where a balance query is answered from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BalanceRead {
+    /// Read the balance stored by the local server.
+    Local,
+    /// Read the balance agreed upon by a majority of the servers.
+    Quorum,
+}
+
+impl From<BalanceRead> for u8 {
+    fn from(read: BalanceRead) -> Self {
+        match read {
+            BalanceRead::Local => 1,
+            BalanceRead::Quorum => 2,
+        }
+    }
+}
+
+impl Balance {
+    pub fn new(available: u64, locked: u64) -> Self {
+        Balance { available, locked }
+    }
+}
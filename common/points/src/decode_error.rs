@@ -0,0 +1,58 @@
+use std::{fmt, io};
+
+/// Error found while reading or decoding protocol bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The underlying reader failed, e.g. the connection was closed.
+    Io(io::ErrorKind),
+    /// The first byte is neither a known frame version nor a legacy message type.
+    UnknownVersion(u8),
+    /// The frame announces a payload longer than `MAX_FRAME_SIZE`.
+    FrameTooLong(u32),
+    /// The payload ended before every field could be read.
+    Truncated,
+    /// The payload has bytes left after the last field of the message.
+    TrailingBytes,
+    UnknownMessageType(u8),
+    UnknownAction(u8),
+    UnknownReadMode(u8),
+    UnknownResponse(u8),
+    /// An optional order field with an unknown tag.
+    UnknownOrderField(u8),
+}
+
+impl DecodeError {
+    /// Returns true if the whole frame was consumed before failing,
+    /// so the next frame can still be read from the same stream.
+    pub fn is_recoverable(&self) -> bool {
+        !matches!(
+            self,
+            DecodeError::Io(_) | DecodeError::UnknownVersion(_) | DecodeError::FrameTooLong(_)
+        )
+    }
+}
+
+impl From<io::Error> for DecodeError {
+    fn from(error: io::Error) -> Self {
+        DecodeError::Io(error.kind())
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Io(kind) => write!(f, "Could not read frame: {}", kind),
+            DecodeError::UnknownVersion(version) => write!(f, "Unknown frame version {}", version),
+            DecodeError::FrameTooLong(len) => write!(f, "Frame too long ({} bytes)", len),
+            DecodeError::Truncated => write!(f, "Unexpected end of payload"),
+            DecodeError::TrailingBytes => write!(f, "Unexpected bytes after the message"),
+            DecodeError::UnknownMessageType(t) => write!(f, "Invalid message type {}", t),
+            DecodeError::UnknownAction(action) => write!(f, "Invalid action type {}", action),
+            DecodeError::UnknownReadMode(read) => write!(f, "Invalid balance read mode {}", read),
+            DecodeError::UnknownResponse(code) => write!(f, "Invalid response code {}", code),
+            DecodeError::UnknownOrderField(tag) => write!(f, "Invalid order field {}", tag),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
@@ -0,0 +1,24 @@
+use std::fmt;
+
+/// Error found while encoding a message in the legacy fixed size encoding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncodeError {
+    /// The client id does not fit in the two bytes of the encoding.
+    ClientIdTooLarge(u64),
+    /// The points do not fit in the three digits of the encoding.
+    TooManyPoints(u64),
+    /// Balance queries and identify messages only have a versioned encoding.
+    NoLegacyEncoding,
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EncodeError::ClientIdTooLarge(id) => write!(f, "Client id {} is too large", id),
+            EncodeError::TooManyPoints(points) => write!(f, "Too many points ({})", points),
+            EncodeError::NoLegacyEncoding => write!(f, "The message has no legacy encoding"),
+        }
+    }
+}
+
+impl std::error::Error for EncodeError {}
@@ -0,0 +1,172 @@
+use std::io::Read;
+
+use crate::{DecodeError, MessageBytes, MESSAGE_BUFFER_SIZE};
+
+/// Version byte that opens every versioned frame.
+/// It has the high bit set so it can never be mistaken for the message type byte
+/// that opens a legacy (fixed size) frame.
+pub const FRAME_VERSION: u8 = 0x81;
+
+/// Maximum payload length accepted when reading a frame.
+pub const MAX_FRAME_SIZE: u32 = 64 * 1024;
+
+/// Wire format in which a message was received.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireFormat {
+    /// Fixed size `MessageBytes` frame sent by older coffee makers.
+    Legacy,
+    /// Frame made of a version byte, a big endian `u32` length and the payload.
+    Versioned,
+}
+
+/// Wraps the given payload in a versioned frame.
+pub fn encode_frame(payload: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(payload.len() + 5);
+    buf.push(FRAME_VERSION);
+    buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    buf.extend_from_slice(payload);
+    buf
+}
+
+/// Reads the payload of a versioned frame whose version byte was already consumed.
+pub fn read_frame_payload(reader: &mut impl Read) -> Result<Vec<u8>, DecodeError> {
+    let mut len_buf = [0; 4];
+    reader.read_exact(&mut len_buf)?;
+    let len = u32::from_be_bytes(len_buf);
+
+    if len > MAX_FRAME_SIZE {
+        return Err(DecodeError::FrameTooLong(len));
+    }
+
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+/// A complete frame read from the wire, not decoded yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Legacy(MessageBytes),
+    Versioned(Vec<u8>),
+}
+
+impl Frame {
+    /// Reads a whole frame from the given reader.
+    /// A legacy frame is recognized by its first byte being one of the legacy message types.
+    pub fn read_from(reader: &mut impl Read) -> Result<Self, DecodeError> {
+        let mut first = [0; 1];
+        reader.read_exact(&mut first)?;
+
+        match first[0] {
+            FRAME_VERSION => Ok(Frame::Versioned(read_frame_payload(reader)?)),
+            1..=3 => {
+                let mut buf: MessageBytes = [0; MESSAGE_BUFFER_SIZE];
+                buf[0] = first[0];
+                reader.read_exact(&mut buf[1..])?;
+                Ok(Frame::Legacy(buf))
+            }
+            version => Err(DecodeError::UnknownVersion(version)),
+        }
+    }
+
+    pub fn format(&self) -> WireFormat {
+        match self {
+            Frame::Legacy(_) => WireFormat::Legacy,
+            Frame::Versioned(_) => WireFormat::Versioned,
+        }
+    }
+}
+
+/// Sequential reader over the payload of a frame.
+/// Every integer is read in big endian order.
+pub struct PayloadReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PayloadReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        PayloadReader { buf, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        if self.buf.len() - self.pos < len {
+            return Err(DecodeError::Truncated);
+        }
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, DecodeError> {
+        let mut buf = [0; 8];
+        buf.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    /// Returns true if every byte of the payload was read.
+    pub fn is_empty(&self) -> bool {
+        self.pos == self.buf.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let frame = encode_frame(&[1, 2, 3]);
+        assert_eq!(frame[0], FRAME_VERSION);
+
+        let payload = read_frame_payload(&mut &frame[1..]).unwrap();
+        assert_eq!(payload, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_frame_too_long() {
+        let mut frame = vec![];
+        frame.extend_from_slice(&(MAX_FRAME_SIZE + 1).to_be_bytes());
+        assert_eq!(
+            read_frame_payload(&mut frame.as_slice()),
+            Err(DecodeError::FrameTooLong(MAX_FRAME_SIZE + 1))
+        );
+    }
+
+    #[test]
+    fn test_read_frames() {
+        let frame = encode_frame(&[4, 2]);
+        let read = Frame::read_from(&mut frame.as_slice()).unwrap();
+        assert_eq!(read, Frame::Versioned(vec![4, 2]));
+        assert_eq!(read.format(), WireFormat::Versioned);
+
+        let legacy = [1, 0, 1, 1, 0, 0, 5];
+        let read = Frame::read_from(&mut legacy.as_slice()).unwrap();
+        assert_eq!(read, Frame::Legacy(legacy));
+        assert_eq!(read.format(), WireFormat::Legacy);
+    }
+
+    #[test]
+    fn test_read_unknown_frame() {
+        let buf = [0x7f, 0, 0, 0, 0];
+        let err = Frame::read_from(&mut buf.as_slice()).unwrap_err();
+        assert_eq!(err, DecodeError::UnknownVersion(0x7f));
+        assert!(!err.is_recoverable());
+    }
+
+    #[test]
+    fn test_payload_reader() {
+        let mut buf = vec![7];
+        buf.extend_from_slice(&u64::MAX.to_be_bytes());
+        let mut reader = PayloadReader::new(&buf);
+
+        assert_eq!(reader.read_u8().unwrap(), 7);
+        assert_eq!(reader.read_u64().unwrap(), u64::MAX);
+        assert!(reader.is_empty());
+        assert_eq!(reader.read_u8(), Err(DecodeError::Truncated));
+    }
+}
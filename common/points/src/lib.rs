@@ -1,12 +1,33 @@
+mod decode_error;
+pub use decode_error::*;
+
+mod encode_error;
+pub use encode_error::*;
+
+mod frame;
+pub use frame::*;
+
+mod balance;
+pub use balance::*;
+
 mod order;
 pub use order::*;
 
+mod order_reader;
+pub use order_reader::*;
+
+mod parse_error;
+pub use parse_error::*;
+
 mod message;
 pub use message::*;
 
 mod control;
 pub use control::*;
 
+mod response;
+pub use response::*;
+
 pub const CLIENT_CONNECTION: u8 = 1;
 pub const SERVER_MESSAGE: u8 = 2;
 pub const CONTROL_MESSAGE: u8 = 3;
@@ -1,58 +1,168 @@
-use crate::{Order, OrderAction, ORDER_BUFFER_SIZE};
+use std::io::Read;
+
+use crate::{
+    encode_frame, BalanceRead, DecodeError, EncodeError, Frame, Order, OrderAction, PayloadReader,
+    WireFormat, ORDER_BUFFER_SIZE,
+};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Message {
     LockOrder(Order),
     FreeOrder(Order),
     CommitOrder(Order),
+    /// Asks for the balance of the given client id.
+    QueryBalance(u64, BalanceRead),
+    /// Tells the server the id of the coffee maker on the other side of the connection,
+    /// so its reservations survive a reconnection.
+    Identify(u64),
 }
 
+/// Size of a message in the legacy fixed size encoding.
 pub const MESSAGE_BUFFER_SIZE: usize = ORDER_BUFFER_SIZE + 1;
+/// Legacy fixed size encoding of a message: type byte followed by the order.
 pub type MessageBytes = [u8; MESSAGE_BUFFER_SIZE];
 
-impl From<Message> for MessageBytes {
-    fn from(message: Message) -> Self {
+const LOCK_ORDER: u8 = 1;
+const FREE_ORDER: u8 = 2;
+const COMMIT_ORDER: u8 = 3;
+const QUERY_BALANCE: u8 = 4;
+const IDENTIFY: u8 = 5;
+
+impl TryFrom<Message> for MessageBytes {
+    type Error = EncodeError;
+
+    fn try_from(message: Message) -> Result<Self, Self::Error> {
         let mut buf = [0; MESSAGE_BUFFER_SIZE];
 
         match message {
             Message::LockOrder(order) => {
                 buf[0] = 1;
-                let order: [u8; ORDER_BUFFER_SIZE] = order.into();
+                let order: [u8; ORDER_BUFFER_SIZE] = order.try_into()?;
                 buf[1..(MESSAGE_BUFFER_SIZE)].copy_from_slice(&order[..ORDER_BUFFER_SIZE]);
             }
             Message::FreeOrder(order) => {
                 buf[0] = 2;
-                let order: [u8; ORDER_BUFFER_SIZE] = order.into();
+                let order: [u8; ORDER_BUFFER_SIZE] = order.try_into()?;
                 buf[1..(MESSAGE_BUFFER_SIZE)].copy_from_slice(&order[..ORDER_BUFFER_SIZE]);
             }
             Message::CommitOrder(order) => {
                 buf[0] = 3;
-                let order: [u8; 6] = order.into();
+                let order: [u8; ORDER_BUFFER_SIZE] = order.try_into()?;
                 buf[1..(MESSAGE_BUFFER_SIZE)].copy_from_slice(&order[..ORDER_BUFFER_SIZE]);
             }
+            Message::QueryBalance(_, _) | Message::Identify(_) => {
+                return Err(EncodeError::NoLegacyEncoding);
+            }
         }
 
-        buf
+        Ok(buf)
     }
 }
 
-impl From<MessageBytes> for Message {
-    fn from(buf: MessageBytes) -> Self {
+impl TryFrom<MessageBytes> for Message {
+    type Error = DecodeError;
+
+    fn try_from(buf: MessageBytes) -> Result<Self, Self::Error> {
         let mut order_buf = [0; ORDER_BUFFER_SIZE];
         order_buf[..6].copy_from_slice(&buf[1..(MESSAGE_BUFFER_SIZE)]);
 
-        let order = Order::from(order_buf);
+        let order = Order::try_from(order_buf)?;
 
         match buf[0] {
-            1 => Message::LockOrder(order),
-            2 => Message::FreeOrder(order),
-            3 => Message::CommitOrder(order),
-            _ => panic!("Invalid message"),
+            LOCK_ORDER => Ok(Message::LockOrder(order)),
+            FREE_ORDER => Ok(Message::FreeOrder(order)),
+            COMMIT_ORDER => Ok(Message::CommitOrder(order)),
+            msg_type => Err(DecodeError::UnknownMessageType(msg_type)),
+        }
+    }
+}
+
+impl TryFrom<Frame> for Message {
+    type Error = DecodeError;
+
+    fn try_from(frame: Frame) -> Result<Self, Self::Error> {
+        match frame {
+            Frame::Legacy(buf) => Message::try_from(buf),
+            Frame::Versioned(payload) => Message::from_payload(&payload),
         }
     }
 }
 
 impl Message {
+    /// Encodes the message as a versioned frame.
+    /// Payload layout: message type (`u8`) followed by the encoded order.
+    /// Balance queries carry the client id (`u64`) and the read mode (`u8`) instead of an order,
+    /// and identify messages carry the machine id (`u64`).
+    pub fn to_frame(&self) -> Vec<u8> {
+        let mut payload = vec![];
+        let (msg_type, order) = match self {
+            Message::LockOrder(order) => (LOCK_ORDER, order),
+            Message::FreeOrder(order) => (FREE_ORDER, order),
+            Message::CommitOrder(order) => (COMMIT_ORDER, order),
+            Message::QueryBalance(client_id, read) => {
+                payload.push(QUERY_BALANCE);
+                payload.extend_from_slice(&client_id.to_be_bytes());
+                payload.push(u8::from(*read));
+                return encode_frame(&payload);
+            }
+            Message::Identify(machine_id) => {
+                payload.push(IDENTIFY);
+                payload.extend_from_slice(&machine_id.to_be_bytes());
+                return encode_frame(&payload);
+            }
+        };
+        payload.push(msg_type);
+        order.encode(&mut payload);
+
+        encode_frame(&payload)
+    }
+
+    /// Decodes a message from the payload of a versioned frame.
+    /// Fails with `TrailingBytes` if the payload goes on after the message.
+    pub fn from_payload(payload: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = PayloadReader::new(payload);
+        let msg_type = reader.read_u8()?;
+
+        let message = match msg_type {
+            QUERY_BALANCE => {
+                let client_id = reader.read_u64()?;
+                let read = match reader.read_u8()? {
+                    1 => BalanceRead::Local,
+                    2 => BalanceRead::Quorum,
+                    read => return Err(DecodeError::UnknownReadMode(read)),
+                };
+                Message::QueryBalance(client_id, read)
+            }
+            IDENTIFY => Message::Identify(reader.read_u64()?),
+            _ => return Self::order_from_payload(msg_type, &mut reader),
+        };
+        if !reader.is_empty() {
+            return Err(DecodeError::TrailingBytes);
+        }
+        Ok(message)
+    }
+
+    /// Decodes the order of a message, which reads the payload until the end.
+    fn order_from_payload(msg_type: u8, reader: &mut PayloadReader) -> Result<Self, DecodeError> {
+        let order = Order::decode(reader)?;
+
+        match msg_type {
+            LOCK_ORDER => Ok(Message::LockOrder(order)),
+            FREE_ORDER => Ok(Message::FreeOrder(order)),
+            COMMIT_ORDER => Ok(Message::CommitOrder(order)),
+            _ => Err(DecodeError::UnknownMessageType(msg_type)),
+        }
+    }
+
+    /// Reads a message from the given reader.
+    /// Both versioned frames and legacy `MessageBytes` frames are accepted,
+    /// the returned `WireFormat` tells which one was received.
+    pub fn read_from(reader: &mut impl Read) -> Result<(Self, WireFormat), DecodeError> {
+        let frame = Frame::read_from(reader)?;
+        let format = frame.format();
+        Ok((Message::try_from(frame)?, format))
+    }
+
     pub fn handle_trivially(&self) -> Result<(), String> {
         let err = "Could not handle message locally".to_string();
 
@@ -60,6 +170,7 @@ impl Message {
             Message::LockOrder(order) => Ok(order),
             Message::FreeOrder(order) => Ok(order),
             Message::CommitOrder(_) => Err(err.clone()),
+            Message::QueryBalance(_, _) | Message::Identify(_) => Err(err.clone()),
         }?;
 
         match order.action {
@@ -68,13 +179,21 @@ impl Message {
         }
     }
 
-    pub fn order(&self) -> &Order {
+    /// Returns the order carried by the message, if any.
+    pub fn order(&self) -> Option<&Order> {
         match self {
-            Message::LockOrder(order) => order,
-            Message::FreeOrder(order) => order,
-            Message::CommitOrder(order) => order,
+            Message::LockOrder(order) => Some(order),
+            Message::FreeOrder(order) => Some(order),
+            Message::CommitOrder(order) => Some(order),
+            Message::QueryBalance(_, _) | Message::Identify(_) => None,
         }
     }
+
+    /// Returns the id of the order carried by the message, if any.
+    /// Messages with an id can be safely retried, as servers apply them only once.
+    pub fn order_id(&self) -> Option<u64> {
+        self.order().and_then(|order| order.id)
+    }
 }
 
 #[cfg(test)]
@@ -85,9 +204,14 @@ mod test {
     use super::*;
 
     fn test_message(message: Message) {
-        let buf: [u8; 7] = message.clone().into();
-        let message2 = Message::from(buf);
+        let buf: [u8; 7] = message.clone().try_into().unwrap();
+        let message2 = Message::try_from(buf).unwrap();
         assert_eq!(message, message2);
+
+        let frame = message.to_frame();
+        let (message3, format) = Message::read_from(&mut frame.as_slice()).unwrap();
+        assert_eq!(message, message3);
+        assert_eq!(format, WireFormat::Versioned);
     }
 
     #[test]
@@ -110,4 +234,112 @@ mod test {
         let message = Message::CommitOrder(order);
         test_message(message);
     }
+
+    #[test]
+    fn large_order() {
+        let order = Order::new(70_000, OrderAction::FillPoints(5_000));
+        let message = Message::CommitOrder(order);
+        let frame = message.to_frame();
+        let (decoded, _) = Message::read_from(&mut frame.as_slice()).unwrap();
+        assert_eq!(message, decoded);
+    }
+
+    #[test]
+    fn legacy_frame() {
+        let order = Order::new(30, OrderAction::UsePoints(123));
+        let message = Message::LockOrder(order);
+        let buf: MessageBytes = message.clone().try_into().unwrap();
+        let (decoded, format) = Message::read_from(&mut buf.as_slice()).unwrap();
+        assert_eq!(message, decoded);
+        assert_eq!(format, WireFormat::Legacy);
+    }
+
+    #[test]
+    fn legacy_frame_does_not_fit() {
+        let order = Order::new(70_000, OrderAction::UsePoints(10));
+        assert_eq!(
+            MessageBytes::try_from(Message::LockOrder(order)),
+            Err(EncodeError::ClientIdTooLarge(70_000))
+        );
+        let order = Order::new(30, OrderAction::UsePoints(1000));
+        assert_eq!(
+            MessageBytes::try_from(Message::CommitOrder(order)),
+            Err(EncodeError::TooManyPoints(1000))
+        );
+    }
+
+    #[test]
+    fn order_with_id() {
+        let order = Order::new(30, OrderAction::UsePoints(123)).with_id(42);
+        let message = Message::CommitOrder(order);
+        let frame = message.to_frame();
+        let (decoded, _) = Message::read_from(&mut frame.as_slice()).unwrap();
+        assert_eq!(decoded.order_id(), Some(42));
+        assert_eq!(message, decoded);
+    }
+
+    #[test]
+    fn query_balance() {
+        let message = Message::QueryBalance(u64::MAX, BalanceRead::Quorum);
+        let frame = message.to_frame();
+        let (decoded, _) = Message::read_from(&mut frame.as_slice()).unwrap();
+        assert_eq!(message, decoded);
+        assert_eq!(
+            MessageBytes::try_from(message),
+            Err(EncodeError::NoLegacyEncoding)
+        );
+    }
+
+    #[test]
+    fn identify() {
+        let message = Message::Identify(u64::MAX);
+        let frame = message.to_frame();
+        let (decoded, _) = Message::read_from(&mut frame.as_slice()).unwrap();
+        assert_eq!(message, decoded);
+        assert!(MessageBytes::try_from(message).is_err());
+    }
+
+    #[test]
+    fn unknown_frame_version() {
+        let buf = [0x7f, 0, 0, 0, 0];
+        assert_eq!(
+            Message::read_from(&mut buf.as_slice()),
+            Err(DecodeError::UnknownVersion(0x7f))
+        );
+    }
+
+    #[test]
+    fn invalid_legacy_action() {
+        let buf: MessageBytes = [1, 0, 1, 9, 0, 0, 5];
+        assert_eq!(Message::try_from(buf), Err(DecodeError::UnknownAction(9)));
+    }
+
+    #[test]
+    fn invalid_message_type() {
+        let mut payload = vec![42];
+        Order::new(1, OrderAction::UsePoints(1)).encode(&mut payload);
+        let err = Message::from_payload(&payload).unwrap_err();
+        assert_eq!(err, DecodeError::UnknownMessageType(42));
+        assert!(err.is_recoverable());
+    }
+
+    #[test]
+    fn trailing_bytes() {
+        let mut query = vec![QUERY_BALANCE];
+        query.extend_from_slice(&1u64.to_be_bytes());
+        query.push(1);
+        let mut identify = vec![IDENTIFY];
+        identify.extend_from_slice(&1u64.to_be_bytes());
+
+        for (mut payload, message) in [
+            (query, Message::QueryBalance(1, BalanceRead::Local)),
+            (identify, Message::Identify(1)),
+        ] {
+            assert_eq!(Message::from_payload(&payload), Ok(message));
+            payload.push(0);
+            let err = Message::from_payload(&payload).unwrap_err();
+            assert_eq!(err, DecodeError::TrailingBytes);
+            assert!(err.is_recoverable());
+        }
+    }
 }
@@ -1,43 +1,112 @@
+use crate::{parse_line, DecodeError, EncodeError, ParseError, ParseErrorKind, PayloadReader};
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum OrderAction {
-    UsePoints(usize),
-    FillPoints(usize),
+    UsePoints(u64),
+    FillPoints(u64),
 }
 
+/// Tags of the optional fields that follow an encoded order.
+const ORDER_ID: u8 = 1;
+const RESERVATION: u8 = 2;
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Order {
-    pub client_id: u16,
+    /// Unique id of the order, used by the servers to recognize retried messages.
+    /// Orders received in the legacy encoding have no id.
+    pub id: Option<u64>,
+    pub client_id: u64,
     pub action: OrderAction,
+    /// Reservation returned by the server when the points of the order were locked.
+    /// Freeing or consuming those points requires it.
+    pub reservation: Option<u64>,
 }
 
 impl Order {
-    pub fn new(client_id: u16, action: OrderAction) -> Self {
-        Order { client_id, action }
+    pub fn new(client_id: u64, action: OrderAction) -> Self {
+        Order {
+            id: None,
+            client_id,
+            action,
+            reservation: None,
+        }
     }
 
-    pub fn parse(line: String) -> Result<Self, String> {
-        let mut parts = line.split(',');
+    /// Returns the same order with the given unique id.
+    pub fn with_id(self, id: u64) -> Self {
+        Order {
+            id: Some(id),
+            ..self
+        }
+    }
 
-        let client_id = parts.next().unwrap().to_string();
-        let action = parts.next().unwrap().to_string();
+    /// Returns the same order referencing the given reservation.
+    pub fn with_reservation(self, reservation: u64) -> Self {
+        Order {
+            reservation: Some(reservation),
+            ..self
+        }
+    }
 
-        let action = match action.as_str() {
-            "USE" => {
-                let points = parts.next().unwrap().parse::<usize>().unwrap();
-                OrderAction::UsePoints(points)
+    /// Parses a single `client_id,action,points` line.
+    /// See `OrderReader` to parse a whole orders file.
+    pub fn parse(line: String) -> Result<Self, ParseError> {
+        parse_line(&line, 1)?
+            .ok_or_else(|| ParseError::new(1, 1, ParseErrorKind::MissingField("client id")))
+    }
+
+    /// Appends the order to a versioned frame payload.
+    /// Layout: client id (`u64`), action type (`u8`), points (`u64`), followed by
+    /// the optional fields the order has, each one as a tag (`u8`) and a value (`u64`).
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.client_id.to_be_bytes());
+        match self.action {
+            OrderAction::UsePoints(points) => {
+                buf.push(1);
+                buf.extend_from_slice(&points.to_be_bytes());
             }
-            "FILL" => {
-                let points = parts.next().unwrap().parse::<usize>().unwrap();
-                OrderAction::FillPoints(points)
+            OrderAction::FillPoints(points) => {
+                buf.push(2);
+                buf.extend_from_slice(&points.to_be_bytes());
             }
-            _ => return Err("Invalid action".to_string()),
+        }
+        if let Some(id) = self.id {
+            buf.push(ORDER_ID);
+            buf.extend_from_slice(&id.to_be_bytes());
+        }
+        if let Some(reservation) = self.reservation {
+            buf.push(RESERVATION);
+            buf.extend_from_slice(&reservation.to_be_bytes());
+        }
+    }
+
+    /// Reads an order from a versioned frame payload.
+    /// The order must be the last field of the payload, as its optional fields are read until the end.
+    pub fn decode(reader: &mut PayloadReader) -> Result<Self, DecodeError> {
+        let client_id = reader.read_u64()?;
+        let action_type = reader.read_u8()?;
+        let points = reader.read_u64()?;
+
+        let action = match action_type {
+            1 => OrderAction::UsePoints(points),
+            2 => OrderAction::FillPoints(points),
+            _ => return Err(DecodeError::UnknownAction(action_type)),
         };
-        Ok(Order::new(client_id.parse::<u16>().unwrap(), action))
+
+        let mut order = Order::new(client_id, action);
+        while !reader.is_empty() {
+            match reader.read_u8()? {
+                ORDER_ID => order.id = Some(reader.read_u64()?),
+                RESERVATION => order.reservation = Some(reader.read_u64()?),
+                tag => return Err(DecodeError::UnknownOrderField(tag)),
+            }
+        }
+        Ok(order)
     }
 }
 
 impl OrderAction {
-    pub fn points(&self) -> usize {
+    pub fn points(&self) -> u64 {
         match self {
             OrderAction::UsePoints(points) => *points,
             OrderAction::FillPoints(points) => *points,
@@ -45,16 +114,27 @@ impl OrderAction {
     }
 }
 
+/// Size of an order in the legacy fixed size encoding.
+/// Points are stored as three decimal digits, so only orders up to 999 points fit.
 pub const ORDER_BUFFER_SIZE: usize = 6;
+/// Most points of an order in the legacy encoding.
+pub const MAX_LEGACY_POINTS: u64 = 999;
 
-impl From<Order> for [u8; ORDER_BUFFER_SIZE] {
-    fn from(order: Order) -> Self {
+impl TryFrom<Order> for [u8; ORDER_BUFFER_SIZE] {
+    type Error = EncodeError;
+
+    /// Fails if the client id does not fit in two bytes or the points in three digits.
+    fn try_from(order: Order) -> Result<Self, Self::Error> {
         let mut buf = [0; ORDER_BUFFER_SIZE];
 
-        let client_id = order.client_id;
-        buf[0] = (client_id >> 8) as u8;
-        buf[1] = client_id as u8;
+        let client_id = u16::try_from(order.client_id)
+            .map_err(|_| EncodeError::ClientIdTooLarge(order.client_id))?;
+        buf[0..2].copy_from_slice(&client_id.to_be_bytes());
 
+        let points = order.action.points();
+        if points > MAX_LEGACY_POINTS {
+            return Err(EncodeError::TooManyPoints(points));
+        }
         match order.action {
             OrderAction::UsePoints(points) => {
                 buf[2] = 1;
@@ -70,26 +150,28 @@ impl From<Order> for [u8; ORDER_BUFFER_SIZE] {
             }
         }
 
-        buf
+        Ok(buf)
     }
 }
 
-impl From<[u8; ORDER_BUFFER_SIZE]> for Order {
-    fn from(buf: [u8; ORDER_BUFFER_SIZE]) -> Self {
+impl TryFrom<[u8; ORDER_BUFFER_SIZE]> for Order {
+    type Error = DecodeError;
+
+    fn try_from(buf: [u8; ORDER_BUFFER_SIZE]) -> Result<Self, Self::Error> {
         // First 2 bytes are client id
         // Next byte is action type
         // Last 3 bytes are points
-        let client_id = ((buf[0] as u16) << 8) | buf[1] as u16;
+        let client_id = ((buf[0] as u64) << 8) | buf[1] as u64;
 
-        let points = (buf[3] as usize) * 100 + (buf[4] as usize) * 10 + (buf[5] as usize);
+        let points = (buf[3] as u64) * 100 + (buf[4] as u64) * 10 + (buf[5] as u64);
 
         let action = match buf[2] {
             1 => OrderAction::UsePoints(points),
             2 => OrderAction::FillPoints(points),
-            _ => panic!("Invalid action type"),
+            _ => return Err(DecodeError::UnknownAction(buf[2])),
         };
 
-        Order::new(client_id, action)
+        Ok(Order::new(client_id, action))
     }
 }
 
@@ -99,8 +181,8 @@ mod tests {
     use super::*;
 
     fn test_order(order: Order) {
-        let order_from_buf: [u8; 6] = order.clone().into();
-        let expected_order = Order::from(order_from_buf);
+        let order_from_buf: [u8; 6] = order.clone().try_into().unwrap();
+        let expected_order = Order::try_from(order_from_buf).unwrap();
         assert_eq!(order, expected_order);
     }
 
@@ -115,4 +197,73 @@ mod tests {
         let order = Order::new(30, OrderAction::FillPoints(123));
         test_order(order);
     }
+
+    #[test]
+    fn test_order_limits() {
+        test_order(Order::new(
+            u16::MAX as u64,
+            OrderAction::UsePoints(MAX_LEGACY_POINTS),
+        ));
+    }
+
+    #[test]
+    fn test_order_does_not_fit() {
+        let too_many = Order::new(25, OrderAction::FillPoints(MAX_LEGACY_POINTS + 1));
+        assert_eq!(
+            <[u8; ORDER_BUFFER_SIZE]>::try_from(too_many),
+            Err(EncodeError::TooManyPoints(1000))
+        );
+        let large_id = Order::new(u16::MAX as u64 + 1, OrderAction::UsePoints(10));
+        assert_eq!(
+            <[u8; ORDER_BUFFER_SIZE]>::try_from(large_id),
+            Err(EncodeError::ClientIdTooLarge(65536))
+        );
+    }
+
+    #[test]
+    fn test_order_invalid_action() {
+        let buf = [0, 25, 7, 1, 2, 3];
+        assert_eq!(Order::try_from(buf), Err(DecodeError::UnknownAction(7)));
+    }
+
+    fn test_encoded_order(order: Order) {
+        let mut buf = vec![];
+        order.encode(&mut buf);
+        let decoded = Order::decode(&mut PayloadReader::new(&buf)).unwrap();
+        assert_eq!(order, decoded);
+    }
+
+    #[test]
+    fn test_encoded_order_large_points() {
+        let order = Order::new(25, OrderAction::UsePoints(1_000_000));
+        test_encoded_order(order);
+    }
+
+    #[test]
+    fn test_encoded_order_large_client_id() {
+        let order = Order::new(u64::MAX, OrderAction::FillPoints(u64::MAX));
+        test_encoded_order(order);
+    }
+
+    #[test]
+    fn test_encoded_order_with_id() {
+        let order = Order::new(25, OrderAction::UsePoints(10)).with_id(u64::MAX);
+        test_encoded_order(order);
+    }
+
+    #[test]
+    fn test_encoded_order_with_reservation() {
+        let order = Order::new(25, OrderAction::UsePoints(10))
+            .with_id(1)
+            .with_reservation(2);
+        test_encoded_order(order.clone());
+
+        let mut buf = vec![];
+        order.encode(&mut buf);
+        buf.push(9);
+        assert_eq!(
+            Order::decode(&mut PayloadReader::new(&buf)),
+            Err(DecodeError::UnknownOrderField(9))
+        );
+    }
 }
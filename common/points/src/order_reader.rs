@@ -0,0 +1,203 @@
+use std::io::{BufRead, Lines};
+
+use crate::{Order, OrderAction, ParseError, ParseErrorKind};
+
+const COMMENT: char = '#';
+const SEPARATOR: char = ',';
+const HEADER: [&str; 3] = ["client_id", "action", "points"];
+
+/// Reads orders from a csv file with one `client_id,action,points` order per line.
+///
+/// Blank lines and comments (starting with `#`) are skipped, fields may be surrounded by whitespace
+/// and the first line may be a header row with the names of the fields (`client_id,action,points`).
+/// Each item is either an order or the error found in its line,
+/// so the caller can decide whether to skip the line or stop reading.
+pub struct OrderReader<R> {
+    lines: Lines<R>,
+    line_number: usize,
+    header_allowed: bool,
+}
+
+impl<R: BufRead> OrderReader<R> {
+    pub fn new(reader: R) -> Self {
+        OrderReader {
+            lines: reader.lines(),
+            line_number: 0,
+            header_allowed: true,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for OrderReader<R> {
+    type Item = Result<Order, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = self.lines.next()?;
+            self.line_number += 1;
+
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    let kind = ParseErrorKind::Io(e.to_string());
+                    return Some(Err(ParseError::new(self.line_number, 1, kind)));
+                }
+            };
+
+            match parse_line(&line, self.line_number) {
+                Ok(None) => continue,
+                Err(_) if self.header_allowed && is_header(&line) => {
+                    self.header_allowed = false;
+                    continue;
+                }
+                res => {
+                    self.header_allowed = false;
+                    return res.transpose();
+                }
+            }
+        }
+    }
+}
+
+/// A header is a line whose columns are the names of the fields, in any case.
+fn is_header(line: &str) -> bool {
+    let content = match line.find(COMMENT) {
+        Some(comment) => &line[..comment],
+        None => line,
+    };
+    let columns: Vec<&str> = content.split(SEPARATOR).map(str::trim).collect();
+    columns.len() == HEADER.len()
+        && columns
+            .iter()
+            .zip(HEADER)
+            .all(|(column, name)| column.eq_ignore_ascii_case(name))
+}
+
+/// Parses a single line of an orders file.
+/// Returns `None` for blank lines and comments.
+pub fn parse_line(line: &str, line_number: usize) -> Result<Option<Order>, ParseError> {
+    let content = match line.find(COMMENT) {
+        Some(comment) => &line[..comment],
+        None => line,
+    };
+    if content.trim().is_empty() {
+        return Ok(None);
+    }
+
+    let error = |offset: usize, kind| {
+        let column = content[..offset].chars().count() + 1;
+        ParseError::new(line_number, column, kind)
+    };
+
+    // Each field with the offset where its trimmed content starts
+    let mut fields = vec![];
+    let mut start = 0;
+    for field in content.split(SEPARATOR) {
+        let leading = field.len() - field.trim_start().len();
+        fields.push((start + leading, field.trim()));
+        start += field.len() + 1;
+    }
+
+    if fields.len() < 2 {
+        return Err(error(content.len(), ParseErrorKind::MissingField("action")));
+    }
+    if fields.len() < 3 {
+        return Err(error(content.len(), ParseErrorKind::MissingField("points")));
+    }
+    if fields.len() > 3 {
+        return Err(error(fields[3].0, ParseErrorKind::TooManyFields));
+    }
+
+    let (offset, client_id) = fields[0];
+    let client_id = client_id.parse::<u64>().map_err(|_| {
+        error(
+            offset,
+            ParseErrorKind::InvalidClientId(client_id.to_string()),
+        )
+    })?;
+
+    let (offset, points) = fields[2];
+    let points = points
+        .parse::<u64>()
+        .map_err(|_| error(offset, ParseErrorKind::InvalidPoints(points.to_string())))?;
+
+    let (offset, action) = fields[1];
+    let action = match action.to_uppercase().as_str() {
+        "USE" => OrderAction::UsePoints(points),
+        "FILL" => OrderAction::FillPoints(points),
+        _ => {
+            return Err(error(
+                offset,
+                ParseErrorKind::InvalidAction(action.to_string()),
+            ))
+        }
+    };
+
+    Ok(Some(Order::new(client_id, action)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(input: &str) -> Vec<Result<Order, ParseError>> {
+        OrderReader::new(input.as_bytes()).collect()
+    }
+
+    #[test]
+    fn test_read_orders() {
+        let orders = read("1,USE,3\n2,FILL,10\n");
+        assert_eq!(
+            orders,
+            vec![
+                Ok(Order::new(1, OrderAction::UsePoints(3))),
+                Ok(Order::new(2, OrderAction::FillPoints(10))),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_skip_header_comments_blank_lines_and_whitespace() {
+        let input = "client_id, action, points\n# comment\n\n   \n 1 , use , 3 # inline\n";
+        assert_eq!(
+            read(input),
+            vec![Ok(Order::new(1, OrderAction::UsePoints(3)))]
+        );
+    }
+
+    #[test]
+    fn test_header_only_on_first_line() {
+        let orders = read("1,USE,3\nclient_id,action,points\n");
+        let kind = ParseErrorKind::InvalidClientId("client_id".to_string());
+        assert_eq!(orders[1], Err(ParseError::new(2, 1, kind)));
+    }
+
+    #[test]
+    fn test_header_needs_every_column() {
+        let orders = read("abc,USE,3\n1,USE,3\n");
+        let kind = ParseErrorKind::InvalidClientId("abc".to_string());
+        assert_eq!(orders[0], Err(ParseError::new(1, 1, kind)));
+
+        let orders = read("client_id,action,amount\n");
+        let kind = ParseErrorKind::InvalidClientId("client_id".to_string());
+        assert_eq!(orders, vec![Err(ParseError::new(1, 1, kind))]);
+
+        let orders = read("Client_ID,Action,Points\n1,USE,3\n");
+        assert_eq!(orders, vec![Ok(Order::new(1, OrderAction::UsePoints(3)))]);
+    }
+
+    #[test]
+    fn test_error_positions() {
+        let orders = read("1,USE,3\n1,DRINK,3\n1, FILL,x\n1,FILL\n1,FILL,3,4\n");
+        let errors: Vec<ParseError> = orders.into_iter().filter_map(|o| o.err()).collect();
+        assert_eq!(
+            errors,
+            vec![
+                ParseError::new(2, 3, ParseErrorKind::InvalidAction("DRINK".to_string())),
+                ParseError::new(3, 9, ParseErrorKind::InvalidPoints("x".to_string())),
+                ParseError::new(4, 7, ParseErrorKind::MissingField("points")),
+                ParseError::new(5, 10, ParseErrorKind::TooManyFields),
+            ]
+        );
+    }
+}
@@ -0,0 +1,53 @@
+use std::fmt;
+
+/// Reason why a line of an orders file could not be parsed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// The line has less than the three expected fields.
+    MissingField(&'static str),
+    /// The line has more than the three expected fields.
+    TooManyFields,
+    InvalidClientId(String),
+    InvalidAction(String),
+    InvalidPoints(String),
+    /// The file could not be read.
+    Io(String),
+}
+
+/// Error found while parsing an orders file.
+/// Lines and columns start at 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub kind: ParseErrorKind,
+}
+
+impl ParseError {
+    pub fn new(line: usize, column: usize, kind: ParseErrorKind) -> Self {
+        ParseError { line, column, kind }
+    }
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErrorKind::MissingField(field) => write!(f, "missing {}", field),
+            ParseErrorKind::TooManyFields => write!(f, "too many fields"),
+            ParseErrorKind::InvalidClientId(id) => write!(f, "invalid client id '{}'", id),
+            ParseErrorKind::InvalidAction(action) => {
+                write!(f, "invalid action '{}', expected USE or FILL", action)
+            }
+            ParseErrorKind::InvalidPoints(points) => write!(f, "invalid points '{}'", points),
+            ParseErrorKind::Io(e) => write!(f, "could not read file: {}", e),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.kind)
+    }
+}
+
+impl std::error::Error for ParseError {}
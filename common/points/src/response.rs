@@ -0,0 +1,166 @@
+use std::{fmt, io::Read};
+
+use crate::{
+    encode_frame, read_frame_payload, Balance, DecodeError, PayloadReader, WireFormat,
+    FRAME_VERSION,
+};
+
+/// Outcome of a message sent by a coffee maker, as reported by its local server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Response {
+    Ok,
+    InsufficientPoints,
+    InsufficientLocked,
+    Offline,
+    Aborted,
+    WaitDieConflict,
+    Timeout,
+    Malformed,
+    /// Successful answer to a balance query.
+    Balance(Balance),
+    /// Successful lock, with the reservation that frees or consumes the locked points.
+    Reserved(u64),
+    /// The reservation referenced by a free or commit is unknown or already settled.
+    UnknownReservation,
+}
+
+impl From<Response> for u8 {
+    fn from(response: Response) -> Self {
+        match response {
+            Response::Ok => 1,
+            Response::InsufficientPoints => 2,
+            Response::InsufficientLocked => 3,
+            Response::Offline => 4,
+            Response::Aborted => 5,
+            Response::WaitDieConflict => 6,
+            Response::Timeout => 7,
+            Response::Malformed => 8,
+            Response::Balance(_) => 9,
+            Response::Reserved(_) => 10,
+            Response::UnknownReservation => 11,
+        }
+    }
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let description = match self {
+            Response::Ok => "Ok",
+            Response::InsufficientPoints => "Not enough points available",
+            Response::InsufficientLocked => "Not enough points locked",
+            Response::Offline => "Server is offline",
+            Response::Aborted => "Transaction aborted",
+            Response::WaitDieConflict => "Account is busy with an older transaction",
+            Response::Timeout => "Server did not respond in time",
+            Response::Malformed => "Malformed message",
+            Response::UnknownReservation => "Unknown or already settled reservation",
+            Response::Reserved(reservation) => {
+                return write!(f, "Ok (reservation {})", reservation)
+            }
+            Response::Balance(balance) => {
+                return write!(
+                    f,
+                    "{} Available [{} Locked]",
+                    balance.available, balance.locked
+                )
+            }
+        };
+        write!(f, "{}", description)
+    }
+}
+
+impl Response {
+    pub fn is_ok(&self) -> bool {
+        matches!(
+            self,
+            Response::Ok | Response::Balance(_) | Response::Reserved(_)
+        )
+    }
+
+    /// Encodes the response for a client that talks the given wire format.
+    /// Legacy clients only understand a single byte: 1 on success, 0 on error.
+    pub fn to_frame(&self, format: WireFormat) -> Vec<u8> {
+        match format {
+            WireFormat::Legacy => vec![u8::from(self.is_ok())],
+            WireFormat::Versioned => {
+                let mut payload = vec![u8::from(*self)];
+                match self {
+                    Response::Balance(balance) => {
+                        payload.extend_from_slice(&balance.available.to_be_bytes());
+                        payload.extend_from_slice(&balance.locked.to_be_bytes());
+                    }
+                    Response::Reserved(reservation) => {
+                        payload.extend_from_slice(&reservation.to_be_bytes());
+                    }
+                    _ => {}
+                }
+                encode_frame(&payload)
+            }
+        }
+    }
+
+    /// Decodes a response from the payload of a versioned frame.
+    /// Balances carry the available and locked points (`u64` each) after the code,
+    /// and reservations carry their id (`u64`).
+    pub fn from_payload(payload: &[u8]) -> Result<Self, DecodeError> {
+        let mut reader = PayloadReader::new(payload);
+        match reader.read_u8()? {
+            1 => Ok(Response::Ok),
+            2 => Ok(Response::InsufficientPoints),
+            3 => Ok(Response::InsufficientLocked),
+            4 => Ok(Response::Offline),
+            5 => Ok(Response::Aborted),
+            6 => Ok(Response::WaitDieConflict),
+            7 => Ok(Response::Timeout),
+            8 => Ok(Response::Malformed),
+            9 => {
+                let available = reader.read_u64()?;
+                let locked = reader.read_u64()?;
+                Ok(Response::Balance(Balance::new(available, locked)))
+            }
+            10 => Ok(Response::Reserved(reader.read_u64()?)),
+            11 => Ok(Response::UnknownReservation),
+            code => Err(DecodeError::UnknownResponse(code)),
+        }
+    }
+
+    /// Reads a versioned response frame from the given reader.
+    pub fn read_from(reader: &mut impl Read) -> Result<Self, DecodeError> {
+        let mut version = [0; 1];
+        reader.read_exact(&mut version)?;
+        if version[0] != FRAME_VERSION {
+            return Err(DecodeError::UnknownVersion(version[0]));
+        }
+
+        let payload = read_frame_payload(reader)?;
+        Response::from_payload(&payload)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_response(response: Response) {
+        let frame = response.to_frame(WireFormat::Versioned);
+        let decoded = Response::read_from(&mut frame.as_slice()).unwrap();
+        assert_eq!(response, decoded);
+    }
+
+    #[test]
+    fn test_responses() {
+        test_response(Response::Ok);
+        test_response(Response::InsufficientPoints);
+        test_response(Response::WaitDieConflict);
+        test_response(Response::Malformed);
+        test_response(Response::Balance(Balance::new(1_000, 25)));
+        test_response(Response::Reserved(u64::MAX));
+        test_response(Response::UnknownReservation);
+    }
+
+    #[test]
+    fn test_legacy_response() {
+        assert_eq!(Response::Ok.to_frame(WireFormat::Legacy), vec![1]);
+        assert_eq!(Response::Aborted.to_frame(WireFormat::Legacy), vec![0]);
+    }
+}
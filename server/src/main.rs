@@ -1,23 +1,104 @@
 mod server;
 mod threadpool;
 
+use std::{path::PathBuf, time::Duration};
+
 use points::parse_addr;
-use server::Server;
+use server::{Config, Quorum, Replication, Server};
 use tracing::{error, Level};
 use tracing_subscriber::FmtSubscriber;
 
-fn parse_args() -> Result<(String, Option<String>), ()> {
-    let args: Vec<String> = std::env::args().collect();
+const LOCK_TTL_FLAG: &str = "--lock-ttl";
+const RECONNECT_GRACE_FLAG: &str = "--reconnect-grace";
+const DATA_DIR_FLAG: &str = "--data-dir";
+const SNAPSHOT_INTERVAL_FLAG: &str = "--snapshot-interval";
+const REPLICATION_FLAG: &str = "--replication";
+const QUORUM_FLAG: &str = "--quorum";
+
+/// Takes the optional flags out of the arguments, leaving only the positional ones.
+/// `--lock-ttl <seconds>` sets the lease of the locked points.
+/// `--reconnect-grace <seconds>` sets how long the locks of a dropped coffee maker are kept.
+/// `--data-dir <path>` sets the directory of the write-ahead log.
+/// `--snapshot-interval <seconds>` sets how often the points are snapshotted and the log compacted.
+/// `--replication <2pc|raft>` sets how the servers agree on the transactions, `2pc` by default.
+/// `--quorum <majority|all|<n>|regions:<address>=<region>,...>` sets the servers that must approve a transaction.
+fn parse_flags(args: &mut Vec<String>) -> Result<Config, ()> {
+    let mut config = Config::default();
+
+    if let Some(secs) = take_secs_flag(args, LOCK_TTL_FLAG)? {
+        config.lock_ttl = Duration::from_secs(secs);
+    }
+    if let Some(secs) = take_secs_flag(args, RECONNECT_GRACE_FLAG)? {
+        config.reconnect_grace = Duration::from_secs(secs);
+    }
+    if let Some(path) = take_flag(args, DATA_DIR_FLAG)? {
+        config.data_dir = Some(PathBuf::from(path));
+    }
+    if let Some(secs) = take_secs_flag(args, SNAPSHOT_INTERVAL_FLAG)? {
+        config.snapshot_interval = Duration::from_secs(secs);
+    }
+    if let Some(replication) = take_flag(args, REPLICATION_FLAG)? {
+        config.replication = match replication.as_str() {
+            "2pc" => Replication::TwoPhaseCommit,
+            "raft" => Replication::Raft,
+            _ => {
+                error!("{} expects 2pc or raft", REPLICATION_FLAG);
+                return Err(());
+            }
+        };
+    }
+    if let Some(quorum) = take_flag(args, QUORUM_FLAG)? {
+        config.quorum = quorum
+            .parse::<Quorum>()
+            .map_err(|e| error!("{} {}", QUORUM_FLAG, e))?;
+    }
+
+    Ok(config)
+}
+
+/// Removes the given flag and its amount of seconds from the arguments.
+fn take_secs_flag(args: &mut Vec<String>, flag: &str) -> Result<Option<u64>, ()> {
+    match take_flag(args, flag)? {
+        Some(secs) => secs
+            .parse::<u64>()
+            .ok()
+            .filter(|secs| *secs > 0)
+            .map(Some)
+            .ok_or_else(|| error!("{} expects a positive amount of seconds", flag)),
+        None => Ok(None),
+    }
+}
+
+/// Removes the given flag and its value from the arguments.
+fn take_flag(args: &mut Vec<String>, flag: &str) -> Result<Option<String>, ()> {
+    let pos = match args.iter().position(|arg| arg == flag) {
+        Some(pos) => pos,
+        None => return Ok(None),
+    };
+    let value = args
+        .get(pos + 1)
+        .cloned()
+        .ok_or_else(|| error!("{} expects a value", flag))?;
+    args.drain(pos..pos + 2);
+    Ok(Some(value))
+}
+
+fn parse_args() -> Result<(String, Option<String>, Config), ()> {
+    let mut args: Vec<String> = std::env::args().collect();
+    let config = parse_flags(&mut args)?;
     if args.len() == 2 {
-        return Ok((parse_addr(args[1].clone()), None));
+        return Ok((parse_addr(args[1].clone()), None, config));
     }
     if args.len() == 3 {
         return Ok((
             parse_addr(args[1].clone()),
             Some(parse_addr(args[2].clone())),
+            config,
         ));
     }
-    error!("Usage: local_server <address> [<known_server_address>]");
+    error!(
+        "Usage: local_server <address> [<known_server_address>] [--lock-ttl <seconds>] [--reconnect-grace <seconds>] [--data-dir <path>] [--snapshot-interval <seconds>] [--replication <2pc|raft>] [--quorum <policy>]"
+    );
     Err(())
 }
 
@@ -32,8 +113,14 @@ fn init_logger() {
 fn main() {
     init_logger();
 
-    if let Ok((addr, core_server_addr)) = parse_args() {
-        let server = Server::new(addr, core_server_addr);
+    if let Ok((addr, core_server_addr, config)) = parse_args() {
+        let server = match Server::new(addr, core_server_addr, config) {
+            Ok(server) => server,
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        };
         let handler = server.listen();
 
         handler.join().unwrap();
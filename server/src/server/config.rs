@@ -0,0 +1,53 @@
+use std::{path::PathBuf, time::Duration};
+
+use super::quorum::Quorum;
+
+/// Default time a coffee maker has to free or commit the points it locked.
+pub const DEFAULT_LOCK_TTL: Duration = Duration::from_secs(60);
+
+/// Default time a coffee maker has to connect again before the points it locked are freed.
+pub const DEFAULT_RECONNECT_GRACE: Duration = Duration::from_secs(10);
+
+/// Default time between snapshots of the points, after which the log is compacted.
+pub const DEFAULT_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How the servers agree on the transactions applied to the points.
+/// Every server of a cluster must use the same one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Replication {
+    /// The server that receives an order coordinates it with the others in a two-phase commit.
+    #[default]
+    TwoPhaseCommit,
+    /// The orders are appended to a log replicated by an elected leader, see `Raft`.
+    Raft,
+}
+
+/// Settings of a server, given through command line flags.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Lease of the locked points. Reservations not settled in time are freed by the server.
+    pub lock_ttl: Duration,
+    /// Time the reservations of a dropped connection are kept for the coffee maker to connect again.
+    /// Reservations of connections that did not identify their coffee maker are freed right away.
+    pub reconnect_grace: Duration,
+    /// Directory of the write-ahead log. Without it the server keeps its points only in memory.
+    pub data_dir: Option<PathBuf>,
+    /// Time between snapshots of the points, only taken when there is a data directory.
+    pub snapshot_interval: Duration,
+    pub replication: Replication,
+    /// Servers that must approve the transactions this server coordinates with a two-phase commit.
+    pub quorum: Quorum,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            lock_ttl: DEFAULT_LOCK_TTL,
+            reconnect_grace: DEFAULT_RECONNECT_GRACE,
+            data_dir: None,
+            snapshot_interval: DEFAULT_SNAPSHOT_INTERVAL,
+            replication: Replication::default(),
+            quorum: Quorum::default(),
+        }
+    }
+}
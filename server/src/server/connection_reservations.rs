@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+
+use points::{Message, OrderAction, Response};
+
+/// A reservation created over a client connection and not settled yet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeldReservation {
+    pub client_id: u64,
+    pub reservation: u64,
+    pub points: u64,
+}
+
+/// Reservations held by a client connection,
+/// released by the server if the connection drops before they are settled.
+#[derive(Debug, Default)]
+pub struct ConnectionReservations {
+    /// Id of the coffee maker, if it identified itself.
+    pub machine_id: Option<u64>,
+    held: HashMap<u64, HeldReservation>,
+}
+
+impl ConnectionReservations {
+    /// Keeps track of the reservations created or settled by a message, given the response to it.
+    pub fn track(&mut self, msg: &Message, response: Response) {
+        match (msg, response) {
+            (Message::LockOrder(order), Response::Reserved(reservation)) => {
+                self.held.insert(
+                    reservation,
+                    HeldReservation {
+                        client_id: order.client_id,
+                        reservation,
+                        points: order.action.points(),
+                    },
+                );
+            }
+            (Message::FreeOrder(order), Response::Ok)
+            | (Message::CommitOrder(order), Response::Ok) => {
+                if let Some(reservation) = order.reservation {
+                    self.held.remove(&reservation);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Fills in the reservation of a free or a consume received in a legacy frame, which can not carry it:
+    /// it settles one of the reservations held by the connection for the same client and points.
+    pub fn settle_legacy(&self, msg: Message) -> Message {
+        let order = match &msg {
+            Message::FreeOrder(order) | Message::CommitOrder(order)
+                if order.reservation.is_none()
+                    && matches!(order.action, OrderAction::UsePoints(_)) =>
+            {
+                order
+            }
+            _ => return msg,
+        };
+        let held = self
+            .held
+            .values()
+            .filter(|held| {
+                held.client_id == order.client_id && held.points == order.action.points()
+            })
+            .map(|held| held.reservation)
+            .min();
+        let order = match held {
+            Some(reservation) => order.clone().with_reservation(reservation),
+            None => return msg,
+        };
+        match msg {
+            Message::FreeOrder(_) => Message::FreeOrder(order),
+            _ => Message::CommitOrder(order),
+        }
+    }
+
+    /// Adds reservations held by a previous connection of the same coffee maker.
+    pub fn adopt(&mut self, reservations: Vec<HeldReservation>) {
+        for reservation in reservations {
+            self.held.insert(reservation.reservation, reservation);
+        }
+    }
+
+    /// Returns the reservations that were not settled over the connection.
+    pub fn into_held(self) -> Vec<HeldReservation> {
+        self.held.into_values().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use points::Order;
+
+    use super::*;
+
+    #[test]
+    fn test_track_reservations() {
+        let mut reservations = ConnectionReservations::default();
+        let order = Order::new(1, OrderAction::UsePoints(10));
+
+        reservations.track(&Message::LockOrder(order.clone()), Response::Reserved(1));
+        reservations.track(&Message::LockOrder(order.clone()), Response::Reserved(2));
+        reservations.track(&Message::LockOrder(order.clone()), Response::Aborted);
+        reservations.track(
+            &Message::CommitOrder(order.clone().with_reservation(1)),
+            Response::Ok,
+        );
+        reservations.track(
+            &Message::FreeOrder(order.with_reservation(2)),
+            Response::UnknownReservation,
+        );
+
+        assert_eq!(
+            reservations.into_held(),
+            vec![HeldReservation {
+                client_id: 1,
+                reservation: 2,
+                points: 10
+            }]
+        );
+    }
+
+    #[test]
+    fn test_settle_legacy_orders_with_a_held_reservation() {
+        let mut reservations = ConnectionReservations::default();
+        let order = Order::new(1, OrderAction::UsePoints(10));
+        reservations.track(&Message::LockOrder(order.clone()), Response::Reserved(7));
+
+        assert_eq!(
+            reservations.settle_legacy(Message::CommitOrder(order.clone())),
+            Message::CommitOrder(order.clone().with_reservation(7))
+        );
+        assert_eq!(
+            reservations.settle_legacy(Message::FreeOrder(order.clone())),
+            Message::FreeOrder(order.clone().with_reservation(7))
+        );
+        // Other points or clients have no reservation to settle
+        let other = Order::new(1, OrderAction::UsePoints(20));
+        assert_eq!(
+            reservations.settle_legacy(Message::CommitOrder(other.clone())),
+            Message::CommitOrder(other)
+        );
+        let fill = Order::new(1, OrderAction::FillPoints(10));
+        assert_eq!(
+            reservations.settle_legacy(Message::CommitOrder(fill.clone())),
+            Message::CommitOrder(fill)
+        );
+    }
+}
@@ -0,0 +1,26 @@
+use serde::{Deserialize, Serialize};
+
+use super::transaction::{Transaction, TransactionState};
+
+/// Outcome of a transaction decided by this server as its coordinator.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Decision {
+    pub transaction: Transaction,
+    pub commit: bool,
+    /// Servers that voted on the transaction and may not know the outcome yet.
+    pub participants: Vec<String>,
+}
+
+impl Decision {
+    pub fn new(
+        transaction: Transaction,
+        state: &TransactionState,
+        participants: Vec<String>,
+    ) -> Self {
+        Decision {
+            transaction,
+            commit: matches!(state, TransactionState::Proceed),
+            participants,
+        }
+    }
+}
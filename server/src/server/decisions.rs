@@ -0,0 +1,178 @@
+use std::sync::{Arc, Mutex};
+
+use super::{
+    decision::Decision,
+    outcomes::Outcomes,
+    point_record::Points,
+    transaction::Transaction,
+    wal::{Wal, WalEntry},
+};
+
+/// Decisions of the transactions coordinated by this server that some participant may not know.
+/// Every decision is written to the log before it is sent, so a coordinator that crashes
+/// before sending it sends it again after a restart.
+#[derive(Debug)]
+pub struct Decisions {
+    decisions: Mutex<Vec<Decision>>,
+    /// Where every decision is remembered, also after all participants received it.
+    outcomes: Arc<Outcomes>,
+    wal: Arc<Wal>,
+}
+
+impl Decisions {
+    /// Creates an empty table that records its changes in the given log
+    /// and remembers the decisions in the given outcomes.
+    pub fn new(wal: Arc<Wal>, outcomes: Arc<Outcomes>) -> Arc<Self> {
+        Arc::new(Decisions {
+            decisions: Mutex::new(vec![]),
+            outcomes,
+            wal,
+        })
+    }
+
+    /// Adds the decisions recovered from the log, without logging them again.
+    pub fn restore(&self, decisions: Vec<Decision>) {
+        for decision in &decisions {
+            self.outcomes.record(&decision.transaction, decision.commit);
+        }
+        self.decisions
+            .lock()
+            .expect("Could not lock decisions")
+            .extend(decisions);
+    }
+
+    /// Writes a decision to the log before it is sent to the participants.
+    /// A commit also writes the points the coordinator has after applying it,
+    /// and the amount of transactions applied with it.
+    /// A decision that could not be written is not taken: the transaction is aborted instead,
+    /// and the participants that ask for its outcome are told so.
+    pub fn decide(&self, decision: Decision, applied: Option<(Points, u64)>) -> Result<(), String> {
+        // Held while logging, so a snapshot never misses a decision already in the log
+        let mut decisions = self.decisions.lock().expect("Could not lock decisions");
+        let (points, committed) = match applied {
+            Some((points, committed)) => (Some(points), committed),
+            None => (None, 0),
+        };
+        let logged = self.wal.append(&WalEntry::Decide {
+            decision: decision.clone(),
+            points,
+            committed,
+        });
+        if let Err(e) = logged {
+            self.outcomes.record(&decision.transaction, false);
+            return Err(e);
+        }
+        self.outcomes.record(&decision.transaction, decision.commit);
+        decisions.push(decision);
+        Ok(())
+    }
+
+    /// Records that the decision of the transaction was sent to every participant but the given ones.
+    pub fn sent(&self, transaction: &Transaction, missing: Vec<String>) {
+        let mut decisions = self.decisions.lock().expect("Could not lock decisions");
+        self.update(&mut decisions, transaction, |_| missing);
+    }
+
+    /// Records that a participant received the decision of the transaction.
+    pub fn delivered_to(&self, transaction: &Transaction, participant: &str) {
+        let mut decisions = self.decisions.lock().expect("Could not lock decisions");
+        self.update(&mut decisions, transaction, |participants| {
+            participants
+                .iter()
+                .filter(|server| *server != participant)
+                .cloned()
+                .collect()
+        });
+    }
+
+    /// Replaces the participants that may not know the decision of the transaction,
+    /// forgetting the decision once every participant knows it.
+    fn update(
+        &self,
+        decisions: &mut Vec<Decision>,
+        transaction: &Transaction,
+        missing: impl FnOnce(&[String]) -> Vec<String>,
+    ) {
+        let position = match decisions
+            .iter()
+            .position(|decision| decision.transaction.same_as(transaction))
+        {
+            Some(position) => position,
+            None => return,
+        };
+        let missing = missing(&decisions[position].participants);
+        if missing.is_empty() {
+            self.wal
+                .append_or_stop(&WalEntry::Delivered(transaction.clone()));
+            decisions.remove(position);
+        } else {
+            decisions[position].participants = missing;
+        }
+    }
+
+    /// Returns the decisions that some participant may not know.
+    pub fn snapshot(&self) -> Vec<Decision> {
+        self.decisions
+            .lock()
+            .expect("Could not lock decisions")
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use points::{Message, Order, OrderAction};
+
+    use super::*;
+    use crate::server::transaction::TransactionState;
+
+    #[test]
+    fn test_abort_a_decision_the_log_can_not_keep() {
+        let outcomes = Outcomes::new();
+        let decisions = Decisions::new(Wal::failing(), outcomes.clone());
+        let message = Message::CommitOrder(Order::new(1, OrderAction::FillPoints(10)));
+        let transaction = Transaction::new("127.0.0.1:9000".to_string(), &message).unwrap();
+        let decision = Decision::new(
+            transaction.clone(),
+            &TransactionState::Proceed,
+            vec!["127.0.0.1:9001".to_string()],
+        );
+
+        assert!(decisions
+            .decide(decision, Some((Points(10, 0), 1)))
+            .is_err());
+        assert_eq!(outcomes.get(&transaction), Some(false));
+        assert!(decisions.snapshot().is_empty());
+    }
+
+    #[test]
+    fn test_forget_decisions_known_by_every_participant() {
+        let outcomes = Outcomes::new();
+        let decisions = Decisions::new(Wal::disabled(), outcomes.clone());
+        let message = Message::CommitOrder(Order::new(1, OrderAction::FillPoints(10)));
+        let transaction = Transaction::new("127.0.0.1:9000".to_string(), &message).unwrap();
+        let participants = vec!["127.0.0.1:9001".to_string(), "127.0.0.1:9002".to_string()];
+
+        decisions
+            .decide(
+                Decision::new(
+                    transaction.clone(),
+                    &TransactionState::Proceed,
+                    participants,
+                ),
+                Some((Points(10, 0), 1)),
+            )
+            .unwrap();
+        decisions.sent(&transaction, vec!["127.0.0.1:9002".to_string()]);
+        let undelivered = decisions.snapshot();
+        assert_eq!(undelivered.len(), 1);
+        assert!(undelivered[0].commit);
+        assert_eq!(undelivered[0].participants, vec!["127.0.0.1:9002"]);
+
+        decisions.delivered_to(&transaction, "127.0.0.1:9001");
+        assert_eq!(decisions.snapshot().len(), 1);
+        decisions.delivered_to(&transaction, "127.0.0.1:9002");
+        assert!(decisions.snapshot().is_empty());
+        assert_eq!(outcomes.get(&transaction), Some(true));
+    }
+}
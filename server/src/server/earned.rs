@@ -0,0 +1,57 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Points added to an account by each server while it could not reach the others.
+/// Each server only grows its own count, so two counters merge by keeping the greatest count
+/// of every server, no matter the order or how many times they are merged.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Earned(HashMap<String, u64>);
+
+impl Earned {
+    /// Counts points added by the given server.
+    pub fn add(&mut self, server: &str, points: u64) {
+        *self.0.entry(server.to_string()).or_insert(0) += points;
+    }
+
+    /// Merges the counts of another counter into this one.
+    /// Returns the amount of points this counter did not know about.
+    pub fn merge(&mut self, other: &Earned) -> u64 {
+        let mut gained = 0;
+        for (server, points) in &other.0 {
+            let known = self.0.entry(server.clone()).or_insert(0);
+            if *points > *known {
+                gained += *points - *known;
+                *known = *points;
+            }
+        }
+        gained
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_counts_every_point_once() {
+        let mut first = Earned::default();
+        first.add("127.0.0.1:9000", 10);
+        let mut second = Earned::default();
+        second.add("127.0.0.1:9001", 5);
+        second.add("127.0.0.1:9000", 4);
+
+        assert_eq!(first.merge(&second), 5);
+        assert_eq!(first.merge(&second), 0);
+
+        second.add("127.0.0.1:9000", 8);
+        assert_eq!(second.merge(&first), 0);
+        assert_eq!(first.merge(&second), 2);
+        assert_eq!(first, second);
+    }
+}
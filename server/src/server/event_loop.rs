@@ -0,0 +1,564 @@
+use std::{
+    collections::HashMap,
+    io::{self, Cursor, Read, Write},
+    net::{TcpListener, TcpStream},
+    os::unix::io::AsRawFd,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread,
+};
+
+use mio::{unix::SourceFd, Events, Interest, Poll, Token, Waker};
+use points::{
+    DecodeError, Frame, Response, WireFormat, CLIENT_CONNECTION, CONTROL_MESSAGE, MAX_FRAME_SIZE,
+    SERVER_MESSAGE,
+};
+use tracing::{debug, error};
+
+use super::{
+    connection_reservations::ConnectionReservations, link::Link, point_storage::PointStorage,
+    Server,
+};
+use crate::threadpool::ThreadPool;
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+const FIRST_CONNECTION: usize = 2;
+
+/// Most readiness events handled on each turn of the loop.
+const EVENTS_CAPACITY: usize = 1024;
+
+const READ_BUFFER_SIZE: usize = 4096;
+
+/// Most received bytes kept for a coffee maker connection, enough for a couple of whole frames.
+/// The loop stops reading the connection until its messages are handled, so it does not fill the memory.
+const MAX_INPUT: usize = 2 * MAX_FRAME_SIZE as usize;
+
+/// State of a coffee maker connection kept between its messages.
+#[derive(Debug)]
+pub struct ClientSession {
+    pub addr: String,
+    pub reservations: ConnectionReservations,
+}
+
+/// Answer to a message of a coffee maker, handled on the thread pool.
+struct Reply {
+    token: Token,
+    session: ClientSession,
+    bytes: Vec<u8>,
+}
+
+#[derive(Debug)]
+struct Connection {
+    stream: TcpStream,
+    /// Whether the first byte said it is a coffee maker connection.
+    client: bool,
+    /// `None` while a message of the connection is being handled.
+    session: Option<ClientSession>,
+    /// Received bytes not handled yet, at most `MAX_INPUT` plus a read.
+    input: Vec<u8>,
+    /// Bytes of the replies not written yet.
+    output: Vec<u8>,
+    /// Nothing more is read from the connection, it is closed once its replies are written.
+    closing: bool,
+    writable: bool,
+}
+
+/// Non-blocking readiness loop that accepts the connections and serves the coffee makers.
+/// A coffee maker connection takes no thread while it is idle: the loop reads its frames
+/// and only sends a message to the thread pool once it is whole, one at a time per connection,
+/// writing the reply when the pool is done with it.
+/// Links from other servers are read on a thread of their own, one per server, and their exchanges
+/// are handled on a pool of their own. Control messages are short lived.
+/// Both are taken out of the loop once their first byte is read.
+pub struct EventLoop {
+    poll: Poll,
+    listener: TcpListener,
+    connections: HashMap<Token, Connection>,
+    next_token: usize,
+    storage: Arc<PointStorage>,
+    thread_pool: ThreadPool,
+    /// Handles the exchanges of the links from other servers.
+    link_pool: ThreadPool,
+    waker: Arc<Waker>,
+    replies: Receiver<Reply>,
+    reply_sender: Sender<Reply>,
+}
+
+impl EventLoop {
+    pub fn new(
+        listener: TcpListener,
+        storage: Arc<PointStorage>,
+        thread_pool: ThreadPool,
+        link_pool: ThreadPool,
+    ) -> io::Result<Self> {
+        let poll = Poll::new()?;
+        listener.set_nonblocking(true)?;
+        poll.registry().register(
+            &mut SourceFd(&listener.as_raw_fd()),
+            LISTENER,
+            Interest::READABLE,
+        )?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let (reply_sender, replies) = mpsc::channel();
+
+        Ok(EventLoop {
+            poll,
+            listener,
+            connections: HashMap::new(),
+            next_token: FIRST_CONNECTION,
+            storage,
+            thread_pool,
+            link_pool,
+            waker,
+            replies,
+            reply_sender,
+        })
+    }
+
+    /// Serves the connections until polling fails.
+    pub fn run(mut self) -> io::Result<()> {
+        let mut events = Events::with_capacity(EVENTS_CAPACITY);
+        loop {
+            if let Err(e) = self.poll.poll(&mut events, None) {
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(e);
+            }
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => self.accept(),
+                    WAKER => self.receive_replies(),
+                    token => {
+                        if event.is_readable() || event.is_read_closed() || event.is_error() {
+                            self.read(token);
+                        }
+                        if event.is_writable() {
+                            self.write(token);
+                        }
+                        self.advance(token);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Accepts every pending connection.
+    fn accept(&mut self) {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    error!("Could not accept connection: {}", e);
+                    return;
+                }
+            };
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            let registered = stream.set_nonblocking(true).and_then(|_| {
+                self.poll.registry().register(
+                    &mut SourceFd(&stream.as_raw_fd()),
+                    token,
+                    Interest::READABLE,
+                )
+            });
+            if let Err(e) = registered {
+                error!("Could not register connection: {}", e);
+                continue;
+            }
+            self.connections.insert(
+                token,
+                Connection {
+                    stream,
+                    client: false,
+                    session: None,
+                    input: vec![],
+                    output: vec![],
+                    closing: false,
+                    writable: false,
+                },
+            );
+        }
+    }
+
+    /// Reads what the connection received.
+    /// The first byte tells the kind of connection, only a coffee maker connection stays in the loop.
+    fn read(&mut self, token: Token) {
+        let conn = match self.connections.get_mut(&token) {
+            Some(conn) if !conn.closing => conn,
+            _ => return,
+        };
+
+        if !conn.client {
+            let mut first = [0; 1];
+            match conn.stream.read(&mut first) {
+                Ok(0) => conn.closing = true,
+                Ok(_) => match first[0] {
+                    CLIENT_CONNECTION => {
+                        let addr = conn
+                            .stream
+                            .peer_addr()
+                            .map(|addr| addr.to_string())
+                            .unwrap_or_default();
+                        debug!("Connection established with {}", addr);
+                        conn.client = true;
+                        conn.session = Some(ClientSession {
+                            addr,
+                            reservations: ConnectionReservations::default(),
+                        });
+                    }
+                    SERVER_MESSAGE => return self.serve_link(token),
+                    CONTROL_MESSAGE => {
+                        return self.hand_off(token, Server::control_message_handler)
+                    }
+                    _ => {
+                        error!("Unknown message type");
+                        conn.closing = true;
+                    }
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(_) => conn.closing = true,
+            }
+            if conn.closing {
+                return;
+            }
+        }
+
+        // The rest is read once the messages received are handled
+        let mut buf = [0; READ_BUFFER_SIZE];
+        while conn.input.len() < MAX_INPUT {
+            match conn.stream.read(&mut buf) {
+                Ok(0) => {
+                    conn.closing = true;
+                    return;
+                }
+                Ok(n) => conn.input.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => {
+                    conn.closing = true;
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Takes the connection out of the loop and handles the rest of it on the thread pool.
+    fn hand_off(&mut self, token: Token, handler: fn(TcpStream, Arc<PointStorage>)) {
+        let stream = match self.take_blocking(token) {
+            Some(stream) => stream,
+            None => return,
+        };
+        let storage = self.storage.clone();
+        self.thread_pool.execute(move || handler(stream, storage));
+    }
+
+    /// Takes the link another server connected out of the loop and reads it on a thread of its own,
+    /// handling its exchanges on the link pool.
+    /// A participant waits for the turn of a record and for the decision of its coordinator,
+    /// so the exchanges never wait behind the coffee makers on the thread pool.
+    fn serve_link(&mut self, token: Token) {
+        let stream = match self.take_blocking(token) {
+            Some(stream) => stream,
+            None => return,
+        };
+        let storage = self.storage.clone();
+        let link_pool = self.link_pool.clone();
+        thread::spawn(move || {
+            Link::serve(stream, |exchange| {
+                let storage = storage.clone();
+                link_pool.execute(move || Server::server_message_handler(exchange, storage));
+            })
+        });
+    }
+
+    /// Takes the connection out of the loop, making it blocking.
+    fn take_blocking(&mut self, token: Token) -> Option<TcpStream> {
+        let conn = self.connections.remove(&token)?;
+        if let Err(e) = self
+            .poll
+            .registry()
+            .deregister(&mut SourceFd(&conn.stream.as_raw_fd()))
+        {
+            error!("Could not deregister connection: {}", e);
+        }
+        if let Err(e) = conn.stream.set_nonblocking(false) {
+            error!("Could not hand off connection: {}", e);
+            return None;
+        }
+        Some(conn.stream)
+    }
+
+    /// Writes the pending replies of the connection, as much as it accepts without blocking.
+    fn write(&mut self, token: Token) {
+        let conn = match self.connections.get_mut(&token) {
+            Some(conn) => conn,
+            None => return,
+        };
+        while !conn.output.is_empty() {
+            match conn.stream.write(&conn.output) {
+                Ok(0) => {
+                    conn.output.clear();
+                    conn.closing = true;
+                }
+                Ok(n) => {
+                    conn.output.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => {
+                    error!("Failed to send response");
+                    conn.output.clear();
+                    conn.closing = true;
+                }
+            }
+        }
+
+        // Only asks to be told when it can write while some reply is left
+        let writable = !conn.output.is_empty();
+        if writable != conn.writable {
+            let interest = if writable {
+                Interest::READABLE | Interest::WRITABLE
+            } else {
+                Interest::READABLE
+            };
+            if let Err(e) = self.poll.registry().reregister(
+                &mut SourceFd(&conn.stream.as_raw_fd()),
+                token,
+                interest,
+            ) {
+                error!("Could not reregister connection: {}", e);
+            }
+            conn.writable = writable;
+        }
+    }
+
+    /// Sends the next whole frame of the connection to the thread pool if none is being handled,
+    /// or closes the connection once nothing is left to do with it.
+    fn advance(&mut self, token: Token) {
+        let conn = match self.connections.get_mut(&token) {
+            Some(conn) => conn,
+            None => return,
+        };
+        let mut session = match conn.session.take() {
+            Some(session) => session,
+            // Waits for the reply to the message being handled
+            None if conn.client => return,
+            None => {
+                if conn.closing {
+                    self.close(token);
+                }
+                return;
+            }
+        };
+
+        let frame = match take_frame(&mut conn.input) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => {
+                conn.session = Some(session);
+                if conn.closing && conn.output.is_empty() {
+                    self.close(token);
+                }
+                return;
+            }
+            Err(e) => {
+                // The stream can not be resynchronized, so the connection is closed
+                error!("Invalid frame from {}: {}", session.addr, e);
+                conn.session = Some(session);
+                conn.input.clear();
+                conn.output
+                    .extend(Response::Malformed.to_frame(WireFormat::Versioned));
+                conn.closing = true;
+                self.write(token);
+                return self.advance(token);
+            }
+        };
+
+        let storage = self.storage.clone();
+        let replies = self.reply_sender.clone();
+        let waker = self.waker.clone();
+        self.thread_pool.execute(move || {
+            let bytes = Server::handle_client_frame(frame, &mut session, storage);
+            let reply = Reply {
+                token,
+                session,
+                bytes,
+            };
+            if replies.send(reply).is_ok() {
+                if let Err(e) = waker.wake() {
+                    error!("Could not wake the event loop: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Writes the replies handled by the thread pool and goes on with their connections.
+    fn receive_replies(&mut self) {
+        while let Ok(reply) = self.replies.try_recv() {
+            match self.connections.get_mut(&reply.token) {
+                Some(conn) => {
+                    conn.session = Some(reply.session);
+                    conn.output.extend(reply.bytes);
+                    self.write(reply.token);
+                    // Reads what was left in the stream while the input was full
+                    self.read(reply.token);
+                    self.advance(reply.token);
+                }
+                None => self.finish(reply.session),
+            }
+        }
+    }
+
+    fn close(&mut self, token: Token) {
+        let conn = match self.connections.remove(&token) {
+            Some(conn) => conn,
+            None => return,
+        };
+        if let Err(e) = self
+            .poll
+            .registry()
+            .deregister(&mut SourceFd(&conn.stream.as_raw_fd()))
+        {
+            error!("Could not deregister connection: {}", e);
+        }
+        if let Some(session) = conn.session {
+            self.finish(session);
+        }
+    }
+
+    /// Releases what a closed coffee maker connection held, on the thread pool
+    /// since it may take a distributed transaction.
+    fn finish(&self, session: ClientSession) {
+        let storage = self.storage.clone();
+        self.thread_pool
+            .execute(move || Server::close_client_session(session, storage));
+    }
+}
+
+/// Takes the first whole frame out of the received bytes.
+/// Returns `None` if the bytes do not make a whole frame yet.
+fn take_frame(input: &mut Vec<u8>) -> Result<Option<Frame>, DecodeError> {
+    let mut cursor = Cursor::new(&input[..]);
+    match Frame::read_from(&mut cursor) {
+        Ok(frame) => {
+            let read = cursor.position() as usize;
+            input.drain(..read);
+            Ok(Some(frame))
+        }
+        Err(DecodeError::Io(io::ErrorKind::UnexpectedEof)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use points::{encode_frame, BalanceRead, Message, Order, OrderAction};
+
+    use super::*;
+    use crate::server::{config::Config, ping::ping_to, snapshot::Snapshot, wal::Wal};
+
+    #[test]
+    fn test_answer_every_frame_of_a_full_input() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let storage = PointStorage::new(
+            addr.to_string(),
+            None,
+            Wal::disabled(),
+            Snapshot::default(),
+            &Config::default(),
+        )
+        .unwrap();
+        let event_loop =
+            EventLoop::new(listener, storage, ThreadPool::new(2), ThreadPool::new(1)).unwrap();
+        thread::spawn(move || event_loop.run());
+
+        // Sent at once, so the loop stops reading the connection while it handles them
+        let query = Message::QueryBalance(1, BalanceRead::Local).to_frame();
+        let queries = 2 * MAX_INPUT / query.len();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let sender = thread::spawn(move || {
+            writer.write_all(&[CLIENT_CONNECTION]).unwrap();
+            writer.write_all(&query.repeat(queries)).unwrap();
+        });
+
+        for _ in 0..queries {
+            assert!(matches!(
+                Response::read_from(&mut stream).unwrap(),
+                Response::Balance(_)
+            ));
+        }
+        sender.join().unwrap();
+    }
+
+    #[test]
+    fn test_handle_the_exchanges_of_a_link_on_the_link_pool() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let storage = PointStorage::new(
+            addr.clone(),
+            None,
+            Wal::disabled(),
+            Snapshot::default(),
+            &Config::default(),
+        )
+        .unwrap();
+        let event_loop =
+            EventLoop::new(listener, storage, ThreadPool::new(1), ThreadPool::new(1)).unwrap();
+        thread::spawn(move || event_loop.run());
+
+        // Many exchanges at once over the same link, all of them handled by a single thread
+        let pings: Vec<_> = (0..20)
+            .map(|_| {
+                let addr = addr.clone();
+                thread::spawn(move || ping_to(&addr))
+            })
+            .collect();
+        for ping in pings {
+            assert_eq!(ping.join().unwrap(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_take_frames_as_they_arrive() {
+        let order = Order::new(1, OrderAction::UsePoints(10));
+        let first = Message::LockOrder(order.clone()).to_frame();
+        let second = encode_frame(&[7; 3]);
+        let mut wire = first.clone();
+        wire.extend(&second);
+
+        let mut input = vec![];
+        let mut frames = vec![];
+        for byte in wire {
+            input.push(byte);
+            if let Some(frame) = take_frame(&mut input).unwrap() {
+                frames.push(frame);
+            }
+        }
+
+        assert!(input.is_empty());
+        assert_eq!(frames.len(), 2);
+        assert_eq!(
+            Message::try_from(frames.remove(0)).unwrap(),
+            Message::LockOrder(order)
+        );
+        assert_eq!(frames[0], Frame::Versioned(vec![7; 3]));
+    }
+
+    #[test]
+    fn test_invalid_frame() {
+        let mut input = vec![0x42, 0, 0];
+        assert_eq!(
+            take_frame(&mut input),
+            Err(DecodeError::UnknownVersion(0x42))
+        );
+    }
+}
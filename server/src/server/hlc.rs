@@ -0,0 +1,155 @@
+use std::{
+    fmt,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Clock of this server, shared by every thread.
+/// It timestamps the transactions and every message sent to another server.
+pub static CLOCK: HybridClock = HybridClock::new();
+
+/// Timestamp of a hybrid logical clock.
+/// Timestamps are ordered by their physical time, and then by their logical counter.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct Timestamp {
+    /// Greatest wall-clock time known when the timestamp was taken, in milliseconds since the epoch.
+    pub physical: u64,
+    /// Tells apart the events that happened with the same physical time.
+    pub logical: u32,
+}
+
+impl Timestamp {
+    /// Size of a timestamp as bytes.
+    pub const LEN: usize = 12;
+
+    pub fn to_be_bytes(self) -> [u8; Self::LEN] {
+        let mut bytes = [0; Self::LEN];
+        bytes[..8].copy_from_slice(&self.physical.to_be_bytes());
+        bytes[8..].copy_from_slice(&self.logical.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_be_bytes(bytes: [u8; Self::LEN]) -> Self {
+        let mut physical = [0; 8];
+        let mut logical = [0; 4];
+        physical.copy_from_slice(&bytes[..8]);
+        logical.copy_from_slice(&bytes[8..]);
+        Timestamp {
+            physical: u64::from_be_bytes(physical),
+            logical: u32::from_be_bytes(logical),
+        }
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.physical, self.logical)
+    }
+}
+
+/// Hybrid logical clock: follows the wall clock of the machine, but never goes backwards
+/// and always moves past the timestamps received from other servers.
+/// An event that causes another one always has a smaller timestamp, even if the clocks of the machines are skewed.
+#[derive(Debug)]
+pub struct HybridClock {
+    last: Mutex<Timestamp>,
+}
+
+impl HybridClock {
+    pub const fn new() -> Self {
+        HybridClock {
+            last: Mutex::new(Timestamp {
+                physical: 0,
+                logical: 0,
+            }),
+        }
+    }
+
+    /// Returns a new timestamp, greater than every previous one of this clock.
+    pub fn now(&self) -> Timestamp {
+        self.tick(wall_clock(), None)
+    }
+
+    /// Moves the clock past a timestamp received from another server.
+    pub fn update(&self, received: Timestamp) -> Timestamp {
+        self.tick(wall_clock(), Some(received))
+    }
+
+    fn tick(&self, wall: u64, received: Option<Timestamp>) -> Timestamp {
+        let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+        let received = received.unwrap_or_default();
+        let physical = wall.max(last.physical).max(received.physical);
+
+        let logical = if physical == last.physical && physical == received.physical {
+            last.logical.max(received.logical) + 1
+        } else if physical == last.physical {
+            last.logical + 1
+        } else if physical == received.physical {
+            received.logical + 1
+        } else {
+            0
+        };
+
+        *last = Timestamp { physical, logical };
+        *last
+    }
+}
+
+/// Returns the time of the machine, in milliseconds since the epoch.
+fn wall_clock() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(physical: u64, logical: u32) -> Timestamp {
+        Timestamp { physical, logical }
+    }
+
+    #[test]
+    fn test_clock_never_goes_backwards() {
+        let clock = HybridClock::new();
+        let first = clock.tick(2_000, None);
+        // The wall clock of the machine went backwards
+        let second = clock.tick(1_000, None);
+        let third = clock.tick(2_000, None);
+
+        assert_eq!(first, at(2_000, 0));
+        assert!(first < second && second < third);
+        assert_eq!(third, at(2_000, 2));
+        assert_eq!(clock.tick(3_000, None), at(3_000, 0));
+    }
+
+    #[test]
+    fn test_clock_moves_past_the_received_timestamps() {
+        let clock = HybridClock::new();
+        clock.tick(1_000, None);
+
+        // Another server with its clock ahead
+        let received = at(5_000, 3);
+        let updated = clock.tick(1_000, Some(received));
+        assert!(received < updated);
+        assert!(updated < clock.tick(1_000, None));
+
+        // Another server with its clock behind
+        let received = at(500, 9);
+        let updated = clock.tick(1_000, Some(received));
+        assert!(received < updated);
+        assert_eq!(updated, at(5_000, 6));
+    }
+
+    #[test]
+    fn test_timestamp_bytes() {
+        let timestamp = at(1_700_000_000_000, 42);
+        assert_eq!(Timestamp::from_be_bytes(timestamp.to_be_bytes()), timestamp);
+    }
+}
@@ -0,0 +1,127 @@
+use std::sync::{Arc, Mutex};
+
+use super::{
+    record_queue::Turn,
+    transaction::Transaction,
+    wal::{Wal, WalEntry},
+};
+
+/// Transactions this server voted to commit as a participant but whose decision it did not receive,
+/// e.g. because the coordinator crashed before sending it.
+/// They stay in doubt until the coordinator or another server tells their outcome,
+/// holding the turn of their record and the reservation they settle, as the coordinator may have committed them.
+#[derive(Debug)]
+pub struct InDoubt {
+    transactions: Mutex<Vec<Doubt>>,
+    wal: Arc<Wal>,
+}
+
+#[derive(Debug)]
+struct Doubt {
+    transaction: Transaction,
+    /// Whether its outcome is being applied.
+    resolving: bool,
+    /// Turn of the record, released once the outcome is applied.
+    _turn: Option<Turn>,
+}
+
+impl InDoubt {
+    /// Creates an empty table that records its changes in the given log.
+    pub fn new(wal: Arc<Wal>) -> Arc<Self> {
+        Arc::new(InDoubt {
+            transactions: Mutex::new(vec![]),
+            wal,
+        })
+    }
+
+    /// Adds the transactions recovered from the log with the turns taken again, without logging them again.
+    pub fn restore(&self, transactions: Vec<(Transaction, Option<Turn>)>) {
+        self.transactions
+            .lock()
+            .expect("Could not lock in doubt transactions")
+            .extend(transactions.into_iter().map(|(transaction, turn)| Doubt {
+                transaction,
+                resolving: false,
+                _turn: turn,
+            }));
+    }
+
+    /// Adds a transaction in doubt, holding the turn of its record until it is resolved.
+    pub fn add(&self, transaction: Transaction, turn: Option<Turn>) {
+        let mut transactions = self
+            .transactions
+            .lock()
+            .expect("Could not lock in doubt transactions");
+        self.wal
+            .append_or_stop(&WalEntry::InDoubt(transaction.clone()));
+        transactions.push(Doubt {
+            transaction,
+            resolving: false,
+            _turn: turn,
+        });
+    }
+
+    /// Takes the given transaction to apply its outcome, so no other thread applies it meanwhile.
+    /// Returns whether the transaction was in doubt and not taken yet.
+    /// It stays in the table until it is `resolved`.
+    pub fn take(&self, transaction: &Transaction) -> bool {
+        let mut transactions = self
+            .transactions
+            .lock()
+            .expect("Could not lock in doubt transactions");
+        match transactions
+            .iter_mut()
+            .find(|doubt| !doubt.resolving && doubt.transaction.same_as(transaction))
+        {
+            Some(doubt) => {
+                doubt.resolving = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Records that the outcome of a taken transaction was applied, releasing the turn of its record.
+    pub fn resolved(&self, transaction: &Transaction) {
+        let mut transactions = self
+            .transactions
+            .lock()
+            .expect("Could not lock in doubt transactions");
+        self.wal
+            .append_or_stop(&WalEntry::Resolved(transaction.clone()));
+        transactions.retain(|doubt| !doubt.transaction.same_as(transaction));
+    }
+
+    /// Returns the transactions in doubt, including the ones being resolved.
+    pub fn snapshot(&self) -> Vec<Transaction> {
+        self.transactions
+            .lock()
+            .expect("Could not lock in doubt transactions")
+            .iter()
+            .map(|doubt| doubt.transaction.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use points::{Message, Order, OrderAction};
+
+    use super::*;
+
+    #[test]
+    fn test_resolve_a_transaction_once() {
+        let in_doubt = InDoubt::new(Wal::disabled());
+        let message = Message::CommitOrder(Order::new(1, OrderAction::FillPoints(10)));
+        let transaction = Transaction::new("127.0.0.1:9000".to_string(), &message).unwrap();
+        in_doubt.add(transaction.clone(), None);
+
+        assert!(in_doubt.take(&transaction));
+        assert!(!in_doubt.take(&transaction));
+        assert_eq!(in_doubt.snapshot().len(), 1);
+
+        in_doubt.resolved(&transaction);
+        assert!(in_doubt.snapshot().is_empty());
+        assert!(!in_doubt.take(&transaction));
+    }
+}
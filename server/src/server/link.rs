@@ -0,0 +1,527 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Condvar, LazyLock, Mutex, MutexGuard,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use points::SERVER_MESSAGE;
+use tracing::{debug, trace, warn};
+
+/// Links of this server to the other servers, shared by every message sent to them.
+pub static LINKS: LazyLock<Links> = LazyLock::new(Links::default);
+
+/// Frame with bytes of an exchange.
+const DATA: u8 = 0;
+/// Frame that ends an exchange, like closing a connection.
+const CLOSE: u8 = 1;
+/// Id of the exchange (`u64`), kind of frame (`u8`) and payload length (`u32`).
+const HEADER_LEN: usize = 13;
+/// Maximum payload length accepted when reading a frame, a full sync may be large.
+pub const MAX_PAYLOAD: u32 = 64 * 1024 * 1024;
+
+/// Longest wait for a server to accept a link, a server that does not is taken as down.
+const CONNECT_TIMEOUT: Duration = Duration::from_millis(500);
+/// Wait before connecting again to a server after failing to, doubled on each failure.
+const RECONNECT_BACKOFF: Duration = Duration::from_millis(50);
+const RECONNECT_MAX_BACKOFF: Duration = Duration::from_millis(1000);
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Long lived connection with another server that carries many exchanges at once.
+/// Every frame carries the id of its exchange, so concurrent requests do not wait for each other.
+/// A server opens its exchanges over the link it connected, and answers the ones of the other
+/// server over the link the other server connected.
+#[derive(Debug)]
+pub struct Link {
+    peer: String,
+    writer: Mutex<TcpStream>,
+    /// Exchanges waiting for frames, by id.
+    routes: Mutex<HashMap<u64, Sender<Vec<u8>>>>,
+    /// Ended exchanges, a frame for one of them is late and does not start a new one.
+    finished: Mutex<Finished>,
+    next_id: AtomicU64,
+    open: AtomicBool,
+}
+
+impl Link {
+    fn new(peer: String, stream: &TcpStream) -> io::Result<Arc<Self>> {
+        // The frames are small and answered right away, they are not worth batching
+        stream.set_nodelay(true)?;
+        Ok(Arc::new(Link {
+            peer,
+            writer: Mutex::new(stream.try_clone()?),
+            routes: Mutex::new(HashMap::new()),
+            finished: Mutex::new(Finished::default()),
+            next_id: AtomicU64::new(0),
+            open: AtomicBool::new(true),
+        }))
+    }
+
+    /// Connects a link to the given server, reading its answers from a thread of its own.
+    fn connect(addr: &str) -> io::Result<Arc<Self>> {
+        let mut last_error =
+            io::Error::new(io::ErrorKind::InvalidInput, "No address to connect to");
+        let mut connected = None;
+        for socket in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&socket, CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    connected = Some(stream);
+                    break;
+                }
+                Err(e) => last_error = e,
+            }
+        }
+        let mut stream = connected.ok_or(last_error)?;
+        stream.write_all(&[SERVER_MESSAGE])?;
+        let link = Link::new(addr.to_string(), &stream)?;
+
+        let reader = link.clone();
+        thread::spawn(move || reader.read_frames(stream, |_| {}));
+        debug!("Connected link to {}", addr);
+        Ok(link)
+    }
+
+    /// Serves a link another server connected to this one, until it drops.
+    /// Each exchange the other server opens is given to `on_exchange`.
+    pub fn serve(stream: TcpStream, on_exchange: impl FnMut(Exchange)) {
+        let peer = stream
+            .peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default();
+        match Link::new(peer, &stream) {
+            Ok(link) => link.read_frames(stream, on_exchange),
+            Err(e) => warn!("Could not serve link: {}", e),
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        self.open.load(Ordering::SeqCst)
+    }
+
+    /// Opens a new exchange over the link.
+    fn open_exchange(self: &Arc<Self>) -> Exchange {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.exchange(id)
+    }
+
+    fn exchange(self: &Arc<Self>, id: u64) -> Exchange {
+        let (sender, incoming) = mpsc::channel();
+        lock(&self.routes).insert(id, sender);
+        Exchange {
+            link: self.clone(),
+            id,
+            incoming,
+            buf: vec![],
+            pos: 0,
+            read_timeout: None,
+        }
+    }
+
+    fn send(&self, id: u64, kind: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+        frame.extend_from_slice(&id.to_be_bytes());
+        frame.push(kind);
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(payload);
+
+        let mut writer = lock(&self.writer);
+        if let Err(e) = writer.write_all(&frame) {
+            self.close();
+            let _ = writer.shutdown(Shutdown::Both);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Reads the frames of the link until it drops, handing them to their exchanges.
+    fn read_frames(self: Arc<Self>, mut stream: TcpStream, mut on_exchange: impl FnMut(Exchange)) {
+        loop {
+            let (id, kind, payload) = match Self::read_frame(&mut stream) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    debug!("Link with {} dropped: {}", self.peer, e);
+                    break;
+                }
+            };
+            trace!("Frame {} of exchange {} with {}", kind, id, self.peer);
+            match kind {
+                DATA => {
+                    let route = lock(&self.routes).get(&id).cloned();
+                    match route {
+                        Some(route) => {
+                            // The exchange may be dropping, e.g. after a timeout
+                            let _ = route.send(payload);
+                        }
+                        None if !lock(&self.finished).contains(id) => {
+                            let exchange = self.exchange(id);
+                            if let Some(route) = lock(&self.routes).get(&id) {
+                                let _ = route.send(payload);
+                            }
+                            on_exchange(exchange);
+                        }
+                        None => trace!("Dropping late frame of exchange {}", id),
+                    }
+                }
+                _ => {
+                    lock(&self.routes).remove(&id);
+                    lock(&self.finished).insert(id);
+                }
+            }
+        }
+
+        self.close();
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+
+    fn read_frame(stream: &mut TcpStream) -> io::Result<(u64, u8, Vec<u8>)> {
+        let mut header = [0; HEADER_LEN];
+        stream.read_exact(&mut header)?;
+        let mut id = [0; 8];
+        id.copy_from_slice(&header[..8]);
+        let mut len = [0; 4];
+        len.copy_from_slice(&header[9..]);
+        let len = u32::from_be_bytes(len);
+        if len > MAX_PAYLOAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Frame of {} bytes is too long", len),
+            ));
+        }
+
+        let mut payload = vec![0; len as usize];
+        stream.read_exact(&mut payload)?;
+        Ok((u64::from_be_bytes(id), header[8], payload))
+    }
+
+    /// Marks the link as dropped, ending every exchange waiting for it.
+    fn close(&self) {
+        self.open.store(false, Ordering::SeqCst);
+        lock(&self.routes).clear();
+    }
+}
+
+/// Ids of the ended exchanges of a link.
+/// Every exchange ends, so the ids below the first one still going are kept as a bound.
+#[derive(Debug, Default)]
+struct Finished {
+    below: u64,
+    ids: BTreeSet<u64>,
+}
+
+impl Finished {
+    fn insert(&mut self, id: u64) {
+        if id < self.below {
+            return;
+        }
+        self.ids.insert(id);
+        while self.ids.remove(&self.below) {
+            self.below += 1;
+        }
+    }
+
+    fn contains(&self, id: u64) -> bool {
+        id < self.below || self.ids.contains(&id)
+    }
+}
+
+/// One request to another server and the messages that follow it, over a link.
+/// It reads and writes like a connection of its own: what one side writes the other one reads,
+/// and dropping it ends the exchange, so the other side reads the end of the stream.
+#[derive(Debug)]
+pub struct Exchange {
+    link: Arc<Link>,
+    id: u64,
+    incoming: Receiver<Vec<u8>>,
+    buf: Vec<u8>,
+    pos: usize,
+    read_timeout: Option<Duration>,
+}
+
+impl Exchange {
+    /// Sets how long a read waits for the other server before failing, forever by default.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        self.read_timeout = timeout;
+        Ok(())
+    }
+
+    /// Address of the other server.
+    pub fn peer(&self) -> &str {
+        &self.link.peer
+    }
+}
+
+impl Read for Exchange {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.buf.len() {
+            let received = match self.read_timeout {
+                Some(timeout) => self.incoming.recv_timeout(timeout),
+                None => self
+                    .incoming
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match received {
+                Ok(payload) => {
+                    self.buf = payload;
+                    self.pos = 0;
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("No answer from {}", self.link.peer),
+                    ))
+                }
+                // The other side ended the exchange, or the link dropped
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+        }
+
+        let read = out.len().min(self.buf.len() - self.pos);
+        out[..read].copy_from_slice(&self.buf[self.pos..self.pos + read]);
+        self.pos += read;
+        Ok(read)
+    }
+}
+
+impl Write for Exchange {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.link.is_open() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                format!("Link with {} dropped", self.link.peer),
+            ));
+        }
+        self.link.send(self.id, DATA, buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Exchange {
+    fn drop(&mut self) {
+        lock(&self.link.routes).remove(&self.id);
+        lock(&self.link.finished).insert(self.id);
+        if self.link.is_open() {
+            let _ = self.link.send(self.id, CLOSE, &[]);
+        }
+    }
+}
+
+/// Link to a server and when to connect it again if it dropped.
+#[derive(Debug, Default)]
+struct Peer {
+    link: Option<Arc<Link>>,
+    /// Whether an exchange is connecting the link, without holding the lock of the peer.
+    connecting: bool,
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+struct PeerSlot {
+    peer: Mutex<Peer>,
+    /// Notified when connecting the link succeeds or fails.
+    connected: Condvar,
+}
+
+/// Links of this server to the others, connected when first needed.
+/// A link that drops is connected again on the next exchange, waiting longer after each failure.
+#[derive(Debug, Default)]
+pub struct Links {
+    peers: Mutex<HashMap<String, Arc<PeerSlot>>>,
+}
+
+impl Links {
+    /// Opens an exchange with the given server over its link, connecting the link if needed.
+    /// The exchanges opened while the link connects wait for it, at most `CONNECT_TIMEOUT`.
+    /// Fails right away while waiting to connect again to a server that could not be reached.
+    pub fn open(&self, addr: &str) -> Result<Exchange, String> {
+        let slot = lock(&self.peers)
+            .entry(addr.to_string())
+            .or_default()
+            .clone();
+        let mut peer = lock(&slot.peer);
+        while peer.connecting {
+            peer = slot.connected.wait(peer).unwrap_or_else(|e| e.into_inner());
+        }
+
+        match &peer.link {
+            Some(link) if link.is_open() => return Ok(link.open_exchange()),
+            Some(_) => {
+                debug!("Link to {} dropped, connecting again", addr);
+                peer.link = None;
+            }
+            None => {}
+        }
+        if let Some(retry_at) = peer.retry_at {
+            if Instant::now() < retry_at {
+                return Err(format!("Link to {} is down", addr));
+            }
+        }
+
+        peer.connecting = true;
+        drop(peer);
+        let connected = Link::connect(addr);
+        let mut peer = lock(&slot.peer);
+        peer.connecting = false;
+        slot.connected.notify_all();
+
+        match connected {
+            Ok(link) => {
+                peer.failures = 0;
+                peer.retry_at = None;
+                peer.link = Some(link.clone());
+                Ok(link.open_exchange())
+            }
+            Err(e) => {
+                let backoff = RECONNECT_BACKOFF
+                    .saturating_mul(1 << peer.failures.min(16))
+                    .min(RECONNECT_MAX_BACKOFF);
+                peer.failures += 1;
+                peer.retry_at = Some(Instant::now() + backoff);
+                debug!(
+                    "Could not connect link to {}: {}. Retrying in {:?}",
+                    addr, e, backoff
+                );
+                Err(format!("Could not connect to {}", addr))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    /// Serves links on a new port, echoing the message each exchange sends.
+    fn echo_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut kind = [0; 1];
+                stream.read_exact(&mut kind).unwrap();
+                thread::spawn(move || {
+                    Link::serve(stream, |mut exchange| {
+                        thread::spawn(move || {
+                            let mut len = [0; 1];
+                            exchange.read_exact(&mut len).unwrap();
+                            let mut received = vec![0; len[0] as usize];
+                            exchange.read_exact(&mut received).unwrap();
+                            exchange.write_all(&received).unwrap();
+                        });
+                    })
+                });
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_concurrent_exchanges_share_the_link() {
+        let addr = echo_server();
+        let links = Links::default();
+
+        let mut first = links.open(&addr).unwrap();
+        let mut second = links.open(&addr).unwrap();
+        assert!(Arc::ptr_eq(&first.link, &second.link));
+
+        second.write_all(b"\x06second").unwrap();
+        first.write_all(b"\x05first").unwrap();
+
+        // Each one reads its own answer until the other server ends the exchange
+        let mut answer = String::new();
+        second.read_to_string(&mut answer).unwrap();
+        assert_eq!(answer, "second");
+        let mut answer = String::new();
+        first.read_to_string(&mut answer).unwrap();
+        assert_eq!(answer, "first");
+    }
+
+    #[test]
+    fn test_finished_exchanges() {
+        let mut finished = Finished::default();
+        finished.insert(1);
+        assert!(finished.contains(1));
+        assert!(!finished.contains(0));
+
+        finished.insert(0);
+        finished.insert(2);
+        assert!((0..3).all(|id| finished.contains(id)));
+        assert!(!finished.contains(3));
+        assert!(finished.ids.is_empty());
+    }
+
+    #[test]
+    fn test_read_timeout() {
+        let addr = echo_server();
+        let links = Links::default();
+
+        let mut exchange = links.open(&addr).unwrap();
+        exchange
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        // The message is not complete, so there is no answer
+        exchange.write_all(b"\x05fir").unwrap();
+        let err = exchange.read(&mut [0; 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn test_exchanges_opened_while_connecting_share_the_link() {
+        let addr = echo_server();
+        let links = Arc::new(Links::default());
+
+        let opened: Vec<Exchange> = (0..8)
+            .map(|_| {
+                let links = links.clone();
+                let addr = addr.clone();
+                thread::spawn(move || links.open(&addr).unwrap())
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|opening| opening.join().unwrap())
+            .collect();
+        assert!(opened
+            .iter()
+            .all(|exchange| Arc::ptr_eq(&exchange.link, &opened[0].link)));
+    }
+
+    #[test]
+    fn test_reconnect_with_backoff() {
+        // A port nobody listens on
+        let addr = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap().to_string()
+        };
+        let links = Links::default();
+
+        assert!(links.open(&addr).is_err());
+        // Waits before trying to connect again
+        let listener = TcpListener::bind(&addr).unwrap();
+        assert_eq!(
+            links.open(&addr).unwrap_err(),
+            format!("Link to {} is down", addr)
+        );
+
+        thread::sleep(RECONNECT_BACKOFF);
+        let exchange = links.open(&addr);
+        assert!(exchange.is_ok());
+        drop(listener);
+    }
+}
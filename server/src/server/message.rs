@@ -1,20 +1,40 @@
 use std::{
-    collections::HashSet,
-    io::{BufRead, BufReader, BufWriter, Read, Write},
-    net::TcpStream,
+    collections::{HashMap, HashSet},
+    io::{Read, Write},
+    time::Duration,
 };
 
-use points::SERVER_MESSAGE;
 use serde::{Deserialize, Serialize};
-use tracing::{debug, error, trace};
+use tracing::{debug, trace};
 
-use super::point_storage::PointMap;
+use super::{
+    decision::Decision,
+    earned::Earned,
+    hlc::{Timestamp, CLOCK},
+    link::{Exchange, LINKS, MAX_PAYLOAD},
+    point_record::Points,
+    point_storage::PointMap,
+    raft_log::LogEntry,
+    server_error::ServerError,
+    transaction::Transaction,
+};
 
 pub const TIMEOUT: u64 = 1000;
 pub const CONNECT: u8 = 1;
 pub const SYNC: u8 = 2;
 pub const TRANSACTION: u8 = 3;
 pub const PING: u8 = 4;
+pub const BALANCE: u8 = 5;
+pub const DECISION: u8 = 6;
+pub const TX_STATUS: u8 = 7;
+pub const VOTE: u8 = 8;
+pub const APPEND_ENTRIES: u8 = 9;
+pub const PROPOSE: u8 = 10;
+
+/// Status of a response, followed by the timestamp of the clock of the responder and the length (`u64`) of its payload.
+/// The payload of an error response is a `ServerError`.
+const RESPONSE_OK: u8 = 0;
+const RESPONSE_ERROR: u8 = 1;
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ConnectRequest {
@@ -27,49 +47,176 @@ pub struct ConnectResponse {
     pub servers: HashSet<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct SyncRequest {}
+/// Changes of the points of a server that a requester already received.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncMark {
+    /// Run of the responder, versions of different runs can not be compared.
+    pub epoch: u64,
+    /// Version of the last change received.
+    pub version: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SyncRequest {
+    /// Mark of the previous sync with the same server. Without it, every record is returned.
+    #[serde(default)]
+    pub since: Option<SyncMark>,
+    /// Points earned offline by the requester, for the responder to merge before answering.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub earned: HashMap<u64, Earned>,
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct SyncResponse {
     pub points: PointMap,
+    /// Whether `points` are all the records or only the ones changed since the requested mark.
+    pub full: bool,
+    /// Mark to request the next changes with.
+    pub mark: SyncMark,
+    /// Amount of transactions the responder applied, including the ones of the states it adopted.
+    /// The responder that applied the most has the freshest state, whatever the clocks of the servers say.
+    pub committed: u64,
+}
+
+/// Decision of a transaction sent again by its coordinator, to a participant that may not know it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DecisionRequest {
+    pub transaction: Transaction,
+    pub commit: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DecisionResponse {}
+
+/// Question for the outcome of a transaction, from a participant that did not receive its decision.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TxStatusRequest {
+    pub transaction: Transaction,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxStatus {
+    Committed,
+    Aborted,
+    /// The server does not know the outcome of the transaction.
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TxStatusResponse {
+    pub status: TxStatus,
+}
+
+/// Request of a candidate for the vote of a server in its term.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VoteRequest {
+    pub term: u64,
+    pub candidate: String,
+    pub last_log_index: u64,
+    pub last_log_term: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct VoteResponse {
+    pub term: u64,
+    pub granted: bool,
+}
+
+/// Entries the leader replicates to a follower, empty for a heartbeat.
+/// They follow the entry at `prev_log_index`, which the follower must have with `prev_log_term`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AppendEntriesRequest {
+    pub term: u64,
+    pub leader: String,
+    pub prev_log_index: u64,
+    pub prev_log_term: u64,
+    pub entries: Vec<LogEntry>,
+    /// Index of the last entry the leader knows to be committed.
+    pub leader_commit: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AppendEntriesResponse {
+    pub term: u64,
+    pub success: bool,
+    /// Index of the last entry the follower has in common with the leader, when it succeeds.
+    pub match_index: u64,
+}
+
+/// Transaction a server forwards to the leader to append it to the replicated log.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProposeRequest {
+    pub transaction: Transaction,
 }
 
-/// Sends a message to the given address.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProposeResponse {
+    /// Whether the transaction was appended, it is not if the server is not the leader anymore.
+    pub accepted: bool,
+}
+
+/// Vote of a participant on a transaction it was asked to prepare.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PrepareResponse {
+    pub proceed: bool,
+    /// Why the participant did not approve the transaction.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<AbortReason>,
+}
+
+/// Why a participant did not approve a transaction.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AbortReason {
+    /// It is younger than the transaction holding the record, so it dies by wait-die.
+    WaitDie,
+    /// The record can not perform it, or it did not get its turn in time.
+    Refused,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BalanceRequest {
+    pub client_id: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BalanceResponse {
+    pub points: Points,
+}
+
+/// Sends a message to the given address, over a new exchange of the link to it.
 /// The message is serialized and sent as a byte array.
-/// The first byte is the message type.
+/// The first byte is the message type, followed by the timestamp of the clock of this server.
 /// The rest of the bytes are the serialized message.
 ///
 /// # Returns
 ///
-/// The stream to the given address.
+/// The exchange with the given address.
 pub fn write_message_to(
     msg_type: u8,
     msg: impl Serialize,
     addr: &String,
-) -> Result<TcpStream, String> {
-    let err = format!("Could not write message to {}", addr);
-    let stream = TcpStream::connect(addr).map_err(|_| err)?;
-    let mut writer = BufWriter::new(stream.try_clone().unwrap());
-
-    stream
-        .set_read_timeout(Some(std::time::Duration::from_millis(TIMEOUT)))
-        .map_err(|e| e.to_string())?;
-
+) -> Result<Exchange, String> {
     let msg = serde_json::to_string(&msg).map_err(|e| e.to_string())?;
     let msg = msg.as_bytes();
-    let msg_len: [u8; 8] = msg.len().to_be_bytes();
 
-    writer
-        .write_all(&[SERVER_MESSAGE])
-        .map_err(|e| e.to_string())?;
-    writer.write_all(&[msg_type]).map_err(|e| e.to_string())?;
+    let mut buf = Vec::with_capacity(1 + Timestamp::LEN + 8 + msg.len());
+    buf.push(msg_type);
+    buf.extend_from_slice(&CLOCK.now().to_be_bytes());
+    buf.extend_from_slice(&msg.len().to_be_bytes());
+    buf.extend_from_slice(msg);
 
-    writer.write_all(&msg_len).map_err(|e| e.to_string())?;
-    writer.write_all(msg).map_err(|e| e.to_string())?;
-    writer.flush().map_err(|e| e.to_string())?;
+    let err = format!("Could not write message to {}", addr);
+    let mut exchange = LINKS.open(addr)?;
+    if exchange.write_all(&buf).is_err() {
+        // The link dropped since it was last used, the message is sent over a new one
+        exchange = LINKS.open(addr)?;
+        exchange.write_all(&buf).map_err(|_| err)?;
+    }
+    exchange
+        .set_read_timeout(Some(Duration::from_millis(TIMEOUT)))
+        .map_err(|e| e.to_string())?;
 
-    Ok(stream)
+    Ok(exchange)
 }
 
 /// Sends a message to the given address and waits (blocks) for a response.
@@ -78,15 +225,61 @@ pub fn write_message_to(
 ///
 /// # Returns
 ///
-/// The response message as a string.
-pub fn send_message_to(msg_type: u8, msg: impl Serialize, addr: &String) -> Result<String, String> {
-    let stream = write_message_to(msg_type, msg, addr)?;
-    let mut reader = BufReader::new(stream.try_clone().unwrap());
+/// The payload of the response as a string, or the error the server responded.
+pub fn send_message_to(
+    msg_type: u8,
+    msg: impl Serialize,
+    addr: &String,
+) -> Result<String, ServerError> {
+    let mut exchange = write_message_to(msg_type, msg, addr).map_err(ServerError::Unreachable)?;
+
+    let response = receive_response(&mut exchange)?;
+    String::from_utf8(response).map_err(|_| ServerError::Malformed("Response is not UTF-8".into()))
+}
+
+/// Receives a response from the given stream, moving the clock of this server past the one of the responder.
+/// The first byte is the status, followed by the timestamp of the responder,
+/// the length (`u64`) of the payload and the payload.
+///
+/// # Returns
+///
+/// The payload of an ok response, or the error of an error response.
+pub fn receive_response(stream: &mut impl Read) -> Result<Vec<u8>, ServerError> {
+    let unreachable = |e: std::io::Error| match e.kind() {
+        std::io::ErrorKind::UnexpectedEof => {
+            ServerError::Unreachable("Closed without responding".to_string())
+        }
+        _ => ServerError::Unreachable(e.to_string()),
+    };
+    let mut header = [0; 1 + Timestamp::LEN + 8];
+    stream.read_exact(&mut header).map_err(unreachable)?;
+    let mut timestamp = [0; Timestamp::LEN];
+    timestamp.copy_from_slice(&header[1..1 + Timestamp::LEN]);
+    let mut len = [0; 8];
+    len.copy_from_slice(&header[1 + Timestamp::LEN..]);
+    let len = checked_len(u64::from_be_bytes(len))?;
 
-    let mut response = String::new();
-    reader.read_line(&mut response).map_err(|e| e.to_string())?;
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload).map_err(unreachable)?;
+    CLOCK.update(Timestamp::from_be_bytes(timestamp));
 
-    Ok(response)
+    match header[0] {
+        RESPONSE_OK => Ok(payload),
+        RESPONSE_ERROR => Err(serde_json::from_slice(&payload)
+            .unwrap_or_else(|_| ServerError::Malformed("Invalid error response".to_string()))),
+        status => Err(ServerError::Malformed(format!(
+            "Unknown response status {}",
+            status
+        ))),
+    }
+}
+
+/// Receives the timestamp of the clock of the sender from the given stream,
+/// and moves the clock of this server past it.
+pub fn receive_timestamp(stream: &mut impl Read) -> Result<Timestamp, String> {
+    let mut buf = [0; Timestamp::LEN];
+    stream.read_exact(&mut buf).map_err(|e| e.to_string())?;
+    Ok(CLOCK.update(Timestamp::from_be_bytes(buf)))
 }
 
 /// Receives a message from the given stream.
@@ -96,27 +289,59 @@ pub fn send_message_to(msg_type: u8, msg: impl Serialize, addr: &String) -> Resu
 /// # Returns
 ///
 /// The message type and the message.
-pub fn receive_from(stream: &mut TcpStream) -> Result<Vec<u8>, String> {
+pub fn receive_from(stream: &mut impl Read) -> Result<Vec<u8>, ServerError> {
     let mut len_buf = [0; 8];
-    stream.read_exact(&mut len_buf).map_err(|e| e.to_string())?;
-    let len = u64::from_be_bytes(len_buf);
+    stream
+        .read_exact(&mut len_buf)
+        .map_err(|e| ServerError::Malformed(e.to_string()))?;
+    let len = checked_len(u64::from_be_bytes(len_buf))?;
 
-    let mut buf = vec![0; len as usize];
-    stream.read_exact(&mut buf).map_err(|e| e.to_string())?;
+    let mut buf = vec![0; len];
+    stream
+        .read_exact(&mut buf)
+        .map_err(|e| ServerError::Malformed(e.to_string()))?;
 
     Ok(buf)
 }
 
-/// Responds to a message to the given stream.
-pub fn respond_to(stream: &mut TcpStream, response: String) -> Result<(), String> {
+/// Checks the length of a payload before it is read, a message never takes more than a frame of the link.
+fn checked_len(len: u64) -> Result<usize, ServerError> {
+    if len > MAX_PAYLOAD as u64 {
+        return Err(ServerError::Malformed(format!(
+            "Payload of {} bytes is too long",
+            len
+        )));
+    }
+    Ok(len as usize)
+}
+
+/// Responds to a message to the given stream, with an ok response.
+pub fn respond_to(stream: &mut impl Write, response: String) -> Result<(), ServerError> {
     if response != "null" {
         debug!("Responding {:?}", response);
     } else {
         trace!("Responding {:?}", response);
     }
+    write_response(stream, RESPONSE_OK, response.as_bytes())
+}
+
+/// Responds to a message to the given stream, with an error response.
+pub fn respond_error_to(stream: &mut impl Write, error: &ServerError) -> Result<(), ServerError> {
+    debug!("Responding error {:?}", error);
+    let payload = serde_json::to_vec(error).map_err(|e| ServerError::Failed(e.to_string()))?;
+    write_response(stream, RESPONSE_ERROR, &payload)
+}
+
+/// Writes the whole response at once, so it is a single frame of the link.
+fn write_response(stream: &mut impl Write, status: u8, payload: &[u8]) -> Result<(), ServerError> {
+    let mut buf = Vec::with_capacity(1 + Timestamp::LEN + 8 + payload.len());
+    buf.push(status);
+    buf.extend_from_slice(&CLOCK.now().to_be_bytes());
+    buf.extend_from_slice(&(payload.len() as u64).to_be_bytes());
+    buf.extend_from_slice(payload);
     stream
-        .write_all(response.as_bytes())
-        .map_err(|e| e.to_string())
+        .write_all(&buf)
+        .map_err(|e| ServerError::Unreachable(e.to_string()))
 }
 
 /// Connects to the given address and sends a connect message.
@@ -157,29 +382,180 @@ pub fn spread_connect_to(addr: &String, target_address: &String) -> Result<(), S
 }
 
 /// Sends a SYNC message to the given address.
+/// With a mark of a previous sync, the server may only return the records that changed since then.
+/// The given points earned offline are merged by the server before answering.
 ///
 /// # Returns
 ///
 /// The response message containing the points.
-pub fn sync_with(addr: &String) -> Result<PointMap, String> {
-    let msg = SyncRequest {};
+pub fn sync_with(
+    addr: &String,
+    since: Option<SyncMark>,
+    earned: HashMap<u64, Earned>,
+) -> Result<SyncResponse, String> {
+    let msg = SyncRequest { since, earned };
     debug!("Sending SYNC to {}", addr);
     let res = send_message_to(SYNC, msg, addr)?;
     let res: SyncResponse = serde_json::from_str(&res).map_err(|_| "Failed to parse response")?;
 
-    // Remove transactions from the point map
-    let mut points = res.points;
-    for (_, point) in points.iter_mut() {
-        let point = point.0.clone();
-        let point = point.lock();
-        if let Ok(mut point) = point {
-            point.transaction = None;
-        } else {
-            error!("Failed to lock point while parsing sync response");
-        }
+    debug!("Response: {:?}", res);
+
+    Ok(res)
+}
+
+/// Sends a BALANCE message to the given address.
+///
+/// # Returns
+///
+/// The points the server holds for the given client.
+pub fn query_balance_from(addr: &String, client_id: u64) -> Result<Points, String> {
+    let msg = BalanceRequest { client_id };
+    trace!("Sending BALANCE to {}", addr);
+    let res = send_message_to(BALANCE, msg, addr)?;
+    let res: BalanceResponse =
+        serde_json::from_str(&res).map_err(|_| "Failed to parse response")?;
+
+    Ok(res.points)
+}
+
+/// Sends a DECISION message to the given participant of a transaction.
+/// Succeeds once the participant handled the decision.
+pub fn send_decision_to(addr: &String, decision: &Decision) -> Result<(), String> {
+    let msg = DecisionRequest {
+        transaction: decision.transaction.clone(),
+        commit: decision.commit,
+    };
+    debug!("Sending DECISION to {}", addr);
+    let res = send_message_to(DECISION, msg, addr)?;
+    let _res: DecisionResponse =
+        serde_json::from_str(&res).map_err(|_| "Failed to parse response")?;
+
+    Ok(())
+}
+
+/// Sends a TX_STATUS message to the given address.
+///
+/// # Returns
+///
+/// The outcome of the transaction known by the server.
+pub fn query_tx_status_from(addr: &String, transaction: &Transaction) -> Result<TxStatus, String> {
+    let msg = TxStatusRequest {
+        transaction: transaction.clone(),
+    };
+    debug!("Sending TX_STATUS to {}", addr);
+    let res = send_message_to(TX_STATUS, msg, addr)?;
+    let res: TxStatusResponse =
+        serde_json::from_str(&res).map_err(|_| "Failed to parse response")?;
+
+    Ok(res.status)
+}
+
+/// Sends a VOTE message to the given address.
+pub fn request_vote_from(addr: &String, req: &VoteRequest) -> Result<VoteResponse, String> {
+    trace!("Sending VOTE to {}", addr);
+    let res = send_message_to(VOTE, req, addr)?;
+    serde_json::from_str(&res).map_err(|_| "Failed to parse response".to_string())
+}
+
+/// Sends an APPEND_ENTRIES message to the given address.
+pub fn append_entries_to(
+    addr: &String,
+    req: &AppendEntriesRequest,
+) -> Result<AppendEntriesResponse, String> {
+    trace!("Sending APPEND_ENTRIES to {}", addr);
+    let res = send_message_to(APPEND_ENTRIES, req, addr)?;
+    serde_json::from_str(&res).map_err(|_| "Failed to parse response".to_string())
+}
+
+/// Sends a PROPOSE message to the given leader.
+///
+/// # Returns
+///
+/// Whether the leader appended the transaction to the replicated log.
+pub fn propose_to(addr: &String, transaction: &Transaction) -> Result<bool, String> {
+    let msg = ProposeRequest {
+        transaction: transaction.clone(),
+    };
+    debug!("Sending PROPOSE to {}", addr);
+    let res = send_message_to(PROPOSE, msg, addr)?;
+    let res: ProposeResponse =
+        serde_json::from_str(&res).map_err(|_| "Failed to parse response")?;
+
+    Ok(res.accepted)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn test_responses_are_framed() {
+        let mut buf = vec![];
+        respond_to(&mut buf, "{\"a\":\n1}".to_string()).unwrap();
+        respond_to(&mut buf, String::new()).unwrap();
+        respond_error_to(&mut buf, &ServerError::Offline).unwrap();
+
+        let mut stream = Cursor::new(buf);
+        assert_eq!(receive_response(&mut stream).unwrap(), b"{\"a\":\n1}");
+        // An empty response is not an error
+        assert_eq!(receive_response(&mut stream).unwrap(), b"");
+        assert_eq!(receive_response(&mut stream), Err(ServerError::Offline));
     }
 
-    debug!("Response: {:?}", points);
+    #[test]
+    fn test_responses_move_the_clock() {
+        let ahead = Timestamp {
+            physical: CLOCK.now().physical + 1_000,
+            logical: 0,
+        };
+        let mut buf = vec![RESPONSE_OK];
+        buf.extend_from_slice(&ahead.to_be_bytes());
+        buf.extend_from_slice(&0u64.to_be_bytes());
+
+        receive_response(&mut Cursor::new(buf)).unwrap();
+        assert!(CLOCK.now() > ahead);
+    }
 
-    Ok(points)
+    #[test]
+    fn test_refuse_payloads_too_long() {
+        let mut response = vec![RESPONSE_OK];
+        response.extend_from_slice(&Timestamp::default().to_be_bytes());
+        response.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(matches!(
+            receive_response(&mut Cursor::new(response)),
+            Err(ServerError::Malformed(_))
+        ));
+
+        let message = (MAX_PAYLOAD as u64 + 1).to_be_bytes();
+        assert!(matches!(
+            receive_from(&mut Cursor::new(message)),
+            Err(ServerError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn test_response_cut_short() {
+        let mut buf = vec![];
+        respond_to(&mut buf, "{}".to_string()).unwrap();
+        buf.pop();
+
+        let res = receive_response(&mut Cursor::new(buf));
+        assert!(matches!(res, Err(ServerError::Unreachable(_))));
+        assert!(matches!(
+            receive_response(&mut Cursor::new(vec![])),
+            Err(ServerError::Unreachable(_))
+        ));
+    }
+
+    #[test]
+    fn test_unknown_response_status() {
+        let mut buf = vec![7];
+        buf.extend_from_slice(&Timestamp::default().to_be_bytes());
+        buf.extend_from_slice(&0u64.to_be_bytes());
+
+        let res = receive_response(&mut Cursor::new(buf));
+        assert!(matches!(res, Err(ServerError::Malformed(_))));
+    }
 }
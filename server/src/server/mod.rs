@@ -1,36 +1,71 @@
+mod config;
+mod connection_reservations;
+mod decision;
+mod decisions;
+mod earned;
+mod event_loop;
+mod hlc;
+mod in_doubt;
+mod link;
 mod message;
+mod outcomes;
+mod parked_reservations;
 mod pending_transactions;
 mod ping;
 mod point_record;
 mod point_storage;
+mod queue_stats;
+mod quorum;
+mod raft;
+mod raft_log;
+mod recent_orders;
+mod record_queue;
+mod reservation;
+mod server_error;
+mod snapshot;
 mod transaction;
+mod versions;
+mod wal;
 
+pub use config::{Config, Replication};
+use event_loop::{ClientSession, EventLoop};
+use link::Exchange;
 use point_storage::PointStorage;
-use points::{
-    ControlBytes, ControlMessage, Message, CLIENT_CONNECTION, CONTROL_MESSAGE, MESSAGE_BUFFER_SIZE,
-    SERVER_MESSAGE,
-};
+use points::{ControlBytes, ControlMessage, Frame, Message, Response, WireFormat};
+pub use quorum::Quorum;
+use server_error::ServerError;
 
 use std::thread::JoinHandle;
 use std::{
-    io::{Read, Write},
+    io::Read,
     net::{TcpListener, TcpStream},
-    sync::{Arc, Mutex},
+    sync::Arc,
     thread::{self},
     time::Duration,
 };
-use tracing::{debug, error, info, trace};
+use tracing::{debug, error, info, trace, warn};
 
 use crate::server::ping::{ping_to, PingRequest, PingResponse};
 use crate::server::{
-    message::{receive_from, respond_to, SyncRequest},
+    message::{
+        receive_from, receive_timestamp, respond_error_to, respond_to, AppendEntriesRequest,
+        BalanceRequest, BalanceResponse, DecisionRequest, DecisionResponse, ProposeRequest,
+        ProposeResponse, SyncRequest, TxStatusRequest, VoteRequest,
+    },
+    point_record::Points,
     transaction::TransactionAction,
 };
 use crate::threadpool::{Builder, ThreadPool};
 
 use self::{
-    message::{ConnectRequest, CONNECT, PING, SYNC, TRANSACTION},
+    message::{
+        ConnectRequest, APPEND_ENTRIES, BALANCE, CONNECT, DECISION, PING, PROPOSE, SYNC,
+        TRANSACTION, TX_STATUS, VOTE,
+    },
+    raft::{Raft, HEARTBEAT_INTERVAL},
+    snapshot::Snapshot,
     transaction::{Transaction, TxOk},
+    wal::Wal,
 };
 
 #[derive(Debug)]
@@ -41,13 +76,21 @@ use self::{
 pub struct Server {
     address: String,
     listener: TcpListener,
-    points: Arc<Mutex<PointStorage>>,
+    points: Arc<PointStorage>,
     thread_pool: ThreadPool,
+    link_pool: ThreadPool,
+    config: Config,
 }
 
 const PING_INTERVAL: u64 = 1000;
 
-const N_THREADS: usize = 10;
+const LEASE_INTERVAL: u64 = 1000;
+
+const DECISION_INTERVAL: u64 = 1000;
+
+const N_THREADS: usize = 14;
+
+const N_LINK_THREADS: usize = 14;
 
 const INTERVAL_LOGGER: u64 = 3000;
 
@@ -58,201 +101,382 @@ impl Server {
     ///
     /// * `address` - The address to listen on.
     /// * `core_server_addr` - The address of any known server.
-    pub fn new(address: String, core_server_addr: Option<String>) -> Server {
+    /// * `config` - The settings of the server.
+    ///
+    /// # Returns
+    ///
+    /// The server, or an error if the quorum asks for more servers than the cluster has.
+    pub fn new(
+        address: String,
+        core_server_addr: Option<String>,
+        config: Config,
+    ) -> Result<Server, String> {
         let listener = TcpListener::bind(address.clone()).unwrap();
 
-        Server {
+        let (wal, recovered) = match &config.data_dir {
+            Some(data_dir) => Wal::open(data_dir, &address).expect("Could not open the log"),
+            None => (Wal::disabled(), Snapshot::default()),
+        };
+
+        Ok(Server {
             address: address.clone(),
             listener,
-            points: PointStorage::new(address, core_server_addr),
+            points: PointStorage::new(address, core_server_addr, wal, recovered, &config)?,
             thread_pool: Builder::new().num_threads(N_THREADS).build(),
-        }
+            link_pool: Builder::new().num_threads(N_LINK_THREADS).build(),
+            config,
+        })
     }
 
-    /// Starts listening for incoming connections spawning a new thread to listen for each connection.
+    /// Starts listening for incoming connections, serving them from an event loop.
+    /// The messages of the coffee makers are handled on the thread pool,
+    /// the messages of other servers on the link pool, and the periodic jobs run on their own threads.
     pub fn listen(mut self) -> JoinHandle<()> {
-        let listener = self.listener.try_clone().unwrap();
-
         self.spawn_logger(INTERVAL_LOGGER);
         self.spawn_pending_handler();
         self.spawn_ping_handler();
+        self.spawn_lease_handler();
+        self.spawn_decision_handler();
+        if self.config.replication == Replication::Raft {
+            self.spawn_replication_handler();
+        }
+        if self.config.data_dir.is_some() {
+            self.spawn_snapshot_handler();
+        }
 
         thread::spawn(move || {
             debug!("Listening on {}", self.address);
-            for stream in listener.incoming() {
-                let new_connection = stream.unwrap();
-                self.handle_stream(new_connection);
+            let event_loop =
+                EventLoop::new(self.listener, self.points, self.thread_pool, self.link_pool)
+                    .expect("Could not start the event loop");
+            if let Err(e) = event_loop.run() {
+                error!("Event loop failed: {}", e);
             }
         })
     }
 
     pub fn spawn_logger(&mut self, interval: u64) {
         let points = self.points.clone();
-        self.thread_pool.execute(move || loop {
+        thread::spawn(move || loop {
             thread::sleep(Duration::from_millis(interval));
-            let points = points.lock().unwrap();
             debug!("Points: {:?}", points);
+            debug!("Record queues: {}", points.queue_stats);
         });
     }
 
-    /// Handles a stream by spawning a new thread to handle it.
-    /// The new connection could be a client (coffee machine) or another server.
-    fn handle_stream(&mut self, mut stream: TcpStream) {
-        let mut type_buf = [0; 1];
-
-        stream
-            .read_exact(&mut type_buf)
-            .unwrap_or_else(|_| error!("Could not read from stream"));
-
-        match type_buf[0] {
-            CLIENT_CONNECTION => self.spawn_client_connection_handler(stream),
-            SERVER_MESSAGE => self.spawn_server_message_handler(stream),
-            CONTROL_MESSAGE => self.handle_control_message(stream),
-            _ => error!("Unknown message type"),
+    /// Handles a frame received from a client connection, returning the frame to answer it with.
+    /// The reservations the connection did not settle are kept in its session,
+    /// so they can be freed when the connection drops.
+    fn handle_client_frame(
+        frame: Frame,
+        session: &mut ClientSession,
+        points: Arc<PointStorage>,
+    ) -> Vec<u8> {
+        let format = frame.format();
+        if format == WireFormat::Legacy {
+            trace!("Received legacy frame from {}", session.addr);
         }
-    }
 
-    /// Spawns a new thread to handle a client connection.
-    fn spawn_client_connection_handler(&mut self, stream: TcpStream) {
-        let points = self.points.clone();
-        self.thread_pool.execute(move || {
-            Self::connection_handler(stream, points);
-        });
+        let response = match Message::try_from(frame) {
+            Ok(Message::Identify(machine_id)) => {
+                debug!(
+                    "Connection with {} is from machine {}",
+                    session.addr, machine_id
+                );
+                let reservations = &mut session.reservations;
+                if let Some(previous) = reservations.machine_id.replace(machine_id) {
+                    points.parked.disconnect(previous, vec![]);
+                }
+                reservations.adopt(points.parked.connect(machine_id));
+                Response::Ok
+            }
+            Ok(msg) => {
+                let msg = match format {
+                    WireFormat::Legacy => session.reservations.settle_legacy(msg),
+                    _ => msg,
+                };
+                let response = Self::handle_client_message(msg.clone(), points);
+                session.reservations.track(&msg, response);
+                response
+            }
+            Err(e) => {
+                warn!("Malformed message from {}: {}", session.addr, e);
+                Response::Malformed
+            }
+        };
+        info!("Sending response: {:?}", response);
+        response.to_frame(format)
     }
 
-    /// Handles messages from a client connection while the connection is open.
-    fn connection_handler(mut stream: TcpStream, points: Arc<Mutex<PointStorage>>) {
-        let addr = stream.local_addr().unwrap().ip().to_string();
-        debug!("Connection established with {}", addr);
-
-        let mut message_buffer = [0; MESSAGE_BUFFER_SIZE];
-
-        while stream.read_exact(&mut message_buffer).is_ok() {
-            let msg = message_buffer.into();
-            Self::handle_client_message(msg, &mut stream, points.clone())
+    /// Frees the reservations a dropped client connection did not settle, or parks them
+    /// until the coffee maker connects again if it identified itself.
+    fn close_client_session(session: ClientSession, points: Arc<PointStorage>) {
+        debug!("Connection closed with {}", session.addr);
+        let reservations = session.reservations;
+        match reservations.machine_id {
+            Some(machine_id) => points
+                .parked
+                .disconnect(machine_id, reservations.into_held()),
+            None => PointStorage::release_held(points, reservations.into_held()),
         }
-
-        debug!("Connection closed with {}", addr);
     }
 
     /// Handles a message from a client connection.
     /// The message could mean the beginning of a new transaction.
-    /// The message is responded to with a message containing the points that were affected by the transaction.
+    /// Returns the `Response` for the client describing the outcome of the transaction.
     /// The points are also synchronized with other servers.
-    fn handle_client_message(
-        msg: Message,
-        stream: &mut TcpStream,
-        points: Arc<Mutex<PointStorage>>,
-    ) {
+    /// A retried message is not handled again, it gets the response of the original one.
+    fn handle_client_message(msg: Message, points: Arc<PointStorage>) -> Response {
         info!("Received {:?}", msg);
 
-        let result = match msg.handle_trivially() {
-            Ok(()) => {
-                debug!("Handled trivially {:?}", msg);
-                Ok(())
+        let recent_orders = points.recent_orders.clone();
+        let in_progress = match recent_orders.begin(&msg) {
+            Ok(in_progress) => in_progress,
+            Err(response) => {
+                info!("Not handling {:?} again", msg);
+                return response;
+            }
+        };
+
+        let result = match &msg {
+            Message::QueryBalance(client_id, read) => {
+                PointStorage::query_balance(points, *client_id, *read).map(Response::Balance)
             }
-            Err(_) => Self::handle_client_message_distributively(msg, points),
+            _ => match msg.handle_trivially() {
+                Ok(()) => {
+                    debug!("Handled trivially {:?}", msg);
+                    Ok(Response::Ok)
+                }
+                Err(_) => Self::handle_client_message_distributively(msg.clone(), points),
+            },
         };
 
-        let response = u8::from(result.is_ok());
-        if stream.write_all(&[response]).is_err() {
-            error!("Failed to send response");
+        let response = match result {
+            Ok(response) => response,
+            Err(response) => response,
         };
-        info!("Sent response: {:?} [{}]", result, response);
+        in_progress.finish(response);
+        response
     }
 
     /// Handles a message from a client connection that needs to be distributed to other servers.
     /// Verifies if the transaction could be completed and attempts to distribute it.
     fn handle_client_message_distributively(
         msg: Message,
-        points: Arc<Mutex<PointStorage>>,
-    ) -> Result<(), String> {
-        PointStorage::coordinate_msg(msg, points)?;
-        Ok(())
-    }
-
-    /// Spawns a new thread to handle a received message from another server.
-    fn spawn_server_message_handler(&mut self, stream: TcpStream) {
-        let points = self.points.clone();
-
-        {
-            let points = points.lock().expect("Could not lock points");
-            if !points.online {
-                return;
-            }
-        }
-        self.thread_pool.execute(move || {
-            Self::server_message_handler(stream, points);
-        });
+        points: Arc<PointStorage>,
+    ) -> Result<Response, Response> {
+        PointStorage::coordinate_msg(msg, points)
     }
 
     /// Handles the received message from another server.
     /// The message could be a request to synchronize points, a new transaction or a connection request.
-    fn server_message_handler(mut stream: TcpStream, storage: Arc<Mutex<PointStorage>>) {
+    /// Messages received while the server is offline are answered with an `Offline` error,
+    /// and a message that can not be handled is answered with the reason.
+    fn server_message_handler(mut stream: Exchange, storage: Arc<PointStorage>) {
+        if !storage.is_online() {
+            let _ = respond_error_to(&mut stream, &ServerError::Offline);
+            return;
+        }
         let mut buf = [0; 1];
 
-        stream.read_exact(&mut buf).unwrap();
+        if let Err(e) = stream.read_exact(&mut buf) {
+            error!("Failed to read server message type: {}", e);
+            return;
+        }
+        if let Err(e) = receive_timestamp(&mut stream) {
+            error!("Failed to read server message timestamp: {}", e);
+            let _ = respond_error_to(&mut stream, &ServerError::Malformed(e));
+            return;
+        }
 
         let res = match buf[0] {
-            CONNECT => Self::handle_server_connection(stream, storage),
-            SYNC => Self::handle_server_sync(stream, storage),
-            TRANSACTION => Self::handle_server_transaction(stream, storage),
-            PING => Self::handle_server_ping(stream, storage),
-            _ => Err("Unknown message type".to_string()),
+            CONNECT => Self::handle_server_connection(&mut stream, storage),
+            SYNC => Self::handle_server_sync(&mut stream, storage),
+            TRANSACTION => Self::handle_server_transaction(&mut stream, storage),
+            PING => Self::handle_server_ping(&mut stream, storage),
+            BALANCE => Self::handle_server_balance(&mut stream, storage),
+            DECISION => Self::handle_server_decision(&mut stream, storage),
+            TX_STATUS => Self::handle_server_tx_status(&mut stream, storage),
+            VOTE => Self::handle_server_vote(&mut stream, storage),
+            APPEND_ENTRIES => Self::handle_server_append_entries(&mut stream, storage),
+            PROPOSE => Self::handle_server_propose(&mut stream, storage),
+            msg_type => Err(ServerError::Malformed(format!(
+                "Unknown message type {}",
+                msg_type
+            ))),
         };
 
-        if res.is_err() {
-            error!("Failed to handle server message: {:?}", res);
+        if let Err(e) = res {
+            error!("Failed to handle server message: {}", e);
+            // A coordinator that already received the vote of a transaction is not reading anymore,
+            // so the error is dropped
+            let _ = respond_error_to(&mut stream, &e);
         }
     }
 
     /// Handles a connection request from another server.
     /// The connection request is responded to with a message containing the list of all available servers.
     fn handle_server_connection(
-        mut stream: TcpStream,
-        points: Arc<Mutex<PointStorage>>,
-    ) -> Result<(), String> {
-        let res = receive_from(&mut stream)?;
+        stream: &mut Exchange,
+        points: Arc<PointStorage>,
+    ) -> Result<(), ServerError> {
+        let res = receive_from(stream)?;
 
         let request: ConnectRequest =
-            serde_json::from_slice(&res).map_err(|_| "Failed to parse connect req")?;
-
-        let mut points = points.lock().unwrap();
+            serde_json::from_slice(&res).map_err(|e| ServerError::Malformed(e.to_string()))?;
 
         debug!("Connect {:?}", request.addr);
         let res = points.add_connection(request)?;
 
-        respond_to(&mut stream, res)
+        respond_to(stream, res)
     }
 
     /// Handles a synchronization request from another server.
     /// The synchronization request is responded to with a message containing the points for each client.
     fn handle_server_sync(
-        mut stream: TcpStream,
-        points: Arc<Mutex<PointStorage>>,
-    ) -> Result<(), String> {
-        let res = receive_from(&mut stream)?;
+        stream: &mut Exchange,
+        points: Arc<PointStorage>,
+    ) -> Result<(), ServerError> {
+        let res = receive_from(stream)?;
 
         let req: SyncRequest =
-            serde_json::from_slice(&res).map_err(|_| "Failed to parse connect req")?;
-
-        let points = points.lock().unwrap();
+            serde_json::from_slice(&res).map_err(|e| ServerError::Malformed(e.to_string()))?;
 
         debug!("Send Sync");
         let res = points.sync(req)?;
 
-        respond_to(&mut stream, res)
+        respond_to(stream, res)
+    }
+
+    /// Handles a balance request from another server.
+    /// The request is responded to with the points this server holds for the client.
+    fn handle_server_balance(
+        stream: &mut Exchange,
+        storage: Arc<PointStorage>,
+    ) -> Result<(), ServerError> {
+        let res = receive_from(stream)?;
+
+        let req: BalanceRequest =
+            serde_json::from_slice(&res).map_err(|e| ServerError::Malformed(e.to_string()))?;
+
+        storage.check_online().map_err(|_| ServerError::Offline)?;
+
+        let balance = PointStorage::read_balance(storage, req.client_id)?;
+        let res = BalanceResponse {
+            points: Points(balance.available, balance.locked),
+        };
+        let res = serde_json::to_string(&res).map_err(|e| e.to_string())?;
+
+        respond_to(stream, res)
+    }
+
+    /// Handles a decision that the coordinator of a transaction sent again.
+    /// The decision is applied if this server did not receive it when the transaction was coordinated.
+    fn handle_server_decision(
+        stream: &mut Exchange,
+        storage: Arc<PointStorage>,
+    ) -> Result<(), ServerError> {
+        let res = receive_from(stream)?;
+
+        let req: DecisionRequest =
+            serde_json::from_slice(&res).map_err(|e| ServerError::Malformed(e.to_string()))?;
+
+        // The decision is answered even if it can not be applied, sending it again would not help
+        if let Err(e) = PointStorage::apply_decision(storage, req) {
+            error!("{}", e);
+        }
+        let res = serde_json::to_string(&DecisionResponse {}).map_err(|e| e.to_string())?;
+
+        respond_to(stream, res)
+    }
+
+    /// Handles a question for the outcome of a transaction from a participant that has it in doubt.
+    fn handle_server_tx_status(
+        stream: &mut Exchange,
+        storage: Arc<PointStorage>,
+    ) -> Result<(), ServerError> {
+        let res = receive_from(stream)?;
+
+        let req: TxStatusRequest =
+            serde_json::from_slice(&res).map_err(|e| ServerError::Malformed(e.to_string()))?;
+
+        let res = storage.tx_status(req)?;
+
+        respond_to(stream, res)
+    }
+
+    /// Returns the replicated log of the storage, if it is in the Raft replication mode.
+    fn raft_of(storage: &Arc<PointStorage>) -> Result<Arc<Raft>, String> {
+        storage
+            .raft
+            .clone()
+            .ok_or_else(|| "Not in the Raft replication mode".to_string())
+    }
+
+    /// Handles the request of a candidate for the vote of this server.
+    fn handle_server_vote(
+        stream: &mut Exchange,
+        storage: Arc<PointStorage>,
+    ) -> Result<(), ServerError> {
+        let res = receive_from(stream)?;
+
+        let req: VoteRequest =
+            serde_json::from_slice(&res).map_err(|e| ServerError::Malformed(e.to_string()))?;
+
+        let res = Self::raft_of(&storage)?.vote(req);
+        let res = serde_json::to_string(&res).map_err(|e| e.to_string())?;
+
+        respond_to(stream, res)
+    }
+
+    /// Handles the entries the leader replicates to this server,
+    /// applying the ones the leader committed.
+    fn handle_server_append_entries(
+        stream: &mut Exchange,
+        storage: Arc<PointStorage>,
+    ) -> Result<(), ServerError> {
+        let res = receive_from(stream)?;
+
+        let req: AppendEntriesRequest =
+            serde_json::from_slice(&res).map_err(|e| ServerError::Malformed(e.to_string()))?;
+
+        let res = Self::raft_of(&storage)?.append_entries(req);
+        let res = serde_json::to_string(&res).map_err(|e| e.to_string())?;
+
+        respond_to(stream, res)?;
+        // The leader already has its answer
+        if let Err(e) = PointStorage::apply_replicated(storage) {
+            error!("Failed to apply replicated entries: {}", e);
+        }
+        Ok(())
+    }
+
+    /// Handles a transaction another server forwarded to this one, as the leader of the replicated log.
+    fn handle_server_propose(
+        stream: &mut Exchange,
+        storage: Arc<PointStorage>,
+    ) -> Result<(), ServerError> {
+        let res = receive_from(stream)?;
+
+        let req: ProposeRequest =
+            serde_json::from_slice(&res).map_err(|e| ServerError::Malformed(e.to_string()))?;
+
+        let accepted = Self::raft_of(&storage)?.propose(req.transaction).is_ok();
+        let res =
+            serde_json::to_string(&ProposeResponse { accepted }).map_err(|e| e.to_string())?;
+
+        respond_to(stream, res)
     }
 
     /// Handles a transaction from another server.
     fn handle_server_transaction(
-        mut stream: TcpStream,
-        points: Arc<Mutex<PointStorage>>,
-    ) -> Result<(), String> {
-        let res = receive_from(&mut stream)?;
+        stream: &mut Exchange,
+        points: Arc<PointStorage>,
+    ) -> Result<(), ServerError> {
+        let res = receive_from(stream)?;
 
         let tx: Transaction =
-            serde_json::from_slice(&res).map_err(|_| "Failed to parse transaction")?;
+            serde_json::from_slice(&res).map_err(|e| ServerError::Malformed(e.to_string()))?;
         // debug!("Received: {:?}", tx);
         let action = match tx.action {
             TransactionAction::Add => "ADD",
@@ -265,11 +489,11 @@ impl Server {
             tx.coordinator, tx.timestamp, tx.client_id, action, tx.points
         );
 
-        PointStorage::handle_transaction(points, tx, stream)
+        Ok(PointStorage::handle_transaction(points, tx, stream)?)
     }
 
     /// Handles a server control message
-    fn handle_control_message(&mut self, mut stream: TcpStream) {
+    fn control_message_handler(mut stream: TcpStream, points: Arc<PointStorage>) {
         let mut buf: ControlBytes = ControlMessage::Unknown.into();
         let r = stream.read_exact(&mut buf);
 
@@ -278,7 +502,6 @@ impl Server {
             return;
         }
 
-        let mut points = self.points.lock().expect("Failed to lock points");
         match buf.into() {
             ControlMessage::Disconnect => {
                 points.disconnect();
@@ -293,22 +516,30 @@ impl Server {
     /// Spawn a job to handle a pending transactions.
     fn spawn_pending_handler(&mut self) {
         let storage = self.points.clone();
-        self.thread_pool.execute(|| {
+        thread::spawn(|| {
             Self::pending_handler(storage);
         });
     }
 
     /// Handles pending transactions.
     /// Coordinates the pending transactions if the server is online.
-    fn pending_handler(storage: Arc<Mutex<PointStorage>>) {
-        let storage_lock = storage.lock().expect("Failed to lock storage");
-        let pending = storage_lock.pending.clone();
-        drop(storage_lock);
+    fn pending_handler(storage: Arc<PointStorage>) {
+        let pending = storage.pending.clone();
 
         loop {
             let storage = storage.clone();
             let transaction = pending.pop().unwrap();
-            let op = PointStorage::coordinate_tx(transaction, storage);
+            let op = PointStorage::coordinate_tx(transaction.clone(), storage);
+            // A transaction that did not get its turn to use the record is retried later
+            if matches!(op, Err(Response::Timeout)) {
+                if let Err(e) = pending.add(transaction.clone()) {
+                    error!("Could not queue transaction again: {}", e);
+                }
+            }
+            // A transaction that is still pending was queued again
+            if !matches!(op, Ok(TxOk::Pending) | Err(Response::Timeout)) {
+                pending.forget(&transaction);
+            }
             match op {
                 Ok(TxOk::Finalized) => {}
                 _ => {
@@ -322,45 +553,129 @@ impl Server {
     /// The ping request is responded to with an OK message and it is used to check if the other
     /// servers are online or if the current server is online.
     fn handle_server_ping(
-        mut stream: TcpStream,
-        storage: Arc<Mutex<PointStorage>>,
-    ) -> Result<(), String> {
-        let res = receive_from(&mut stream)?;
-        let points = storage.lock().expect("Failed to lock points");
-        let online = points.online;
-        if !online {
-            return Ok(());
+        stream: &mut Exchange,
+        storage: Arc<PointStorage>,
+    ) -> Result<(), ServerError> {
+        let res = receive_from(stream)?;
+        if !storage.is_online() {
+            return Err(ServerError::Offline);
         }
 
         let _req: PingRequest =
-            serde_json::from_slice(&res).map_err(|_| "Failed to parse ping request")?;
+            serde_json::from_slice(&res).map_err(|e| ServerError::Malformed(e.to_string()))?;
 
         let _res: PingResponse = PingResponse {};
 
         let serialized_res =
             serde_json::to_string(&_res).expect("Failed to serialize ping response");
 
-        respond_to(&mut stream, serialized_res)
+        respond_to(stream, serialized_res)
     }
 
     /// Spawns a job to handle pings to other servers.
     fn spawn_ping_handler(&mut self) {
         let storage = self.points.clone();
-        self.thread_pool.execute(move || {
+        thread::spawn(move || {
             Self::ping_handler(storage);
         });
     }
 
+    /// Spawns a job to free the locked points whose lease expired.
+    fn spawn_lease_handler(&mut self) {
+        let storage = self.points.clone();
+        let config = self.config.clone();
+        thread::spawn(move || {
+            Self::lease_handler(storage, config);
+        });
+    }
+
+    /// Periodically frees the reservations that were not freed or committed before their lease expired,
+    /// e.g. because the coffee maker that locked them crashed.
+    /// It also frees the reservations of dropped connections whose coffee maker did not connect again.
+    fn lease_handler(storage: Arc<PointStorage>, config: Config) {
+        let parked = storage.parked.clone();
+        loop {
+            thread::sleep(Duration::from_millis(LEASE_INTERVAL));
+            if let Err(e) = PointStorage::release_expired_leases(storage.clone(), config.lock_ttl) {
+                error!("Failed to release expired leases: {}", e);
+            }
+            let abandoned = parked.take_expired(config.reconnect_grace);
+            PointStorage::release_held(storage.clone(), abandoned);
+        }
+    }
+
+    /// Spawn a job to send the decisions that some participant may not know
+    /// and to ask for the ones this server does not know.
+    fn spawn_decision_handler(&mut self) {
+        let storage = self.points.clone();
+        thread::spawn(move || {
+            Self::decision_handler(storage);
+        });
+    }
+
+    /// Periodically sends the decisions of the coordinated transactions to the participants
+    /// that did not receive them, e.g. because this server crashed before sending them,
+    /// and asks the other servers for the outcome of the transactions in doubt.
+    fn decision_handler(storage: Arc<PointStorage>) {
+        loop {
+            thread::sleep(Duration::from_millis(DECISION_INTERVAL));
+            if let Err(e) = PointStorage::send_decisions(storage.clone()) {
+                error!("Failed to send decisions: {}", e);
+            }
+            if let Err(e) = PointStorage::resolve_in_doubt(storage.clone()) {
+                error!("Failed to resolve transactions in doubt: {}", e);
+            }
+        }
+    }
+
+    /// Spawn a job to run the replicated log, in the Raft replication mode.
+    fn spawn_replication_handler(&mut self) {
+        let storage = self.points.clone();
+        thread::spawn(move || {
+            Self::replication_handler(storage);
+        });
+    }
+
+    /// Periodically replicates the log to the other servers as their leader,
+    /// or starts an election if no leader was heard in a while.
+    fn replication_handler(storage: Arc<PointStorage>) {
+        loop {
+            thread::sleep(HEARTBEAT_INTERVAL);
+            if let Err(e) = PointStorage::replication_round(storage.clone()) {
+                error!("Failed to run the replicated log: {}", e);
+            }
+        }
+    }
+
+    /// Spawn a job to take snapshots of the points.
+    fn spawn_snapshot_handler(&mut self) {
+        let storage = self.points.clone();
+        let interval = self.config.snapshot_interval;
+        thread::spawn(move || {
+            Self::snapshot_handler(storage, interval);
+        });
+    }
+
+    /// Periodically writes a snapshot of the points and the pending transactions,
+    /// compacting the log so a restart does not replay the whole history.
+    fn snapshot_handler(storage: Arc<PointStorage>, interval: Duration) {
+        let wal = storage.wal.clone();
+        loop {
+            thread::sleep(interval);
+            if let Err(e) = wal.checkpoint(|| PointStorage::snapshot(&storage)) {
+                error!("Failed to take a snapshot: {}", e);
+            }
+        }
+    }
+
     /// Pings to other servers to check if they are online or if the current server is offline.
     /// If no server responded, this server will go into offline mode.
-    fn ping_handler(storage: Arc<Mutex<PointStorage>>) {
+    fn ping_handler(storage: Arc<PointStorage>) {
         loop {
             thread::sleep(Duration::from_millis(PING_INTERVAL));
-            let points = storage.lock().expect("Failed to lock points");
-            let other_servers = points.get_other_servers();
-            let online = points.online;
-            let pending = points.pending.clone();
-            drop(points);
+            let other_servers = storage.get_other_servers();
+            let online = storage.is_online();
+            let pending = storage.pending.clone();
             let mut ping_response = false;
             for server in other_servers {
                 if !online {
@@ -386,10 +701,13 @@ impl Server {
 #[cfg(test)]
 mod tests {
     use crate::server::message::{send_message_to, SyncRequest, SYNC};
-    use points::{parse_addr, ControlMessage, CONTROL_MESSAGE};
+    use points::{
+        parse_addr, Balance, BalanceRead, ControlMessage, Message, MessageBytes, Order,
+        OrderAction, Response, CLIENT_CONNECTION, CONTROL_MESSAGE,
+    };
     use serde_json::{json, Value};
     use serial_test::serial;
-    use std::io::Write;
+    use std::io::{Read, Write};
     use std::process::{Child, Command, Stdio};
     use std::thread;
     use std::time::Duration;
@@ -446,6 +764,27 @@ mod tests {
             .expect("Failed to start server")
     }
 
+    /// Kills the server and waits for it, so it is not left as a zombie process.
+    fn stop_server(mut server: Child) {
+        server.kill().expect("Failed to kill server");
+        server.wait().expect("Failed to wait for server");
+    }
+
+    fn create_server_with_flags(
+        address: &str,
+        known_server_address: Option<&str>,
+        flags: &[&str],
+    ) -> Child {
+        let mut args = vec!["run", "--bin", "server", address];
+        args.extend(known_server_address);
+        args.extend(flags);
+        Command::new("cargo")
+            .args(args)
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("Failed to start server")
+    }
+
     fn create_coffee_maker(
         address: &str,
         orders_path: &str,
@@ -493,6 +832,61 @@ mod tests {
         let _ = request_connect.unwrap().send();
     }
 
+    /// Id the tests use to identify as a coffee maker,
+    /// so the reservations survive the connection of each message.
+    const TEST_MACHINE_ID: u64 = 1;
+
+    fn connect_client(address: &str, machine_id: Option<u64>) -> std::net::TcpStream {
+        let mut stream = std::net::TcpStream::connect(parse_addr(address.to_string()))
+            .expect("Failed to connect to server");
+        stream.write_all(&[CLIENT_CONNECTION]).unwrap();
+        if let Some(machine_id) = machine_id {
+            let response = send_over(&mut stream, Message::Identify(machine_id));
+            assert_eq!(response, Response::Ok);
+        }
+        stream
+    }
+
+    fn send_over(stream: &mut std::net::TcpStream, msg: Message) -> Response {
+        stream.write_all(&msg.to_frame()).unwrap();
+        Response::read_from(stream).expect("Failed to read response")
+    }
+
+    fn send_client_message(address: &str, msg: Message) -> Response {
+        let mut stream = connect_client(address, Some(TEST_MACHINE_ID));
+        send_over(&mut stream, msg)
+    }
+
+    /// Sends a full SYNC to the given server, returning only its points.
+    fn sync_points(address: &str) -> String {
+        let sync = send_message_to(SYNC, SyncRequest::default(), &address.to_owned())
+            .expect("Failed to sync");
+        let sync: Value = serde_json::from_str(&sync).expect("Invalid sync");
+        json!({ "points": sync["points"] }).to_string()
+    }
+
+    /// Removes the reservations from the records of a sync response,
+    /// as their ids are random. Returns the remaining sync and the reserved points.
+    fn without_reservations(sync: String) -> (String, Vec<u64>) {
+        let mut sync: Value = serde_json::from_str(&sync).expect("Invalid sync");
+        let mut reserved = vec![];
+        if let Some(records) = sync["points"].as_object_mut() {
+            for record in records.values_mut() {
+                if let Some(Value::Object(reservations)) = record
+                    .as_object_mut()
+                    .and_then(|r| r.remove("reservations"))
+                {
+                    reserved.extend(reservations.values().filter_map(|r| r["points"].as_u64()));
+                }
+            }
+        }
+        (sync.to_string(), reserved)
+    }
+
+    fn query_balance(address: &str, client_id: u64, read: BalanceRead) -> Response {
+        send_client_message(address, Message::QueryBalance(client_id, read))
+    }
+
     #[test]
     #[serial]
     fn two_servers_should_sync_with_50_points_on_client_2() {
@@ -500,17 +894,16 @@ mod tests {
             "points": {
                 "2": {
                     "points": [50, 0],
-                    "transaction": null,
                 }
             }
         })
         .to_string();
 
-        let mut server_1 = create_server("9000", None);
+        let server_1 = create_server("9000", None);
         // El sleep es para dar tiempo a buildear al tirar un cargo run
         thread::sleep(Duration::from_millis(1000));
 
-        let mut server_2 = create_server("9001", Some("9000"));
+        let server_2 = create_server("9001", Some("9000"));
         // El sleep es para dar tiempo a buildear al tirar un cargo run
         thread::sleep(Duration::from_millis(1000));
 
@@ -518,14 +911,10 @@ mod tests {
         // Esperamos que la cafetera termine de procesar
         coffee_maker.wait().unwrap();
 
-        let synced_points_server_1 =
-            send_message_to(SYNC, SyncRequest {}, &"localhost:9000".to_owned())
-                .expect("Failed to sync");
-        let synced_points_server_2 =
-            send_message_to(SYNC, SyncRequest {}, &"localhost:9001".to_owned())
-                .expect("Failed to sync");
-        server_1.kill().expect("Failed to kill server 1");
-        server_2.kill().expect("Failed to kill server 2");
+        let synced_points_server_1 = sync_points("localhost:9000");
+        let synced_points_server_2 = sync_points("localhost:9001");
+        stop_server(server_1);
+        stop_server(server_2);
 
         assert_eq!(synced_points_server_1, expected_result);
         assert_eq!(synced_points_server_2, expected_result);
@@ -538,30 +927,28 @@ mod tests {
             "points": {
                 "2": {
                     "points": [50, 0],
-                    "transaction": null,
                 }
             }
         })
         .to_string();
-        let mut server_1 = create_server("9000", None);
+        let server_1 = create_server("9000", None);
         // El sleep es para dar tiempo a buildear al tirar un cargo run
         thread::sleep(Duration::from_millis(1000));
-        let mut server_2 = create_server("9001", Some("9000"));
+        let server_2 = create_server("9001", Some("9000"));
         // El sleep es para dar tiempo a buildear al tirar un cargo run
         thread::sleep(Duration::from_millis(1000));
         let mut coffee_maker = create_coffee_maker("9000", "assets/orders-3-test-2.csv", None);
         // Esperamos que la cafetera termine de procesar
         coffee_maker.wait().unwrap();
 
-        let mut new_server = create_server("9002", Some("9000"));
+        let new_server = create_server("9002", Some("9000"));
         thread::sleep(Duration::from_millis(1000));
 
         // Syncing with the new server on port 9002
-        let synced_points = send_message_to(SYNC, SyncRequest {}, &"localhost:9002".to_owned())
-            .expect("Failed to sync");
-        server_1.kill().expect("Failed to kill server 1");
-        server_2.kill().expect("Failed to kill server 2");
-        new_server.kill().expect("Failed to kill server 3");
+        let synced_points = sync_points("localhost:9002");
+        stop_server(server_1);
+        stop_server(server_2);
+        stop_server(new_server);
 
         assert_eq!(synced_points, expected_result);
     }
@@ -573,20 +960,20 @@ mod tests {
             "points": {
                 "2": {
                     "points": [50, 0],
-                    "transaction": null,
+                    "earned": { "localhost:9001": 50 },
                 }
             }
         })
         .to_string();
-        let mut server_1 = create_server("9000", None);
+        let server_1 = create_server("9000", None);
         // El sleep es para dar tiempo a buildear al tirar un cargo run
         thread::sleep(Duration::from_millis(1000));
 
-        let mut server_2 = create_server("9001", Some("9000"));
+        let server_2 = create_server("9001", Some("9000"));
         // El sleep es para dar tiempo a buildear al tirar un cargo run
         thread::sleep(Duration::from_millis(1000));
 
-        let mut server_3 = create_server("9002", Some("9000"));
+        let server_3 = create_server("9002", Some("9000"));
         // El sleep es para dar tiempo a buildear al tirar un cargo run
         thread::sleep(Duration::from_millis(1000));
 
@@ -608,18 +995,12 @@ mod tests {
         thread::sleep(Duration::from_millis(2000));
 
         // Synceamos con los 3 server
-        let synced_points_server_1 =
-            send_message_to(SYNC, SyncRequest {}, &"localhost:9000".to_owned())
-                .expect("Failed to sync");
-        let synced_points_server_2 =
-            send_message_to(SYNC, SyncRequest {}, &"localhost:9001".to_owned())
-                .expect("Failed to sync");
-        let synced_points_server_3 =
-            send_message_to(SYNC, SyncRequest {}, &"localhost:9002".to_owned())
-                .expect("Failed to sync");
-        server_3.kill().expect("Failed to kill server 3");
-        server_1.kill().expect("Failed to kill server 1");
-        server_2.kill().expect("Failed to kill server 2");
+        let synced_points_server_1 = sync_points("localhost:9000");
+        let synced_points_server_2 = sync_points("localhost:9001");
+        let synced_points_server_3 = sync_points("localhost:9002");
+        stop_server(server_3);
+        stop_server(server_1);
+        stop_server(server_2);
 
         assert_eq!(synced_points_server_1, expected_result);
         assert_eq!(synced_points_server_2, expected_result);
@@ -633,26 +1014,25 @@ mod tests {
             "points": {
                 "1": {
                     "points": [25, 0],
-                    "transaction": null,
                 },
                 "2": {
                     "points": [50, 0],
-                    "transaction": null,
+                    "earned": { "localhost:9001": 50 },
                 }
             }
         })["points"]
             .as_object()
             .unwrap()
             .clone();
-        let mut server_1 = create_server("9000", None);
+        let server_1 = create_server("9000", None);
         // El sleep es para dar tiempo a buildear al tirar un cargo run
         thread::sleep(Duration::from_millis(1000));
 
-        let mut server_2 = create_server("9001", Some("9000"));
+        let server_2 = create_server("9001", Some("9000"));
         // El sleep es para dar tiempo a buildear al tirar un cargo run
         thread::sleep(Duration::from_millis(1000));
 
-        let mut server_3 = create_server("9002", Some("9000"));
+        let server_3 = create_server("9002", Some("9000"));
 
         thread::sleep(Duration::from_millis(1000));
 
@@ -676,18 +1056,12 @@ mod tests {
         thread::sleep(Duration::from_millis(2000));
 
         // Synceamos con los 3 server
-        let synced_points_server_1 =
-            send_message_to(SYNC, SyncRequest {}, &"localhost:9000".to_owned())
-                .expect("Failed to sync");
-        let synced_points_server_2 =
-            send_message_to(SYNC, SyncRequest {}, &"localhost:9001".to_owned())
-                .expect("Failed to sync");
-        let synced_points_server_3 =
-            send_message_to(SYNC, SyncRequest {}, &"localhost:9002".to_owned())
-                .expect("Failed to sync");
-        server_3.kill().expect("Failed to kill server 3");
-        server_1.kill().expect("Failed to kill server 1");
-        server_2.kill().expect("Failed to kill server 2");
+        let synced_points_server_1 = sync_points("localhost:9000");
+        let synced_points_server_2 = sync_points("localhost:9001");
+        let synced_points_server_3 = sync_points("localhost:9002");
+        stop_server(server_3);
+        stop_server(server_1);
+        stop_server(server_2);
 
         // Sort the points to make the test deterministic
         let synced_points_server_1: Value = serde_json::from_str(&synced_points_server_1).unwrap();
@@ -710,6 +1084,57 @@ mod tests {
         assert_eq!(synced_points_server_3, expected_result);
     }
 
+    #[test]
+    #[serial]
+    fn servers_should_merge_the_points_earned_while_offline() {
+        let expected_result = json!({
+            "points": {
+                "2": {
+                    "points": [100, 0],
+                    "earned": { "localhost:9001": 50, "localhost:9002": 50 },
+                }
+            }
+        })
+        .to_string();
+        let server_1 = create_server("9000", None);
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let server_2 = create_server("9001", Some("9000"));
+        thread::sleep(Duration::from_millis(1000));
+
+        let server_3 = create_server("9002", Some("9000"));
+        thread::sleep(Duration::from_millis(1000));
+
+        // Desconectamos a los servers 9001 y 9002 a la vez
+        disconnect_server("9001");
+        disconnect_server("9002");
+        thread::sleep(Duration::from_millis(1000));
+
+        // Cada uno suma puntos al mismo cliente mientras esta desconectado
+        let mut coffee_maker = create_coffee_maker("9001", "assets/orders-3-test-2.csv", None);
+        coffee_maker.wait().unwrap();
+        let mut coffee_maker = create_coffee_maker("9002", "assets/orders-3-test-2.csv", None);
+        coffee_maker.wait().unwrap();
+
+        // Se reconectan de a uno, sincronizando con todos los servers
+        connect_server("9001");
+        thread::sleep(Duration::from_millis(2000));
+        connect_server("9002");
+        thread::sleep(Duration::from_millis(2000));
+
+        let synced_points_server_1 = sync_points("localhost:9000");
+        let synced_points_server_2 = sync_points("localhost:9001");
+        let synced_points_server_3 = sync_points("localhost:9002");
+        stop_server(server_3);
+        stop_server(server_1);
+        stop_server(server_2);
+
+        assert_eq!(synced_points_server_1, expected_result);
+        assert_eq!(synced_points_server_2, expected_result);
+        assert_eq!(synced_points_server_3, expected_result);
+    }
+
     #[test]
     #[serial]
     fn server_should_not_apply_use_points_order_when_offline() {
@@ -717,16 +1142,15 @@ mod tests {
         "points": {
             "1": {
                 "points": [25, 0],
-                "transaction": null,
             },
             }
         })
         .to_string();
-        let mut server_1 = create_server("9000", None);
+        let server_1 = create_server("9000", None);
         // El sleep es para dar tiempo a buildear al tirar un cargo run
         thread::sleep(Duration::from_millis(1000));
 
-        let mut server_2 = create_server("9001", Some("9000"));
+        let server_2 = create_server("9001", Some("9000"));
         // El sleep es para dar tiempo a buildear al tirar un cargo run
         thread::sleep(Duration::from_millis(1000));
 
@@ -747,15 +1171,11 @@ mod tests {
 
         thread::sleep(Duration::from_millis(1000));
 
-        let synced_points_server_1 =
-            send_message_to(SYNC, SyncRequest {}, &"localhost:9000".to_owned())
-                .expect("Failed to sync");
-        let synced_points_server_2 =
-            send_message_to(SYNC, SyncRequest {}, &"localhost:9001".to_owned())
-                .expect("Failed to sync");
+        let synced_points_server_1 = sync_points("localhost:9000");
+        let synced_points_server_2 = sync_points("localhost:9001");
 
-        server_1.kill().expect("Failed to kill server 1");
-        server_2.kill().expect("Failed to kill server 2");
+        stop_server(server_1);
+        stop_server(server_2);
 
         assert_eq!(synced_points_server_1, expected_result);
         assert_eq!(synced_points_server_2, expected_result);
@@ -768,20 +1188,19 @@ mod tests {
         "points": {
             "1": {
                 "points": [20, 0],
-                "transaction": null,
             },
             }
         })
         .to_string();
-        let mut server_1 = create_server("9000", None);
+        let server_1 = create_server("9000", None);
         // El sleep es para dar tiempo a buildear al tirar un cargo run
         thread::sleep(Duration::from_millis(1000));
 
-        let mut server_2 = create_server("9001", Some("9000"));
+        let server_2 = create_server("9001", Some("9000"));
         // El sleep es para dar tiempo a buildear al tirar un cargo run
         thread::sleep(Duration::from_millis(1000));
 
-        let mut server_3 = create_server("9002", Some("9000"));
+        let server_3 = create_server("9002", Some("9000"));
         // El sleep es para dar tiempo a buildear al tirar un cargo run
         thread::sleep(Duration::from_millis(1000));
 
@@ -794,19 +1213,13 @@ mod tests {
         let mut coffee_maker = create_coffee_maker("9001", "assets/orders-3-test-3.csv", None);
         coffee_maker.wait().unwrap();
 
-        let synced_points_server_1 =
-            send_message_to(SYNC, SyncRequest {}, &"localhost:9000".to_owned())
-                .expect("Failed to sync");
-        let synced_points_server_2 =
-            send_message_to(SYNC, SyncRequest {}, &"localhost:9001".to_owned())
-                .expect("Failed to sync");
-        let synced_points_server_3 =
-            send_message_to(SYNC, SyncRequest {}, &"localhost:9002".to_owned())
-                .expect("Failed to sync");
+        let synced_points_server_1 = sync_points("localhost:9000");
+        let synced_points_server_2 = sync_points("localhost:9001");
+        let synced_points_server_3 = sync_points("localhost:9002");
 
-        server_1.kill().expect("Failed to kill server 1");
-        server_2.kill().expect("Failed to kill server 2");
-        server_3.kill().expect("Failed to kill server 2");
+        stop_server(server_1);
+        stop_server(server_2);
+        stop_server(server_3);
 
         assert_eq!(synced_points_server_1, expected_result);
         assert_eq!(synced_points_server_2, expected_result);
@@ -820,7 +1233,6 @@ mod tests {
         "points": {
             "1": {
                 "points": [25, 0],
-                "transaction": null,
             },
             }
         })
@@ -829,16 +1241,15 @@ mod tests {
         "points": {
             "1": {
                 "points": [20, 5],
-                "transaction": null,
             },
             }
         })
         .to_string();
-        let mut server_1 = create_server("9000", None);
+        let server_1 = create_server("9000", None);
         // El sleep es para dar tiempo a buildear al tirar un cargo run
         thread::sleep(Duration::from_millis(1000));
 
-        let mut server_2 = create_server("9001", Some("9000"));
+        let server_2 = create_server("9001", Some("9000"));
         // El sleep es para dar tiempo a buildear al tirar un cargo run
         thread::sleep(Duration::from_millis(1000));
 
@@ -852,28 +1263,27 @@ mod tests {
         let mut coffee_maker = create_coffee_maker("9001", "assets/orders-3-test-3.csv", Some(0));
         thread::sleep(Duration::from_millis(1000));
 
-        let sync_reserved_points_server_1 =
-            send_message_to(SYNC, SyncRequest {}, &"localhost:9000".to_owned())
-                .expect("Failed to sync");
+        let sync_reserved_points_server_1 = sync_points("localhost:9000");
 
-        let sync_reserved_points_server_2 =
-            send_message_to(SYNC, SyncRequest {}, &"localhost:9001".to_owned())
-                .expect("Failed to sync");
+        let sync_reserved_points_server_2 = sync_points("localhost:9001");
 
         coffee_maker.wait().unwrap();
 
-        let synced_points_server_1 =
-            send_message_to(SYNC, SyncRequest {}, &"localhost:9000".to_owned())
-                .expect("Failed to sync");
-        let synced_points_server_2 =
-            send_message_to(SYNC, SyncRequest {}, &"localhost:9001".to_owned())
-                .expect("Failed to sync");
+        let synced_points_server_1 = sync_points("localhost:9000");
+        let synced_points_server_2 = sync_points("localhost:9001");
+
+        stop_server(server_1);
+        stop_server(server_2);
 
-        server_1.kill().expect("Failed to kill server 1");
-        server_2.kill().expect("Failed to kill server 2");
+        let (sync_reserved_points_server_1, reserved_server_1) =
+            without_reservations(sync_reserved_points_server_1);
+        let (sync_reserved_points_server_2, reserved_server_2) =
+            without_reservations(sync_reserved_points_server_2);
 
         assert_eq!(sync_reserved_points_server_1, expected_reserved_result);
         assert_eq!(sync_reserved_points_server_2, expected_reserved_result);
+        assert_eq!(reserved_server_1, vec![5]);
+        assert_eq!(reserved_server_2, vec![5]);
         assert_eq!(synced_points_server_1, expected_final_result);
         assert_eq!(synced_points_server_2, expected_final_result);
     }
@@ -885,7 +1295,6 @@ mod tests {
         "points": {
             "1": {
                 "points": [20, 5],
-                "transaction": null,
             },
             }
         })
@@ -895,17 +1304,16 @@ mod tests {
         "points": {
             "1": {
                 "points": [20, 0],
-                "transaction": null,
             },
             }
         })
         .to_string();
 
-        let mut server_1 = create_server("9000", None);
+        let server_1 = create_server("9000", None);
         // El sleep es para dar tiempo a buildear al tirar un cargo run
         thread::sleep(Duration::from_millis(1000));
 
-        let mut server_2 = create_server("9001", Some("9000"));
+        let server_2 = create_server("9001", Some("9000"));
         // El sleep es para dar tiempo a buildear al tirar un cargo run
         thread::sleep(Duration::from_millis(1000));
 
@@ -921,28 +1329,27 @@ mod tests {
         // reserve los puntos y pero no espere la confirmacion de la cafetera
         thread::sleep(Duration::from_millis(1000));
 
-        let sync_reserved_points_server_1 =
-            send_message_to(SYNC, SyncRequest {}, &"localhost:9000".to_owned())
-                .expect("Failed to sync");
+        let sync_reserved_points_server_1 = sync_points("localhost:9000");
 
-        let sync_reserved_points_server_2 =
-            send_message_to(SYNC, SyncRequest {}, &"localhost:9001".to_owned())
-                .expect("Failed to sync");
+        let sync_reserved_points_server_2 = sync_points("localhost:9001");
 
         coffee_maker.wait().unwrap();
 
-        let sync_final_points_server_1 =
-            send_message_to(SYNC, SyncRequest {}, &"localhost:9000".to_owned())
-                .expect("Failed to sync");
-        let sync_final_points_server_2 =
-            send_message_to(SYNC, SyncRequest {}, &"localhost:9001".to_owned())
-                .expect("Failed to sync");
+        let sync_final_points_server_1 = sync_points("localhost:9000");
+        let sync_final_points_server_2 = sync_points("localhost:9001");
 
-        server_1.kill().expect("Failed to kill server 1");
-        server_2.kill().expect("Failed to kill server 2");
+        stop_server(server_1);
+        stop_server(server_2);
+
+        let (sync_reserved_points_server_1, reserved_server_1) =
+            without_reservations(sync_reserved_points_server_1);
+        let (sync_reserved_points_server_2, reserved_server_2) =
+            without_reservations(sync_reserved_points_server_2);
 
         assert_eq!(sync_reserved_points_server_1, expected_reserved_points);
         assert_eq!(sync_reserved_points_server_2, expected_reserved_points);
+        assert_eq!(reserved_server_1, vec![5]);
+        assert_eq!(reserved_server_2, vec![5]);
         assert_eq!(sync_final_points_server_1, expected_final_points);
         assert_eq!(sync_final_points_server_2, expected_final_points);
     }
@@ -954,7 +1361,6 @@ mod tests {
         "points": {
             "1": {
                 "points": [20, 5],
-                "transaction": null,
             },
             }
         })
@@ -964,24 +1370,23 @@ mod tests {
         "points": {
             "1": {
                 "points": [20, 0],
-                "transaction": null,
             },
             }
         })
         .to_string();
 
-        let mut server_1 = create_server("9000", None);
+        let server_1 = create_server("9000", None);
         // El sleep es para dar tiempo a buildear al tirar un cargo run
         thread::sleep(Duration::from_millis(1000));
 
-        let mut server_2 = create_server("9001", Some("9000"));
+        let server_2 = create_server("9001", Some("9000"));
         // El sleep es para dar tiempo a buildear al tirar un cargo run
         thread::sleep(Duration::from_millis(1000));
 
         // El sleep es para dar tiempo a buildear al tirar un cargo run
         thread::sleep(Duration::from_millis(1000));
 
-        let mut server_3 = create_server("9002", Some("9000"));
+        let server_3 = create_server("9002", Some("9000"));
         // El sleep es para dar tiempo a buildear al tirar un cargo run
         thread::sleep(Duration::from_millis(1000));
 
@@ -1002,40 +1407,544 @@ mod tests {
 
         coffee_maker.wait().unwrap();
 
-        let sync_reserved_points_server_1 =
-            send_message_to(SYNC, SyncRequest {}, &"localhost:9000".to_owned())
-                .expect("Failed to sync");
+        let sync_reserved_points_server_1 = sync_points("localhost:9000");
+
+        let sync_reserved_points_server_3 = sync_points("localhost:9002");
 
-        let sync_reserved_points_server_3 =
-            send_message_to(SYNC, SyncRequest {}, &"localhost:9002".to_owned())
-                .expect("Failed to sync");
+        let (sync_reserved_points_server_1, reserved_server_1) =
+            without_reservations(sync_reserved_points_server_1);
+        let (sync_reserved_points_server_3, reserved_server_3) =
+            without_reservations(sync_reserved_points_server_3);
 
         // El server 9001 se desconectó, entonces los demás no pueden seguir con la transaccion
         // ya que nunca les llegó la confimacion de la cafetera del 9001
         assert_eq!(sync_reserved_points_server_1, expected_reserved_points);
         assert_eq!(sync_reserved_points_server_3, expected_reserved_points);
+        assert_eq!(reserved_server_1, vec![5]);
+        assert_eq!(reserved_server_3, vec![5]);
 
         // Conectamos el server 9001
         connect_server("9001");
 
         thread::sleep(Duration::from_millis(1000));
 
-        let sync_final_points_server_1 =
-            send_message_to(SYNC, SyncRequest {}, &"localhost:9000".to_owned())
-                .expect("Failed to sync");
-        let sync_final_points_server_2 =
-            send_message_to(SYNC, SyncRequest {}, &"localhost:9001".to_owned())
-                .expect("Failed to sync");
-        let sync_final_points_server_3 =
-            send_message_to(SYNC, SyncRequest {}, &"localhost:9002".to_owned())
-                .expect("Failed to sync");
+        let sync_final_points_server_1 = sync_points("localhost:9000");
+        let sync_final_points_server_2 = sync_points("localhost:9001");
+        let sync_final_points_server_3 = sync_points("localhost:9002");
 
-        server_1.kill().expect("Failed to kill server 1");
-        server_2.kill().expect("Failed to kill server 2");
-        server_3.kill().expect("Failed to kill server 3");
+        stop_server(server_1);
+        stop_server(server_2);
+        stop_server(server_3);
 
         assert_eq!(sync_final_points_server_1, expected_final_points);
         assert_eq!(sync_final_points_server_2, expected_final_points);
         assert_eq!(sync_final_points_server_3, expected_final_points);
     }
+
+    #[test]
+    #[serial]
+    fn server_should_answer_balance_queries_locally_and_through_a_quorum() {
+        let server_1 = create_server("9000", None);
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let server_2 = create_server("9001", Some("9000"));
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let mut coffee_maker = create_coffee_maker("9000", "assets/orders-3-test.csv", None);
+        // Esperamos que la cafetera termine de procesar
+        coffee_maker.wait().unwrap();
+
+        let local_balance = query_balance("9001", 1, BalanceRead::Local);
+        let quorum_balance = query_balance("9001", 1, BalanceRead::Quorum);
+        let unknown_client_balance = query_balance("9000", 2, BalanceRead::Quorum);
+
+        // Sin el server 9001 no hay mayoria para una lectura por quorum
+        disconnect_server("9001");
+        thread::sleep(Duration::from_millis(1000));
+        let offline_balance = query_balance("9000", 1, BalanceRead::Quorum);
+
+        stop_server(server_1);
+        stop_server(server_2);
+
+        assert_eq!(local_balance, Response::Balance(Balance::new(25, 0)));
+        assert_eq!(quorum_balance, Response::Balance(Balance::new(25, 0)));
+        assert_eq!(
+            unknown_client_balance,
+            Response::Balance(Balance::new(0, 0))
+        );
+        assert_eq!(offline_balance, Response::Offline);
+    }
+
+    #[test]
+    #[serial]
+    fn server_should_reply_malformed_and_keep_serving_after_a_bad_frame() {
+        let server_1 = create_server("9000", None);
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let mut stream = std::net::TcpStream::connect("localhost:9000").unwrap();
+        stream.write_all(&[CLIENT_CONNECTION]).unwrap();
+
+        // Trama legacy con una accion invalida: se responde error y se sigue leyendo
+        stream.write_all(&[1, 0, 1, 9, 0, 0, 5]).unwrap();
+        let mut legacy_response = [0; 1];
+        stream.read_exact(&mut legacy_response).unwrap();
+
+        stream
+            .write_all(&Message::QueryBalance(1, BalanceRead::Local).to_frame())
+            .unwrap();
+        let balance = Response::read_from(&mut stream).unwrap();
+
+        // Version desconocida: se responde error y se cierra la conexion
+        stream.write_all(&[0x7f]).unwrap();
+        let unknown_version = Response::read_from(&mut stream).unwrap();
+        let mut closed = [0; 1];
+        let read_after_close = stream.read(&mut closed).unwrap();
+
+        stop_server(server_1);
+
+        assert_eq!(legacy_response, [0]);
+        assert_eq!(balance, Response::Balance(Balance::new(0, 0)));
+        assert_eq!(unknown_version, Response::Malformed);
+        assert_eq!(read_after_close, 0);
+    }
+
+    #[test]
+    #[serial]
+    fn server_should_apply_a_retried_order_only_once() {
+        let server_1 = create_server("9000", None);
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let order = Order::new(1, OrderAction::FillPoints(50)).with_id(1234);
+        let first = send_client_message("9000", Message::CommitOrder(order.clone()));
+        // La cafetera reintenta el mismo pedido por otra conexion
+        let retry = send_client_message("9000", Message::CommitOrder(order.clone()));
+        let balance = query_balance("9000", 1, BalanceRead::Local);
+
+        // Un pedido distinto con los mismos datos si se aplica
+        let other = Order::new(1, OrderAction::FillPoints(50)).with_id(4321);
+        send_client_message("9000", Message::CommitOrder(other));
+        let final_balance = query_balance("9000", 1, BalanceRead::Local);
+
+        stop_server(server_1);
+
+        assert_eq!(first, Response::Ok);
+        assert_eq!(retry, Response::Ok);
+        assert_eq!(balance, Response::Balance(Balance::new(50, 0)));
+        assert_eq!(final_balance, Response::Balance(Balance::new(100, 0)));
+    }
+
+    #[test]
+    #[serial]
+    fn server_should_only_settle_known_reservations() {
+        let server_1 = create_server("9000", None);
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let server_2 = create_server("9001", Some("9000"));
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let fill = Order::new(1, OrderAction::FillPoints(50)).with_id(1);
+        send_client_message("9000", Message::CommitOrder(fill));
+
+        let order = Order::new(1, OrderAction::UsePoints(20)).with_id(2);
+        let reservation = match send_client_message("9000", Message::LockOrder(order.clone())) {
+            Response::Reserved(reservation) => reservation,
+            response => panic!("Unexpected response {:?}", response),
+        };
+
+        // Un commit sin reserva o con una reserva desconocida se rechaza
+        let without = send_client_message("9000", Message::CommitOrder(order.clone()));
+        let unknown = send_client_message(
+            "9000",
+            Message::CommitOrder(order.clone().with_reservation(reservation + 1)),
+        );
+        // La reserva se puede usar desde otro servidor, pero una sola vez.
+        // El sleep es para dar tiempo a que el server 9001 aplique el lock
+        thread::sleep(Duration::from_millis(200));
+        let commit = send_client_message(
+            "9001",
+            Message::CommitOrder(order.clone().with_reservation(reservation)),
+        );
+        let replayed = send_client_message(
+            "9000",
+            Message::CommitOrder(order.with_id(3).with_reservation(reservation)),
+        );
+        let balance = query_balance("9000", 1, BalanceRead::Quorum);
+
+        stop_server(server_1);
+        stop_server(server_2);
+
+        assert_eq!(without, Response::UnknownReservation);
+        assert_eq!(unknown, Response::UnknownReservation);
+        assert_eq!(commit, Response::Ok);
+        assert_eq!(replayed, Response::UnknownReservation);
+        assert_eq!(balance, Response::Balance(Balance::new(30, 0)));
+    }
+
+    #[test]
+    #[serial]
+    fn server_should_settle_the_reservations_of_legacy_frames() {
+        let server_1 = create_server("9000", None);
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let fill = Order::new(1, OrderAction::FillPoints(50)).with_id(1);
+        send_client_message("9000", Message::CommitOrder(fill));
+
+        // Una cafetera legacy no recibe ni envia reservas, solo un byte de respuesta
+        let mut stream = connect_client("9000", None);
+        let mut send_legacy = |msg: Message| {
+            let frame: MessageBytes = msg.try_into().unwrap();
+            stream.write_all(&frame).unwrap();
+            let mut response = [0; 1];
+            stream.read_exact(&mut response).unwrap();
+            response[0]
+        };
+        let use_points = Order::new(1, OrderAction::UsePoints(20));
+        let lock = send_legacy(Message::LockOrder(use_points.clone()));
+        let commit = send_legacy(Message::CommitOrder(use_points.clone()));
+        let relock = send_legacy(Message::LockOrder(use_points.clone()));
+        let free = send_legacy(Message::FreeOrder(use_points.clone()));
+        // No queda ninguna reserva que liberar
+        let free_again = send_legacy(Message::FreeOrder(use_points));
+        let balance = query_balance("9000", 1, BalanceRead::Local);
+
+        stop_server(server_1);
+
+        assert_eq!([lock, commit, relock, free], [1, 1, 1, 1]);
+        assert_eq!(free_again, 0);
+        assert_eq!(balance, Response::Balance(Balance::new(30, 0)));
+    }
+
+    #[test]
+    #[serial]
+    fn server_should_free_locked_points_when_the_lease_expires() {
+        let server_1 = create_server_with_flags("9000", None, &["--lock-ttl", "1"]);
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let server_2 = create_server_with_flags("9001", Some("9000"), &["--lock-ttl", "1"]);
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let fill = Order::new(1, OrderAction::FillPoints(50)).with_id(1);
+        send_client_message("9000", Message::CommitOrder(fill));
+
+        // La cafetera reserva puntos y nunca los libera ni los consume
+        let order = Order::new(1, OrderAction::UsePoints(20)).with_id(2);
+        let reservation = match send_client_message("9000", Message::LockOrder(order.clone())) {
+            Response::Reserved(reservation) => reservation,
+            response => panic!("Unexpected response {:?}", response),
+        };
+        let locked_balance = query_balance("9000", 1, BalanceRead::Quorum);
+
+        // Esperamos que venza el lease y el server libere los puntos
+        thread::sleep(Duration::from_millis(3000));
+        let balance_server_1 = query_balance("9000", 1, BalanceRead::Local);
+        let balance_server_2 = query_balance("9001", 1, BalanceRead::Local);
+        let late_commit = send_client_message(
+            "9000",
+            Message::CommitOrder(order.with_reservation(reservation)),
+        );
+
+        stop_server(server_1);
+        stop_server(server_2);
+
+        assert_eq!(locked_balance, Response::Balance(Balance::new(30, 20)));
+        assert_eq!(balance_server_1, Response::Balance(Balance::new(50, 0)));
+        assert_eq!(balance_server_2, Response::Balance(Balance::new(50, 0)));
+        assert_eq!(late_commit, Response::UnknownReservation);
+    }
+
+    #[test]
+    #[serial]
+    fn server_should_free_locked_points_when_a_connection_drops() {
+        let server_1 = create_server_with_flags("9000", None, &["--reconnect-grace", "2"]);
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let fill = Order::new(1, OrderAction::FillPoints(50)).with_id(1);
+        send_client_message("9000", Message::CommitOrder(fill));
+        let order = Order::new(1, OrderAction::UsePoints(10));
+
+        // Una conexion sin identificar libera sus reservas apenas se cierra
+        let mut anonymous = connect_client("9000", None);
+        send_over(&mut anonymous, Message::LockOrder(order.clone().with_id(2)));
+        drop(anonymous);
+        thread::sleep(Duration::from_millis(200));
+        let balance_after_anonymous = query_balance("9000", 1, BalanceRead::Local);
+
+        // La cafetera 7 se reconecta a tiempo y consume su reserva
+        let mut machine_7 = connect_client("9000", Some(7));
+        let reservation =
+            match send_over(&mut machine_7, Message::LockOrder(order.clone().with_id(3))) {
+                Response::Reserved(reservation) => reservation,
+                response => panic!("Unexpected response {:?}", response),
+            };
+        drop(machine_7);
+        let mut machine_7 = connect_client("9000", Some(7));
+
+        // La cafetera 8 nunca se reconecta
+        let mut machine_8 = connect_client("9000", Some(8));
+        send_over(&mut machine_8, Message::LockOrder(order.clone().with_id(4)));
+        drop(machine_8);
+
+        thread::sleep(Duration::from_millis(4000));
+        let commit = send_over(
+            &mut machine_7,
+            Message::CommitOrder(order.with_id(3).with_reservation(reservation)),
+        );
+        let final_balance = query_balance("9000", 1, BalanceRead::Local);
+
+        stop_server(server_1);
+
+        assert_eq!(
+            balance_after_anonymous,
+            Response::Balance(Balance::new(50, 0))
+        );
+        assert_eq!(commit, Response::Ok);
+        assert_eq!(final_balance, Response::Balance(Balance::new(40, 0)));
+    }
+
+    #[test]
+    #[serial]
+    fn server_should_serialize_concurrent_orders_on_the_same_account() {
+        let server_1 = create_server("9000", None);
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let server_2 = create_server("9001", Some("9000"));
+        thread::sleep(Duration::from_millis(1000));
+
+        let fill = Order::new(1, OrderAction::FillPoints(100)).with_id(1);
+        let fill_response = send_client_message("9000", Message::CommitOrder(fill));
+
+        // Varios integrantes de la familia usan la misma tarjeta a la vez
+        let locks: Vec<_> = (2..6)
+            .map(|id| {
+                thread::spawn(move || {
+                    let lock = Order::new(1, OrderAction::UsePoints(20)).with_id(id);
+                    send_client_message("9000", Message::LockOrder(lock))
+                })
+            })
+            .collect();
+        let lock_responses: Vec<Response> =
+            locks.into_iter().map(|lock| lock.join().unwrap()).collect();
+        let balance = query_balance("9001", 1, BalanceRead::Local);
+
+        stop_server(server_1);
+        stop_server(server_2);
+
+        assert_eq!(fill_response, Response::Ok);
+        for response in lock_responses {
+            assert!(matches!(response, Response::Reserved(_)));
+        }
+        assert_eq!(balance, Response::Balance(Balance::new(20, 80)));
+    }
+
+    #[test]
+    #[serial]
+    fn servers_should_require_the_configured_quorum_to_lock_points() {
+        let server_1 = create_server_with_flags("9000", None, &["--quorum", "all"]);
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let server_2 = create_server("9001", Some("9000"));
+        thread::sleep(Duration::from_millis(1000));
+
+        let server_3 = create_server("9002", Some("9000"));
+        thread::sleep(Duration::from_millis(1000));
+
+        let fill = Order::new(1, OrderAction::FillPoints(50)).with_id(1);
+        let fill_response = send_client_message("9000", Message::CommitOrder(fill));
+
+        // Desconectamos al server 9002, 9000 necesita que todos aprueben
+        disconnect_server("9002");
+        thread::sleep(Duration::from_millis(1000));
+
+        let all_lock = Order::new(1, OrderAction::UsePoints(20)).with_id(2);
+        let all_response = send_client_message("9000", Message::LockOrder(all_lock));
+        // A 9001 le alcanza con la mayoria
+        let majority_lock = Order::new(1, OrderAction::UsePoints(20)).with_id(3);
+        let majority_response = send_client_message("9001", Message::LockOrder(majority_lock));
+
+        stop_server(server_1);
+        stop_server(server_2);
+        stop_server(server_3);
+
+        assert_eq!(fill_response, Response::Ok);
+        assert_eq!(all_response, Response::Aborted);
+        assert!(matches!(majority_response, Response::Reserved(_)));
+    }
+
+    #[test]
+    #[serial]
+    fn servers_should_apply_the_orders_replicated_by_the_raft_leader() {
+        let raft_flags = ["--replication", "raft"];
+        let server_1 = create_server_with_flags("9000", None, &raft_flags);
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let server_2 = create_server_with_flags("9001", Some("9000"), &raft_flags);
+        thread::sleep(Duration::from_millis(1000));
+
+        let server_3 = create_server_with_flags("9002", Some("9000"), &raft_flags);
+        // Esperamos que el lider le replique el log al nuevo servidor
+        thread::sleep(Duration::from_millis(1000));
+
+        // Cualquier servidor acepta ordenes, los que no son lideres se las reenvian al lider
+        let fill = Order::new(1, OrderAction::FillPoints(50)).with_id(1);
+        let fill_response = send_client_message("9001", Message::CommitOrder(fill));
+        let lock = Order::new(1, OrderAction::UsePoints(20)).with_id(2);
+        let lock_response = send_client_message("9002", Message::LockOrder(lock));
+        let reservation = match lock_response {
+            Response::Reserved(reservation) => reservation,
+            _ => 0,
+        };
+        let consume = Order::new(1, OrderAction::UsePoints(20))
+            .with_reservation(reservation)
+            .with_id(3);
+        let consume_response = send_client_message("9000", Message::CommitOrder(consume));
+
+        // Esperamos que los seguidores apliquen las entradas comprometidas
+        thread::sleep(Duration::from_millis(500));
+        let balances: Vec<Response> = ["9000", "9001", "9002"]
+            .iter()
+            .map(|address| query_balance(address, 1, BalanceRead::Local))
+            .collect();
+
+        // Sin mayoria no se compromete ninguna orden
+        disconnect_server("9001");
+        disconnect_server("9002");
+        thread::sleep(Duration::from_millis(1000));
+        let minority_fill = Order::new(1, OrderAction::FillPoints(10)).with_id(4);
+        let minority_response = send_client_message("9000", Message::CommitOrder(minority_fill));
+
+        stop_server(server_1);
+        stop_server(server_2);
+        stop_server(server_3);
+
+        assert_eq!(fill_response, Response::Ok);
+        assert!(matches!(lock_response, Response::Reserved(_)));
+        assert_eq!(consume_response, Response::Ok);
+        for balance in balances {
+            assert_eq!(balance, Response::Balance(Balance::new(30, 0)));
+        }
+        assert!(matches!(
+            minority_response,
+            Response::Timeout | Response::Offline
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn server_should_recover_its_points_after_a_restart() {
+        let data_dir = std::env::temp_dir().join("server-wal-test");
+        let _ = std::fs::remove_dir_all(&data_dir);
+        let data_dir_flags = ["--data-dir", data_dir.to_str().unwrap()];
+
+        let server_1 = create_server_with_flags("9000", None, &data_dir_flags);
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let server_2 = create_server("9001", Some("9000"));
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let fill = Order::new(1, OrderAction::FillPoints(50)).with_id(1);
+        send_client_message("9000", Message::CommitOrder(fill));
+
+        // Los puntos que carga el server desconectado quedan pendientes
+        disconnect_server("9000");
+        thread::sleep(Duration::from_millis(1000));
+        let offline_fill = Order::new(1, OrderAction::FillPoints(30)).with_id(2);
+        send_client_message("9000", Message::CommitOrder(offline_fill));
+
+        // El server se cae y vuelve a levantar solo, con el mismo directorio de datos
+        stop_server(server_1);
+        stop_server(server_2);
+
+        let server_1 = create_server_with_flags("9000", None, &data_dir_flags);
+        // Esperamos que levante y aplique las transacciones pendientes
+        thread::sleep(Duration::from_millis(2000));
+        let balance = query_balance("9000", 1, BalanceRead::Local);
+
+        stop_server(server_1);
+        let _ = std::fs::remove_dir_all(&data_dir);
+
+        assert_eq!(balance, Response::Balance(Balance::new(80, 0)));
+    }
+
+    #[test]
+    #[serial]
+    fn server_should_recover_its_points_from_a_snapshot() {
+        let data_dir = std::env::temp_dir().join("server-snapshot-test");
+        let _ = std::fs::remove_dir_all(&data_dir);
+        let flags = [
+            "--data-dir",
+            data_dir.to_str().unwrap(),
+            "--snapshot-interval",
+            "1",
+        ];
+
+        let server_1 = create_server_with_flags("9000", None, &flags);
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let fill = Order::new(1, OrderAction::FillPoints(50)).with_id(1);
+        send_client_message("9000", Message::CommitOrder(fill));
+
+        // Esperamos que se tome un snapshot y se compacte el log
+        thread::sleep(Duration::from_millis(2500));
+        let fill = Order::new(1, OrderAction::FillPoints(20)).with_id(2);
+        send_client_message("9000", Message::CommitOrder(fill));
+
+        stop_server(server_1);
+        let snapshot_taken = data_dir.join("localhost_9000.snapshot").exists();
+
+        let server_1 = create_server_with_flags("9000", None, &flags);
+        thread::sleep(Duration::from_millis(1000));
+        let balance = query_balance("9000", 1, BalanceRead::Local);
+
+        stop_server(server_1);
+        let _ = std::fs::remove_dir_all(&data_dir);
+
+        assert!(snapshot_taken);
+        assert_eq!(balance, Response::Balance(Balance::new(70, 0)));
+    }
+
+    #[test]
+    #[serial]
+    fn server_should_serve_many_idle_coffee_makers() {
+        let server_1 = create_server("9000", None);
+        // El sleep es para dar tiempo a buildear al tirar un cargo run
+        thread::sleep(Duration::from_millis(1000));
+
+        let server_2 = create_server("9001", Some("9000"));
+        thread::sleep(Duration::from_millis(1000));
+
+        // Muchas más cafeteras conectadas que threads tiene el servidor
+        let mut coffee_makers: Vec<_> = (0..200).map(|_| connect_client("9000", None)).collect();
+
+        let fill = Order::new(1, OrderAction::FillPoints(30)).with_id(1);
+        let fill_response = send_over(&mut coffee_makers[150], Message::CommitOrder(fill));
+        let local = send_over(
+            &mut coffee_makers[0],
+            Message::QueryBalance(1, BalanceRead::Local),
+        );
+        // El otro servidor también recibió la transacción
+        let remote = query_balance("9001", 1, BalanceRead::Local);
+
+        stop_server(server_1);
+        stop_server(server_2);
+
+        assert_eq!(fill_response, Response::Ok);
+        assert_eq!(local, Response::Balance(Balance::new(30, 0)));
+        assert_eq!(remote, Response::Balance(Balance::new(30, 0)));
+    }
 }
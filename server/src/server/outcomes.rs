@@ -0,0 +1,72 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use super::transaction::Transaction;
+
+/// Amount of outcomes remembered before the oldest ones are forgotten.
+const CAPACITY: usize = 1024;
+
+/// Remembers the outcome of the recent transactions this server decided or was told about,
+/// so it can tell the participants that did not receive it.
+#[derive(Debug, Default)]
+pub struct Outcomes {
+    outcomes: Mutex<VecDeque<(Transaction, bool)>>,
+}
+
+impl Outcomes {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Remembers whether the transaction was committed.
+    pub fn record(&self, transaction: &Transaction, commit: bool) {
+        let mut outcomes = self.outcomes.lock().expect("Could not lock outcomes");
+        outcomes.push_back((transaction.clone(), commit));
+        if outcomes.len() > CAPACITY {
+            outcomes.pop_front();
+        }
+    }
+
+    /// Returns whether the transaction was committed, if its outcome is known.
+    pub fn get(&self, transaction: &Transaction) -> Option<bool> {
+        self.outcomes
+            .lock()
+            .expect("Could not lock outcomes")
+            .iter()
+            .rev()
+            .find(|(tx, _)| tx.same_as(transaction))
+            .map(|(_, commit)| *commit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use points::{Message, Order, OrderAction};
+
+    use super::*;
+
+    #[test]
+    fn test_forget_the_oldest_outcomes() {
+        let outcomes = Outcomes::new();
+        let transaction = |client_id| {
+            let message = Message::CommitOrder(Order::new(client_id, OrderAction::FillPoints(5)));
+            Transaction::new("127.0.0.1:9000".to_string(), &message).unwrap()
+        };
+        let first = transaction(0);
+        outcomes.record(&first, true);
+        let aborted = transaction(1);
+        outcomes.record(&aborted, false);
+
+        assert_eq!(outcomes.get(&first), Some(true));
+        assert_eq!(outcomes.get(&aborted), Some(false));
+        assert_eq!(outcomes.get(&transaction(2)), None);
+
+        for client_id in 2..=CAPACITY as u64 {
+            outcomes.record(&transaction(client_id), true);
+        }
+        assert_eq!(outcomes.get(&first), None);
+        assert_eq!(outcomes.get(&aborted), Some(false));
+    }
+}
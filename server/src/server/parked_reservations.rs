@@ -0,0 +1,143 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tracing::debug;
+
+use super::connection_reservations::HeldReservation;
+
+#[derive(Debug)]
+struct Machine {
+    /// Open connections identified with the machine id.
+    connections: usize,
+    parked_at: Instant,
+    parked: Vec<HeldReservation>,
+}
+
+/// Reservations of coffee makers whose connection dropped,
+/// kept for a grace period in case they connect again.
+#[derive(Debug, Default)]
+pub struct ParkedReservations {
+    machines: Mutex<HashMap<u64, Machine>>,
+}
+
+impl ParkedReservations {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Registers a new connection of the given coffee maker.
+    /// Returns the reservations parked by its previous connections.
+    pub fn connect(&self, machine_id: u64) -> Vec<HeldReservation> {
+        let mut machines = self
+            .machines
+            .lock()
+            .expect("Could not lock parked reservations");
+        let machine = machines.entry(machine_id).or_insert_with(|| Machine {
+            connections: 0,
+            parked_at: Instant::now(),
+            parked: vec![],
+        });
+        machine.connections += 1;
+        std::mem::take(&mut machine.parked)
+    }
+
+    /// Registers that a connection of the given coffee maker dropped,
+    /// parking the reservations it did not settle.
+    /// A connection that drops while the coffee maker is already connected again
+    /// keeps its reservations parked until every connection of the coffee maker dropped.
+    pub fn disconnect(&self, machine_id: u64, reservations: Vec<HeldReservation>) {
+        let mut machines = self
+            .machines
+            .lock()
+            .expect("Could not lock parked reservations");
+        let machine = match machines.get_mut(&machine_id) {
+            Some(machine) => machine,
+            None => return,
+        };
+        if !reservations.is_empty() {
+            debug!(
+                "Parking {} reservations of machine {}",
+                reservations.len(),
+                machine_id
+            );
+        }
+        machine.connections -= 1;
+        machine.parked_at = Instant::now();
+        machine.parked.extend(reservations);
+
+        if machine.connections == 0 && machine.parked.is_empty() {
+            machines.remove(&machine_id);
+        }
+    }
+
+    /// Removes and returns the reservations of the coffee makers
+    /// that did not connect again within the grace period.
+    pub fn take_expired(&self, grace: Duration) -> Vec<HeldReservation> {
+        let mut machines = self
+            .machines
+            .lock()
+            .expect("Could not lock parked reservations");
+        let expired: Vec<u64> = machines
+            .iter()
+            .filter(|(_, machine)| machine.connections == 0 && machine.parked_at.elapsed() >= grace)
+            .map(|(machine_id, _)| *machine_id)
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|machine_id| machines.remove(&machine_id))
+            .flat_map(|machine| machine.parked)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn held(reservation: u64) -> HeldReservation {
+        HeldReservation {
+            client_id: 1,
+            reservation,
+            points: 10,
+        }
+    }
+
+    #[test]
+    fn test_reconnect_claims_parked_reservations() {
+        let parked = ParkedReservations::new();
+        assert_eq!(parked.connect(7), vec![]);
+        parked.disconnect(7, vec![held(1)]);
+
+        assert_eq!(parked.connect(8), vec![]);
+        assert_eq!(parked.connect(7), vec![held(1)]);
+        assert_eq!(parked.take_expired(Duration::ZERO), vec![]);
+    }
+
+    #[test]
+    fn test_expired_parked_reservations() {
+        let parked = ParkedReservations::new();
+        parked.connect(7);
+        parked.disconnect(7, vec![held(1)]);
+
+        assert_eq!(parked.take_expired(Duration::from_secs(60)), vec![]);
+        assert_eq!(parked.take_expired(Duration::ZERO), vec![held(1)]);
+        assert_eq!(parked.connect(7), vec![]);
+    }
+
+    #[test]
+    fn test_late_disconnect_waits_for_every_connection() {
+        let parked = ParkedReservations::new();
+        parked.connect(7);
+        // The coffee maker connects again before its old connection dropped
+        parked.connect(7);
+        parked.disconnect(7, vec![held(1)]);
+        assert_eq!(parked.take_expired(Duration::ZERO), vec![]);
+
+        parked.disconnect(7, vec![held(2)]);
+        assert_eq!(parked.take_expired(Duration::ZERO), vec![held(1), held(2)]);
+    }
+}
@@ -6,14 +6,20 @@ use std::{
 use std_semaphore::Semaphore;
 use tracing::debug;
 
-use super::transaction::Transaction;
+use super::{
+    transaction::Transaction,
+    wal::{Wal, WalEntry},
+};
 
 pub struct PendingTransactions {
     transactions: Mutex<VecDeque<Transaction>>,
+    /// Transaction popped from the queue that is still being coordinated.
+    in_flight: Mutex<Option<Transaction>>,
     semaphore: Semaphore,
     online: Semaphore,
     connected: Mutex<bool>,
     on_connect: Mutex<Option<Box<dyn Fn() + Send + Sync>>>,
+    wal: Arc<Wal>,
 }
 
 impl std::fmt::Debug for PendingTransactions {
@@ -25,13 +31,16 @@ impl std::fmt::Debug for PendingTransactions {
 }
 
 impl PendingTransactions {
-    pub fn new() -> Arc<Self> {
+    /// Creates an empty queue that records its changes in the given log.
+    pub fn new(wal: Arc<Wal>) -> Arc<Self> {
         Arc::new(Self {
             transactions: Mutex::new(VecDeque::new()),
+            in_flight: Mutex::new(None),
             semaphore: Semaphore::new(0),
             online: Semaphore::new(1),
             connected: Mutex::new(true),
             on_connect: Mutex::new(Some(Box::new(|| {}))),
+            wal,
         })
     }
 
@@ -41,16 +50,60 @@ impl PendingTransactions {
     }
 
     /// Adds an transaction to the queue.
+    /// Fails if it could not be written to the log, leaving it out of the queue.
     pub fn add(&self, transaction: Transaction) -> Result<(), String> {
         let mut txs = self
             .transactions
             .lock()
             .map_err(|_| "Could not lock transactions")?;
+        self.wal.append(&WalEntry::Enqueue(transaction.clone()))?;
+        Self::land(
+            &mut self.in_flight.lock().expect("Could not lock in flight"),
+            &transaction,
+        );
         txs.push_back(transaction);
         self.semaphore.release();
         Ok(())
     }
 
+    /// Adds the transactions recovered from the log to the queue, without logging them again.
+    pub fn restore(&self, transactions: Vec<Transaction>) {
+        let mut txs = self
+            .transactions
+            .lock()
+            .expect("Could not lock transactions");
+        for transaction in transactions {
+            txs.push_back(transaction);
+            self.semaphore.release();
+        }
+    }
+
+    /// Records that a popped transaction left the queue for good.
+    pub fn forget(&self, transaction: &Transaction) {
+        // Held while logging, so a snapshot never includes a transaction already dequeued in the log
+        let mut in_flight = self.in_flight.lock().expect("Could not lock in flight");
+        self.wal
+            .append_or_stop(&WalEntry::Dequeue(transaction.clone()));
+        Self::land(&mut in_flight, transaction);
+    }
+
+    /// Stops tracking the given transaction as in flight, as it was queued again or forgotten.
+    fn land(in_flight: &mut Option<Transaction>, transaction: &Transaction) {
+        if matches!(in_flight, Some(tx) if tx.same_as(transaction)) {
+            *in_flight = None;
+        }
+    }
+
+    /// Returns the transactions in the queue, including the one being coordinated.
+    pub fn snapshot(&self) -> Vec<Transaction> {
+        let txs = self
+            .transactions
+            .lock()
+            .expect("Could not lock transactions");
+        let in_flight = self.in_flight.lock().expect("Could not lock in flight");
+        in_flight.iter().chain(txs.iter()).cloned().collect()
+    }
+
     /// Returns the next transaction in the queue.
     /// If there are no transactions, the thread will be blocked until there is one.
     pub fn pop(&self) -> Result<Transaction, String> {
@@ -61,8 +114,11 @@ impl PendingTransactions {
             .transactions
             .lock()
             .expect("Could not lock transactions");
-        txs.pop_front()
-            .ok_or_else(|| "Could not pop transaction".to_string())
+        let transaction = txs
+            .pop_front()
+            .ok_or_else(|| "Could not pop transaction".to_string())?;
+        *self.in_flight.lock().expect("Could not lock in flight") = Some(transaction.clone());
+        Ok(transaction)
     }
 
     pub fn disconnect(&self) {
@@ -94,7 +150,7 @@ mod tests {
     use super::*;
     #[test]
     fn test_add_transactions() {
-        let pending_transactions = PendingTransactions::new();
+        let pending_transactions = PendingTransactions::new(Wal::disabled());
         let order = Order::new(1, OrderAction::UsePoints(123));
         let message = Message::LockOrder(order);
         let transaction = Transaction::new("127.0.0.1:9001".to_string(), &message).unwrap();
@@ -106,4 +162,25 @@ mod tests {
         assert_eq!(&transaction.clone().client_id, &my_transaction.client_id);
         assert_eq!(&transaction.points, &my_transaction.points);
     }
+
+    #[test]
+    fn test_snapshot_includes_the_transaction_in_flight() {
+        let pending_transactions = PendingTransactions::new(Wal::disabled());
+        let order = Order::new(1, OrderAction::FillPoints(10));
+        let message = Message::CommitOrder(order);
+        let first = Transaction::new("127.0.0.1:9001".to_string(), &message).unwrap();
+        let second = Transaction::new("127.0.0.1:9002".to_string(), &message).unwrap();
+
+        let _ = pending_transactions.add(first.clone());
+        let _ = pending_transactions.add(second.clone());
+        let popped = pending_transactions.pop().unwrap();
+        let snapshot = pending_transactions.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert!(snapshot[0].same_as(&first));
+
+        pending_transactions.forget(&popped);
+        let snapshot = pending_transactions.snapshot();
+        assert_eq!(snapshot.len(), 1);
+        assert!(snapshot[0].same_as(&second));
+    }
 }
@@ -1,40 +1,173 @@
 use super::{
+    decision::Decision,
+    decisions::Decisions,
+    earned::Earned,
+    link::Exchange,
+    message::AbortReason,
     pending_transactions::PendingTransactions,
+    point_storage::PointMap,
+    quorum::Quorum,
+    record_queue::RecordQueue,
+    reservation::Reservation,
     transaction::{Transaction, TransactionAction, TransactionState, TxOk, COMMIT_TIMEOUT},
+    versions::Versions,
+    wal::{Wal, WalEntry},
 };
+use points::Response;
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     fmt,
     io::Read,
-    net::TcpStream,
     sync::{Arc, Mutex},
 };
-use tracing::{debug, info, warn};
+use tracing::{debug, error, info, warn};
+
+/// Exchanges with the servers asked to prepare a transaction, by server.
+type Streams = Vec<(String, Result<Exchange, String>)>;
 
 /// Points tuple: available points, locked points
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Points(pub usize, pub usize);
+pub struct Points(pub u64, pub u64);
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct PointRecord {
     pub points: Arc<Mutex<Points>>,
-    pub transaction: Option<Transaction>,
+    /// Transactions holding or waiting for the record.
+    #[serde(skip)]
+    pub queue: RecordQueue,
+    /// Reservations that were not freed or consumed yet, by id.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub reservations: HashMap<u64, Reservation>,
+    /// Points added by each server while offline, included in the available points.
+    #[serde(default, skip_serializing_if = "Earned::is_empty")]
+    pub earned: Earned,
+    /// Version of the last change to the record, to sync only the records that changed.
+    #[serde(skip)]
+    pub version: u64,
 }
 
 impl PointRecord {
     pub fn new() -> Self {
         PointRecord {
             points: Arc::new(Mutex::new(Points(0, 0))),
-            transaction: None,
+            queue: RecordQueue::default(),
+            reservations: HashMap::new(),
+            earned: Earned::default(),
+            version: 0,
         }
     }
 
-    pub fn wait_die(&self, transaction: &Transaction) -> Result<(), String> {
-        if let Some(etx) = self.transaction.clone() {
-            if transaction.older_than(&etx) {
-                return Err("Transaction is older than the current one".to_string());
+    /// Takes the reservation a free or a consume is about to settle,
+    /// so no other transaction can settle it meanwhile.
+    /// Fails if the reservation is unknown, already settled or for a different amount of points.
+    pub fn take_reservation(&mut self, transaction: &Transaction) -> Result<(), Response> {
+        if !transaction.settles_reservation() {
+            return Ok(());
+        }
+        let reservation = transaction
+            .reservation
+            .ok_or(Response::UnknownReservation)?;
+        match self.reservations.get_mut(&reservation) {
+            Some(reservation)
+                if !reservation.settling && reservation.points == transaction.points =>
+            {
+                reservation.settling = true;
+                Ok(())
+            }
+            _ => {
+                debug!("Unknown reservation {:?}", transaction);
+                Err(Response::UnknownReservation)
+            }
+        }
+    }
+
+    /// Gives back a reservation taken by a transaction that was not applied.
+    pub fn restore_reservation(&mut self, transaction: &Transaction) {
+        if !transaction.settles_reservation() {
+            return;
+        }
+        if let Some(reservation) = transaction
+            .reservation
+            .and_then(|reservation| self.reservations.get_mut(&reservation))
+        {
+            reservation.settling = false;
+        }
+    }
+
+    /// Updates the reservations once a transaction was applied:
+    /// a lock creates its reservation and a free or a consume removes the one it settled.
+    pub fn settle(&mut self, transaction: &Transaction) {
+        let reservation = match transaction.reservation {
+            Some(reservation) => reservation,
+            None => return,
+        };
+        match transaction.action {
+            TransactionAction::Lock => {
+                self.reservations
+                    .insert(reservation, Reservation::new(transaction));
+            }
+            TransactionAction::Free | TransactionAction::Consume => {
+                self.reservations.remove(&reservation);
+            }
+            TransactionAction::Add => {}
+        }
+    }
+
+    /// Adds the points of a transaction that could not reach the other servers
+    /// as points earned offline by the given server, writing the record to the log.
+    pub fn earn_logged(
+        &mut self,
+        server: &str,
+        transaction: &Transaction,
+        wal: &Wal,
+    ) -> Result<(), String> {
+        let mut points = self.points.lock().map_err(|_| "Failed to lock points")?;
+        let earned = self.earned.clone();
+        points.0 += transaction.points;
+        self.earned.add(server, transaction.points);
+        drop(points);
+        if let Err(e) = self.log(transaction.client_id, wal) {
+            // Not earned after all
+            self.points.lock().map_err(|_| "Failed to lock points")?.0 -= transaction.points;
+            self.earned = earned;
+            return Err(e);
+        }
+        info!("Earned offline {:?}.", transaction);
+        Ok(())
+    }
+
+    /// Merges the points earned offline known by another server,
+    /// adding the ones this record did not know about to the available points.
+    /// Returns the amount of points added.
+    pub fn merge_earned(&mut self, earned: &Earned) -> Result<u64, String> {
+        let gained = self.earned.merge(earned);
+        if gained > 0 {
+            let mut points = self.points.lock().map_err(|_| "Failed to lock points")?;
+            points.0 += gained;
+        }
+        Ok(gained)
+    }
+
+    /// Writes a copy of the record to the log.
+    pub fn log(&self, client_id: u64, wal: &Wal) -> Result<(), String> {
+        let record = SafePointRecord(Arc::new(Mutex::new(self.clone())));
+        wal.append(&WalEntry::Merge(PointMap::from([(client_id, record)])))
+    }
+
+    /// Wait-die: a transaction younger than the one holding the record dies,
+    /// an older one may wait for its turn.
+    /// A transaction of the same coordinator as the holder also waits: its coordinator already
+    /// decided the holder, which is only being finished here.
+    pub fn wait_die(&self, transaction: &Transaction) -> Result<(), Response> {
+        if let Some(etx) = self.queue.holder() {
+            if etx.older_than(transaction)
+                && !etx.same_as(transaction)
+                && etx.coordinator != transaction.coordinator
+            {
+                debug!("Transaction is younger than the current one");
+                return Err(Response::WaitDieConflict);
             }
         }
         Ok(())
@@ -43,63 +176,76 @@ impl PointRecord {
 
 impl Points {
     /// Prepares the transaction
-    /// Returns (abort, streams)
+    /// Returns (state, died, streams), with the streams to each server,
+    /// `died` being whether a server killed the transaction by wait-die.
+    /// The transaction proceeds if no server aborts it and the servers that approve it,
+    /// along with the coordinator, meet the quorum.
     fn prepare(
         &mut self,
         transaction: Transaction,
         servers: HashSet<String>,
         online: bool,
-    ) -> Result<(TransactionState, Vec<Result<TcpStream, String>>), String> {
+        quorum: &Quorum,
+    ) -> Result<(TransactionState, bool, Streams), String> {
         if !online {
-            return Ok((TransactionState::Disconnected, vec![]));
+            return Ok((TransactionState::Disconnected, false, vec![]));
         }
 
         // PREPARE TRANSACTION
 
-        let res: Vec<Result<(TransactionState, TcpStream), String>> = servers
+        let res: Vec<_> = servers
             .par_iter()
-            .map(|server| Transaction::prepare(&transaction, server))
+            .map(|server| (server.clone(), Transaction::prepare(&transaction, server)))
             .collect();
 
         // Evaluate the results. If any of the servers failed to prepare, abort the transaction.
-        // If less than half timeout, proceed to commit.
+        // If the servers that approved meet the quorum, proceed to commit.
 
         let mut proceed = 0;
         let mut abort = 0;
+        let mut died = false;
+        let mut approved = HashSet::from([transaction.coordinator.clone()]);
 
-        let streams: Vec<Result<TcpStream, String>> = res
+        let streams: Streams = res
             .into_iter()
-            .map(|res| match res {
-                Ok((state, stream)) => {
-                    match state {
-                        TransactionState::Proceed => {
-                            debug!(
-                                "Received APPROVE message for transaction with timestamp {}.",
-                                transaction.timestamp
-                            );
-                            proceed += 1
+            .map(|(server, res)| {
+                let stream = match res {
+                    Ok((state, reason, stream)) => {
+                        match state {
+                            TransactionState::Proceed => {
+                                debug!(
+                                    "Received APPROVE message for transaction with timestamp {}.",
+                                    transaction.timestamp
+                                );
+                                proceed += 1;
+                                approved.insert(server.clone());
+                            }
+                            TransactionState::Abort => {
+                                debug!(
+                                    "Received ABORT message for transaction with timestamp {}.",
+                                    transaction.timestamp
+                                );
+                                abort += 1;
+                                died |= reason == Some(AbortReason::WaitDie);
+                            }
+                            _ => {}
                         }
-                        TransactionState::Abort => {
-                            debug!(
-                                "Received ABORT message for transaction with timestamp {}.",
-                                transaction.timestamp
-                            );
-                            abort += 1
-                        }
-                        _ => {}
+                        Ok(stream)
                     }
-                    Ok(stream)
-                }
-                Err(e) => Err(e),
+                    Err(e) => Err(e),
+                };
+                (server, stream)
             })
             .collect();
 
         // Evaluate if the transaction should be aborted or committed
         if abort == 0 && proceed == 0 {
-            return Ok((TransactionState::Disconnected, streams));
+            return Ok((TransactionState::Disconnected, false, streams));
         }
 
-        let abort = abort > 0 || proceed < servers.len() / 2;
+        let mut cluster = servers;
+        cluster.insert(transaction.coordinator.clone());
+        let abort = abort > 0 || !quorum.is_met(&approved, &cluster);
         let state = if abort {
             debug!(
                 "Coordinator decided to ABORT transaction with timestamp {}.",
@@ -113,57 +259,97 @@ impl Points {
             );
             TransactionState::Proceed
         };
-        Ok((state, streams))
+        Ok((state, died, streams))
     }
 
     /// Coordinates a transaction among all other servers
     /// The algorithm works as follows:
     /// 1. The coordinator sends a prepare message to all other servers
     /// 2. Each server responds with a proceed message if it can commit the transaction
-    /// 3. If all servers (or if less than half of them timeout) respond with proceed, the coordinator sends a commit message to all server
-    /// 3.1 If any server responds with an abort, the coordinator sends an abort message to all servers
+    /// 3. If the servers that respond with proceed meet the quorum, the coordinator sends a commit message to all server
+    ///    - If any server responds with an abort, the coordinator sends an abort message to all servers
+    ///
+    /// The same quorum is required for a lock and for the other actions when their pending transactions are retried.
+    /// The decision is written to the log before it is sent, and kept until every server received it.
+    #[allow(clippy::too_many_arguments)]
     pub fn coordinate(
         &mut self,
         transaction: Transaction,
         servers: HashSet<String>,
         online: bool,
+        quorum: &Quorum,
         pending: Arc<PendingTransactions>,
-    ) -> Result<TxOk, String> {
+        wal: &Wal,
+        versions: &Versions,
+        decisions: &Decisions,
+    ) -> Result<TxOk, Response> {
         self.can_perform(&transaction)?;
 
-        // Commit the transaction directly if this is the only server
-        if servers.is_empty() {
-            self.apply(transaction);
+        // Commit the transaction directly if this is the only server and it is enough
+        let coordinator = HashSet::from([transaction.coordinator.clone()]);
+        if servers.is_empty() && quorum.is_met(&coordinator, &coordinator) {
+            if let Err(e) = self.apply_logged(transaction, wal, versions) {
+                error!("{}", e);
+                return Err(Response::Aborted);
+            }
             return Ok(TxOk::Finalized);
         }
 
         // PREPARE TRANSACTION
-        let (state, streams) = self.prepare(transaction.clone(), servers, online)?;
+        let (state, died, streams) = self
+            .prepare(transaction.clone(), servers, online, quorum)
+            .map_err(|_| Response::Aborted)?;
+
+        // DECIDE TRANSACTION
+        let participants = streams
+            .iter()
+            .filter(|(_, stream)| stream.is_ok())
+            .map(|(server, _)| server.clone())
+            .collect();
+        let decision = Decision::new(transaction.clone(), &state, participants);
+        let decided = match state {
+            TransactionState::Proceed => self.apply_decided(decision, decisions, versions),
+            TransactionState::Abort => decisions.decide(decision, None),
+            _ => Ok(()),
+        };
+        // A decision that could not be logged is not taken, the participants are told to abort
+        let state = match decided {
+            Ok(()) => state,
+            Err(e) => {
+                error!("Aborting {:?}: {}", transaction, e);
+                TransactionState::Abort
+            }
+        };
 
         // FINALIZE TRANSACTION
-        for stream in streams {
+        let mut missing = vec![];
+        for (server, stream) in streams {
             match stream {
                 Ok(mut stream) => {
-                    let _ = Transaction::finalize(&mut stream, state.clone());
+                    if Transaction::finalize(&mut stream, state.clone()).is_err() {
+                        missing.push(server);
+                    }
                 }
                 Err(err) => {
                     warn!(err)
                 }
             }
         }
+        decisions.sent(&transaction, missing);
 
         match state {
             TransactionState::Proceed => {
                 pending.connect();
-                self.apply(transaction);
                 Ok(TxOk::Finalized)
             }
             TransactionState::Abort => {
                 pending.connect();
                 match transaction.action {
-                    TransactionAction::Lock => Err("Transaction Aborted".to_string()),
+                    // The coordinator retries it, as an older transaction would not have died
+                    TransactionAction::Lock if died => Err(Response::WaitDieConflict),
+                    TransactionAction::Lock => Err(Response::Aborted),
                     _ => {
-                        pending.add(transaction)?;
+                        pending.add(transaction).map_err(|_| Response::Aborted)?;
                         Ok(TxOk::Pending)
                     }
                 }
@@ -171,23 +357,24 @@ impl Points {
             TransactionState::Disconnected => {
                 pending.disconnect();
                 match transaction.action {
-                    TransactionAction::Lock => Err("Transaction Aborted".to_string()),
+                    TransactionAction::Lock => Err(Response::Offline),
+                    TransactionAction::Add => Ok(TxOk::Offline),
                     _ => {
-                        pending.add(transaction)?;
+                        pending.add(transaction).map_err(|_| Response::Aborted)?;
                         Ok(TxOk::Pending)
                     }
                 }
             }
-            _ => Err("Invalid TransactionState".to_string()),
+            _ => Err(Response::Aborted),
         }
     }
 
-    pub fn can_perform(&self, transaction: &Transaction) -> Result<(), String> {
+    pub fn can_perform(&self, transaction: &Transaction) -> Result<(), Response> {
         match transaction.action {
             TransactionAction::Add => Ok(()),
             TransactionAction::Lock => {
                 if self.0 < transaction.points {
-                    Err("Not enough points available".to_string())
+                    Err(Response::InsufficientPoints)
                 } else {
                     Ok(())
                 }
@@ -195,7 +382,7 @@ impl Points {
             _ => {
                 // Free or Consume
                 if self.1 < transaction.points {
-                    Err("Not enough points locked".to_string())
+                    Err(Response::InsufficientLocked)
                 } else {
                     Ok(())
                 }
@@ -206,11 +393,14 @@ impl Points {
     /// Handles a transaction waiting for a commit message or an abort message.
     /// If the transaction is aborted, the transaction is discarded.
     /// If the transaction is committed, the transaction is applied to the points.
+    /// Returns the decision received, or an error if none was received.
     pub fn handle_transaction(
         &mut self,
         transaction: Transaction,
-        mut coordinator: TcpStream,
-    ) -> Result<(), String> {
+        coordinator: &mut Exchange,
+        wal: &Wal,
+        versions: &Versions,
+    ) -> Result<TransactionState, String> {
         // Already received a transaction, locked points and answered the prepare
         // Should now wait for the commit (for a fixed period of time) or abort
         coordinator
@@ -227,15 +417,64 @@ impl Points {
                 "Received COMMIT message from coordinator for transaction with timestamp {}.",
                 transaction.timestamp
             );
-            self.apply(transaction);
-            Ok(())
+            // The coordinator committed it, so it can not be refused
+            if let Err(e) = self.apply_logged(transaction, wal, versions) {
+                Wal::stop(&e);
+            }
+            Ok(TransactionState::Proceed)
         } else {
             debug!(
                 "Received ABORT message from coordinator for transaction with timestamp {}.",
                 transaction.timestamp
             );
-            Err("Aborted Transaction".to_string())
+            Ok(TransactionState::Abort)
+        }
+    }
+
+    /// Writes the decision to commit a coordinated transaction to the log before applying it.
+    /// It is not applied if the decision could not be written.
+    fn apply_decided(
+        &mut self,
+        decision: Decision,
+        decisions: &Decisions,
+        versions: &Versions,
+    ) -> Result<(), String> {
+        let transaction = decision.transaction.clone();
+        let mut points = self.clone();
+        points.apply(transaction.clone());
+        let committed = versions.commit();
+        if let Err(e) = decisions.decide(decision, Some((points, committed))) {
+            versions.revert_commit();
+            return Err(e);
+        }
+        self.apply(transaction);
+        Ok(())
+    }
+
+    /// Writes the transaction to the log before applying it to the points, counting it as applied.
+    /// The entry keeps the resulting points and the amount of transactions applied,
+    /// so replaying it does not depend on the previous ones.
+    /// It is not applied if it could not be written.
+    pub fn apply_logged(
+        &mut self,
+        transaction: Transaction,
+        wal: &Wal,
+        versions: &Versions,
+    ) -> Result<(), String> {
+        let mut points = self.clone();
+        points.apply(transaction.clone());
+        let committed = versions.commit();
+        let logged = wal.append(&WalEntry::Apply {
+            transaction: transaction.clone(),
+            points,
+            committed,
+        });
+        if let Err(e) = logged {
+            versions.revert_commit();
+            return Err(e);
         }
+        self.apply(transaction);
+        Ok(())
     }
 
     /// Applies a transaction to the points
@@ -243,21 +482,22 @@ impl Points {
     /// If the transaction is free, the points are unlocked (decreasing the locked points and increasing the available points)
     /// If the transaction is an add, the points are added (increasing the available points)
     /// If the transaction is a consume, the points are subtracted (decreasing the locked points)
+    /// The points never go below zero, as a committed transaction in doubt is applied even if a sync changed them.
     pub fn apply(&mut self, transaction: Transaction) {
         match transaction.action {
             TransactionAction::Add => {
                 self.0 += transaction.points;
             }
             TransactionAction::Lock => {
-                self.0 -= transaction.points;
+                self.0 = self.0.saturating_sub(transaction.points);
                 self.1 += transaction.points;
             }
             TransactionAction::Free => {
                 self.0 += transaction.points;
-                self.1 -= transaction.points;
+                self.1 = self.1.saturating_sub(transaction.points);
             }
             TransactionAction::Consume => {
-                self.1 -= transaction.points;
+                self.1 = self.1.saturating_sub(transaction.points);
             }
         }
         info!("Applied {:?}.", transaction);
@@ -272,7 +512,11 @@ impl fmt::Debug for SafePointRecord {
         let record = self.0.lock().map_err(|_| fmt::Error)?;
         let points = record.points.clone();
         let points = points.lock().map_err(|_| fmt::Error)?;
-        write!(f, "{:?} Available [{:?} Locked]", points.0, points.1)
+        write!(f, "{:?} Available [{:?} Locked]", points.0, points.1)?;
+        match record.queue.depth() {
+            0 => Ok(()),
+            depth => write!(f, " ({} waiting)", depth),
+        }
     }
 }
 
@@ -284,9 +528,73 @@ impl SafePointRecord {
 
 #[cfg(test)]
 mod tests {
+    use std::{net::TcpListener, thread, time::Duration};
+
     use points::{Message, Order, OrderAction};
 
     use super::*;
+    use crate::server::{
+        link::Link, message::respond_error_to, outcomes::Outcomes, server_error::ServerError,
+    };
+
+    /// Serves links on a new port, answering every message with an `Offline` error.
+    fn offline_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                stream.read_exact(&mut [0; 1]).unwrap();
+                thread::spawn(move || {
+                    Link::serve(stream, |mut exchange| {
+                        let _ = respond_error_to(&mut exchange, &ServerError::Offline);
+                    })
+                });
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn test_coordinate_when_every_server_is_offline() {
+        let servers = HashSet::from([offline_server(), offline_server()]);
+        let wal = Wal::disabled();
+        let pending = PendingTransactions::new(wal.clone());
+        let decisions = Decisions::new(wal.clone(), Outcomes::new());
+        let mut points = Points(100, 0);
+
+        let order = Order::new(1, OrderAction::UsePoints(10));
+        let lock =
+            Transaction::new("127.0.0.1:9001".to_string(), &Message::LockOrder(order)).unwrap();
+        let res = points.coordinate(
+            lock,
+            servers.clone(),
+            true,
+            &Quorum::Majority,
+            pending.clone(),
+            &wal,
+            &Versions::new(),
+            &decisions,
+        );
+        assert!(matches!(res, Err(Response::Offline)));
+        assert_eq!((points.0, points.1), (100, 0));
+
+        // Other actions are left pending, to be coordinated once the servers are back
+        let order = Order::new(1, OrderAction::FillPoints(10));
+        let fill =
+            Transaction::new("127.0.0.1:9001".to_string(), &Message::CommitOrder(order)).unwrap();
+        let res = points.coordinate(
+            fill,
+            servers,
+            true,
+            &Quorum::Majority,
+            pending,
+            &wal,
+            &Versions::new(),
+            &decisions,
+        );
+        assert!(matches!(res, Ok(TxOk::Offline)));
+    }
     #[test]
     fn test_add_points() {
         let mut points = Points(0, 0);
@@ -320,6 +628,77 @@ mod tests {
         assert_eq!(0, points.1);
     }
 
+    #[test]
+    fn test_reservations() {
+        let mut record = PointRecord::new();
+        let order = Order::new(1, OrderAction::UsePoints(100));
+        let lock = Transaction::new(
+            "127.0.0.1:9001".to_string(),
+            &Message::LockOrder(order.clone()),
+        )
+        .unwrap();
+        record.settle(&lock);
+        let reservation = lock.reservation.unwrap();
+
+        let commit = |order: Order| {
+            Transaction::new("127.0.0.1:9001".to_string(), &Message::CommitOrder(order)).unwrap()
+        };
+        let unknown = commit(order.clone().with_reservation(reservation + 1));
+        let without = commit(order.clone());
+        let consume = commit(order.with_reservation(reservation));
+
+        assert_eq!(
+            record.take_reservation(&unknown),
+            Err(Response::UnknownReservation)
+        );
+        assert_eq!(
+            record.take_reservation(&without),
+            Err(Response::UnknownReservation)
+        );
+        assert_eq!(record.take_reservation(&consume), Ok(()));
+        assert_eq!(
+            record.take_reservation(&consume),
+            Err(Response::UnknownReservation)
+        );
+
+        record.restore_reservation(&consume);
+        assert_eq!(record.take_reservation(&consume), Ok(()));
+        record.settle(&consume);
+        assert!(record.reservations.is_empty());
+    }
+
+    #[test]
+    fn test_wait_die() {
+        let record = PointRecord::new();
+        let order = Order::new(1, OrderAction::UsePoints(100));
+        let older = Transaction::new(
+            "127.0.0.1:9001".to_string(),
+            &Message::LockOrder(order.clone()),
+        )
+        .unwrap();
+        let younger =
+            Transaction::new("127.0.0.1:9002".to_string(), &Message::LockOrder(order)).unwrap();
+
+        let turn = record.queue.wait_turn(&younger, Duration::ZERO).unwrap();
+        assert_eq!(record.wait_die(&older), Ok(()));
+        drop(turn);
+
+        let turn = record.queue.wait_turn(&older, Duration::ZERO).unwrap();
+        assert_eq!(record.wait_die(&younger), Err(Response::WaitDieConflict));
+        assert_eq!(record.wait_die(&older), Ok(()));
+        drop(turn);
+        assert_eq!(record.wait_die(&younger), Ok(()));
+
+        // The next transaction of the same coordinator waits for the one being finished
+        let next = Transaction::new(
+            "127.0.0.1:9001".to_string(),
+            &Message::LockOrder(Order::new(1, OrderAction::UsePoints(10))),
+        )
+        .unwrap();
+        let _turn = record.queue.wait_turn(&older, Duration::ZERO).unwrap();
+        assert_eq!(record.wait_die(&next), Ok(()));
+    }
+
     #[test]
     fn test_consume_points() {
         let mut points = Points(0, 100);
@@ -1,94 +1,217 @@
 use std::{
     collections::{HashMap, HashSet},
-    io::Write,
-    net::TcpStream,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard,
+    },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use super::{
+    config::{Config, Replication},
+    connection_reservations::HeldReservation,
+    decisions::Decisions,
+    earned::Earned,
+    in_doubt::InDoubt,
+    link::Exchange,
     message::{
-        connect_to, spread_connect_to, sync_with, ConnectRequest, ConnectResponse, SyncRequest,
-        SyncResponse, TIMEOUT,
+        connect_to, propose_to, query_balance_from, query_tx_status_from, respond_to,
+        send_decision_to, spread_connect_to, sync_with, AbortReason, ConnectRequest,
+        ConnectResponse, DecisionRequest, PrepareResponse, SyncMark, SyncRequest, SyncResponse,
+        TxStatus, TxStatusRequest, TxStatusResponse, TIMEOUT,
     },
+    outcomes::Outcomes,
+    parked_reservations::ParkedReservations,
     pending_transactions::PendingTransactions,
     point_record::{PointRecord, SafePointRecord},
-    transaction::{Transaction, TransactionState, TxOk},
+    queue_stats::QueueStats,
+    quorum::Quorum,
+    raft::Raft,
+    recent_orders::RecentOrders,
+    record_queue::Turn,
+    snapshot::Snapshot,
+    transaction::{
+        generate_timestamp, Transaction, TransactionAction, TransactionState, TxOk, COMMIT_TIMEOUT,
+        PREPARE_TIMEOUT,
+    },
+    versions::Versions,
+    wal::{Wal, WalEntry},
 };
-use points::Message;
-use tracing::{debug, error, info};
+use points::{Balance, BalanceRead, Message, Order, OrderAction, Response};
+use rayon::prelude::*;
+use tracing::{debug, error, info, warn};
+
+pub type PointMap = HashMap<u64, SafePointRecord>;
 
-pub type PointMap = HashMap<u16, SafePointRecord>;
+/// Times a transaction is retried before failing, when a participant killed it by wait-die.
+const BUSY_RETRIES: u32 = 5;
+/// Wait before the first retry of a transaction that died, doubled on each retry.
+const BUSY_BACKOFF: Duration = Duration::from_millis(20);
+const BUSY_MAX_BACKOFF: Duration = Duration::from_millis(500);
+/// Longest a pending transaction waits for its turn to use the record.
+const TURN_TIMEOUT: Duration = Duration::from_millis(2000);
+/// Longest the transaction of a client message waits for its turn and its retries,
+/// so the coffee maker, which waits for 1 second, gets an answer.
+const CLIENT_WAIT: Duration = Duration::from_millis(800);
 
+/// Points of the clients and the servers this one knows.
+/// Each part is synchronized on its own, so there is no lock over the whole storage,
+/// and none of its locks is held while talking to other servers.
 #[derive(Debug)]
 pub struct PointStorage {
-    pub points: PointMap,
-    pub servers: HashSet<String>,
+    points: RwLock<PointMap>,
+    servers: RwLock<HashSet<String>>,
     pub self_address: String,
-    pub online: bool,
+    online: AtomicBool,
     pub pending: Arc<PendingTransactions>,
+    pub recent_orders: Arc<RecentOrders>,
+    pub parked: Arc<ParkedReservations>,
+    /// Amount of reservations this server freed because their lease expired.
+    pub expired_leases: AtomicU64,
+    /// Amount of times a transaction was retried because it did not get its turn to use the record.
+    pub busy_retries: AtomicU64,
+    pub queue_stats: Arc<QueueStats>,
+    pub wal: Arc<Wal>,
+    pub versions: Arc<Versions>,
+    /// Mark of the last sync with each server, to only request the records changed since then.
+    sync_marks: Mutex<HashMap<String, SyncMark>>,
+    /// Held while syncing with the other servers, so two syncs are not applied over each other.
+    syncing: Mutex<()>,
+    pub decisions: Arc<Decisions>,
+    pub in_doubt: Arc<InDoubt>,
+    pub outcomes: Arc<Outcomes>,
+    /// Replicated log of the transactions, in the Raft replication mode.
+    pub raft: Option<Arc<Raft>>,
+    /// Servers that must approve a transaction coordinated by this server.
+    pub quorum: Quorum,
 }
 
 impl PointStorage {
     /// Creates a new point storage.
     /// The point storage is initialized with the given address as self address.
-    /// If a known server is given, the point storage will connect to it and sync the points with it.
+    /// If a known server is given, the point storage will connect to it and sync the points
+    /// with the freshest of the servers, otherwise it starts from the recovered points.
+    /// The pending transactions, the decisions to send and the transactions in doubt
+    /// are always the recovered ones.
+    /// In the Raft replication mode the points are not synced nor recovered,
+    /// they are applied again from the replicated log once its entries are committed.
     ///
     /// # Arguments
     ///
     /// * `self_address` - The address of the server.
     /// * `known_server` - An optional address of a known server.
+    /// * `wal` - The log where the changes to the points are written.
+    /// * `recovered` - The state recovered from the latest snapshot and the log.
+    /// * `config` - The settings of the server, such as how the servers agree on the transactions.
     ///
     /// # Returns
     ///
-    /// The point storage.
-    pub fn new(self_address: String, known_address: Option<String>) -> Arc<Mutex<Self>> {
-        let mut servers = HashSet::new();
-        let mut points = PointMap::new();
-
-        if let Some(addr) = known_address {
-            servers = connect_to(&self_address, &addr).unwrap();
-            points = sync_with(&addr).unwrap();
-        } else {
-            servers.insert(self_address.clone());
-        }
+    /// The point storage, or an error if the quorum asks for more servers than the cluster has.
+    pub fn new(
+        self_address: String,
+        known_address: Option<String>,
+        wal: Arc<Wal>,
+        recovered: Snapshot,
+        config: &Config,
+    ) -> Result<Arc<Self>, String> {
+        let servers = match &known_address {
+            Some(addr) => connect_to(&self_address, addr).unwrap(),
+            None => HashSet::from([self_address.clone()]),
+        };
+        config.quorum.check(servers.len())?;
+        let pending = PendingTransactions::new(wal.clone());
+        pending.restore(recovered.pending);
+        let versions = Versions::new();
+        versions.adopt(recovered.committed);
+        let outcomes = Outcomes::new();
+        let decisions = Decisions::new(wal.clone(), outcomes.clone());
+        decisions.restore(recovered.decisions);
+        let (points, raft) = match config.replication {
+            Replication::TwoPhaseCommit => (recovered.points, None),
+            Replication::Raft => {
+                let raft = Raft::new(self_address.clone(), wal.clone(), recovered.raft);
+                (PointMap::new(), Some(raft))
+            }
+        };
+        let in_doubt = InDoubt::new(wal.clone());
+        in_doubt.restore(Self::hold_in_doubt(&points, recovered.in_doubt));
 
-        let res = Arc::new(Mutex::new(PointStorage {
-            points,
-            servers,
+        let storage = Arc::new(PointStorage {
+            points: RwLock::new(points),
+            servers: RwLock::new(servers),
             self_address,
-            online: true,
-            pending: PendingTransactions::new(),
-        }));
+            online: AtomicBool::new(true),
+            pending,
+            recent_orders: RecentOrders::new(),
+            parked: ParkedReservations::new(),
+            expired_leases: AtomicU64::new(0),
+            busy_retries: AtomicU64::new(0),
+            queue_stats: QueueStats::new(),
+            wal,
+            versions,
+            sync_marks: Mutex::new(HashMap::new()),
+            syncing: Mutex::new(()),
+            decisions,
+            in_doubt,
+            outcomes,
+            raft,
+            quorum: config.quorum.clone(),
+        });
 
-        Self::set_on_connect(res.clone());
+        if known_address.is_some() && storage.raft.is_none() {
+            storage.sync_with_freshest().unwrap();
+        }
+
+        Self::set_on_connect(storage.clone());
 
-        res
+        Ok(storage)
+    }
+
+    fn points(&self) -> RwLockReadGuard<'_, PointMap> {
+        self.points.read().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn points_mut(&self) -> RwLockWriteGuard<'_, PointMap> {
+        self.points.write().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn sync_marks(&self) -> MutexGuard<'_, HashMap<String, SyncMark>> {
+        self.sync_marks.lock().unwrap_or_else(|e| e.into_inner())
     }
 
     /// Gets the point record for the given id.
-    pub fn get_point_record(&mut self, client_id: u16) -> Arc<Mutex<PointRecord>> {
-        self.points
+    /// The point map is only locked for writing the first time a client is seen.
+    pub fn get_point_record(&self, client_id: u64) -> Arc<Mutex<PointRecord>> {
+        if let Some(record) = self.points().get(&client_id) {
+            return record.0.clone();
+        }
+        self.points_mut()
             .entry(client_id)
             .or_insert_with(SafePointRecord::new)
             .0
             .clone()
     }
 
+    /// Gets the servers associated with the point storage, including its own address.
+    pub fn servers(&self) -> HashSet<String> {
+        self.servers
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
     /// Gets the list of servers associated with the point storage.
     /// It excludes its own address.
     pub fn get_other_servers(&self) -> HashSet<String> {
-        self.servers
-            .iter()
-            .filter(|addr| **addr != self.self_address)
-            .cloned()
-            .collect()
+        let mut servers = self.servers();
+        servers.remove(&self.self_address);
+        servers
     }
 
     /// Adds a new server to the point storage.
     /// It will also spread the new server to all other servers if the request is not a copy.
-    pub fn add_connection(&mut self, request: ConnectRequest) -> Result<String, String> {
+    pub fn add_connection(&self, request: ConnectRequest) -> Result<String, String> {
         self.check_online()?;
         debug!("Adding connection: {:?}", &request.addr);
 
@@ -96,11 +219,12 @@ impl PointStorage {
             self.spread_connection(request.addr.clone())?;
         }
 
-        self.servers.insert(request.addr);
-
+        let mut servers = self.servers.write().unwrap_or_else(|e| e.into_inner());
+        servers.insert(request.addr);
         let res = ConnectResponse {
-            servers: self.servers.clone(),
+            servers: servers.clone(),
         };
+        drop(servers);
 
         if request.copy {
             Ok(String::from(""))
@@ -110,17 +234,228 @@ impl PointStorage {
     }
 
     /// Creates and serializes a new sync response with the current points.
-    pub fn sync(&self, _req: SyncRequest) -> Result<String, String> {
+    /// The points earned offline by the requester are merged first, so the response includes them.
+    /// Given the mark of a previous sync, only the records that changed since then are sent,
+    /// unless the mark is of a previous run of this server or most of the records changed.
+    pub fn sync(&self, req: SyncRequest) -> Result<String, String> {
         self.check_online()?;
-        let res = SyncResponse {
-            points: self.points.clone(),
+        self.merge_earned(&req.earned)?;
+        // Taken before reading the records, so no change is left behind the mark
+        let mark = self.versions.mark();
+        let committed = self.versions.committed();
+
+        let changed = match req.since {
+            Some(since) if since.epoch == mark.epoch && since.version <= mark.version => {
+                Some(self.changed_since(since.version)?)
+            }
+            _ => None,
+        };
+        let all = self.points();
+        let res = match changed {
+            // Sending most of the records as changes is no cheaper than a full sync
+            Some(points) if points.len() * 2 <= all.len() => SyncResponse {
+                points,
+                full: false,
+                mark,
+                committed,
+            },
+            _ => SyncResponse {
+                points: all.clone(),
+                full: true,
+                mark,
+                committed,
+            },
         };
+        drop(all);
         serde_json::to_string(&res).map_err(|_| "Failed to serialize points".to_string())
     }
 
+    /// Merges the points earned offline by another server into the records.
+    fn merge_earned(&self, earned: &HashMap<u64, Earned>) -> Result<(), String> {
+        for (client_id, earned) in earned {
+            let record = self.get_point_record(*client_id);
+            let mut record = record.lock().map_err(|_| "Failed to lock record")?;
+            let gained = record.merge_earned(earned)?;
+            if gained > 0 {
+                debug!(
+                    "Merged {} points earned offline by client {}",
+                    gained, client_id
+                );
+                record.version = self.versions.next();
+                record.log(*client_id, &self.wal)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the points earned offline of every record that has any.
+    fn earned(points: &PointMap) -> Result<HashMap<u64, Earned>, String> {
+        let mut earned = HashMap::new();
+        for (client_id, record) in points {
+            let record = record.0.lock().map_err(|_| "Failed to lock record")?;
+            if !record.earned.is_empty() {
+                earned.insert(*client_id, record.earned.clone());
+            }
+        }
+        Ok(earned)
+    }
+
+    /// Returns the records changed after the given version.
+    fn changed_since(&self, version: u64) -> Result<PointMap, String> {
+        let mut changed = PointMap::new();
+        for (client_id, record) in self.points().iter() {
+            if record
+                .0
+                .lock()
+                .map_err(|_| "Failed to lock record")?
+                .version
+                > version
+            {
+                changed.insert(*client_id, record.clone());
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Syncs with every other server and adopts the state of the freshest one.
+    /// Every server merges the points earned offline by this one, and this one merges theirs.
+    /// No lock of the storage is held while waiting for the other servers.
+    fn sync_with_freshest(&self) -> Result<(), String> {
+        let _syncing = self.syncing.lock().unwrap_or_else(|e| e.into_inner());
+        let earned = Self::earned(&self.points())?;
+        let marks = self.sync_marks().clone();
+        let synced: Vec<(String, SyncResponse)> = self
+            .get_other_servers()
+            .par_iter()
+            .filter_map(|addr| {
+                let since = marks.get(addr).copied();
+                match sync_with(addr, since, earned.clone()) {
+                    Ok(res) => Some((addr.clone(), res)),
+                    Err(e) => {
+                        debug!("Failed to sync with {}: {}", addr, e);
+                        None
+                    }
+                }
+            })
+            .collect();
+        self.adopt_freshest(synced)
+    }
+
+    /// Adopts the state of the freshest of the synced servers, unless this server is fresher than all of them.
+    /// If less than a majority answered they may all be stale, so only their points earned offline are merged.
+    /// Fails if no server answered.
+    fn adopt_freshest(&self, mut synced: Vec<(String, SyncResponse)>) -> Result<(), String> {
+        let freshest = Self::freshest(&synced).ok_or("Failed to sync with any server")?;
+        let quorum = self.servers().len() / 2 + 1;
+        if synced.len() + 1 < quorum {
+            warn!(
+                "Synced with {} servers, less than a majority of {}. Keeping the state of this server",
+                synced.len(),
+                quorum
+            );
+            for (_, other) in &synced {
+                self.merge_earned(&Self::earned(&other.points)?)?;
+            }
+            return Ok(());
+        }
+
+        let (addr, res) = synced.swap_remove(freshest);
+        for (_, other) in &synced {
+            self.merge_earned(&Self::earned(&other.points)?)?;
+        }
+        if res.committed < self.versions.committed() {
+            warn!(
+                "State of {} is older than the one of this server, keeping it",
+                addr
+            );
+            self.merge_earned(&Self::earned(&res.points)?)?;
+        } else {
+            self.apply_sync(&addr, res)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the position of the sync response with the freshest state,
+    /// warning if the servers that answered disagree on it.
+    fn freshest(synced: &[(String, SyncResponse)]) -> Option<usize> {
+        let states: Vec<(&String, u64)> = synced
+            .iter()
+            .map(|(addr, res)| (addr, res.committed))
+            .collect();
+        if states
+            .iter()
+            .any(|(_, committed)| *committed != states[0].1)
+        {
+            warn!("Servers disagree on the state: {:?}", states);
+        }
+        states
+            .iter()
+            .enumerate()
+            .max_by_key(|(_, (_, committed))| *committed)
+            .map(|(i, _)| i)
+    }
+
+    /// Replaces the points with the ones synced from the given server:
+    /// all of them after a full sync, only the changed records otherwise.
+    /// The points earned offline known by this server are merged into the synced records,
+    /// so they are not lost whatever server answered the sync.
+    /// The synced records get new versions, so they reach the servers that sync with this one.
+    /// Fails without replacing the points if the synced ones could not be written to the log.
+    fn apply_sync(&self, addr: &str, mut res: SyncResponse) -> Result<(), String> {
+        for (client_id, record) in self.points().iter() {
+            let record = match record.0.lock() {
+                Ok(record) => record,
+                Err(_) => {
+                    error!("Failed to lock record to merge");
+                    continue;
+                }
+            };
+            if record.earned.is_empty() || !(res.full || res.points.contains_key(client_id)) {
+                continue;
+            }
+            let synced = res
+                .points
+                .entry(*client_id)
+                .or_insert_with(SafePointRecord::new);
+            let merged = synced
+                .0
+                .lock()
+                .map_err(|_| "Failed to lock synced record".to_string());
+            if let Err(e) = merged.and_then(|mut synced| synced.merge_earned(&record.earned)) {
+                error!("{}", e);
+            }
+        }
+        for record in res.points.values() {
+            match record.0.lock() {
+                Ok(mut record) => record.version = self.versions.next(),
+                Err(_) => error!("Failed to lock synced record"),
+            }
+        }
+        debug!(
+            "Synced {} records from {} ({})",
+            res.points.len(),
+            addr,
+            if res.full { "full" } else { "changes" }
+        );
+        // Logged while the map is locked, so the log keeps the order of the changes
+        let mut points = self.points_mut();
+        if res.full {
+            self.wal.append(&WalEntry::Reset(res.points.clone()))?;
+            *points = res.points;
+        } else {
+            self.wal.append(&WalEntry::Merge(res.points.clone()))?;
+            points.extend(res.points);
+        }
+        self.wal.append_or_stop(&WalEntry::Adopt(res.committed));
+        drop(points);
+        self.sync_marks().insert(addr.to_string(), res.mark);
+        self.versions.adopt(res.committed);
+        Ok(())
+    }
+
     /// Spreads the given server address to all other servers.
-    pub fn spread_connection(&mut self, addr: String) -> Result<(), String> {
-        for server in &self.servers {
+    pub fn spread_connection(&self, addr: String) -> Result<(), String> {
+        for server in &self.servers() {
             if server == &addr || server == &self.self_address {
                 continue;
             }
@@ -175,53 +510,259 @@ impl PointStorage {
     */
     /// Handles a transaction for the given storage.
     pub fn handle_transaction(
-        storage: Arc<Mutex<PointStorage>>,
+        storage: Arc<PointStorage>,
         transaction: Transaction,
-        mut coordinator: TcpStream,
+        coordinator: &mut Exchange,
     ) -> Result<(), String> {
-        let mut storage = storage.lock().map_err(|_| "Failed to lock storage")?;
         storage.check_online()?;
 
-        let record = storage.get_point_record(transaction.client_id);
-        drop(storage);
-        let record = record.lock().map_err(|_| "Failed to lock record")?;
+        let wal = storage.wal.clone();
+        let versions = storage.versions.clone();
+        let in_doubt = storage.in_doubt.clone();
+        let outcomes = storage.outcomes.clone();
+        let queue_stats = storage.queue_stats.clone();
+        let record_ref = storage.get_point_record(transaction.client_id);
 
-        let wait_die = record.wait_die(&transaction);
+        // Only an older transaction waits for its turn, so two coordinators never wait for each other.
+        // It waits less than the coordinator waits for the answer.
+        let wait_die = record_ref
+            .lock()
+            .map_err(|_| "Failed to lock record")?
+            .wait_die(&transaction);
+        let died = wait_die.is_err();
+        let turn = match wait_die {
+            Ok(()) => {
+                Self::wait_turn(&record_ref, &transaction, PREPARE_TIMEOUT / 2, &queue_stats).ok()
+            }
+            Err(_) => None,
+        };
 
+        let mut record = record_ref.lock().map_err(|_| "Failed to lock record")?;
         let points = record.points.clone();
         let mut points = points.lock().map_err(|_| "Failed to lock points")?;
+
+        let approve = turn.is_some()
+            && points.can_perform(&transaction).is_ok()
+            && record.take_reservation(&transaction).is_ok();
         drop(record);
 
-        let state = if wait_die.is_ok() && points.can_perform(&transaction).is_ok() {
+        let reason = if approve {
             debug!("Sending APPROVE for {:?}.", transaction);
-            TransactionState::Proceed as u8
+            None
+        } else if died {
+            debug!("Sending ABORT for {:?}, it dies by wait-die.", transaction);
+            Some(AbortReason::WaitDie)
         } else {
             debug!("Sending ABORT for {:?}.", transaction);
-            TransactionState::Abort as u8
+            Some(AbortReason::Refused)
+        };
+        let vote = serde_json::to_string(&PrepareResponse {
+            proceed: approve,
+            reason,
+        })
+        .map_err(|e| e.to_string())?;
+        if let Err(e) = respond_to(coordinator, vote) {
+            if approve {
+                drop(points);
+                Self::restore_reservation(&record_ref, &transaction);
+            }
+            return Err(e.to_string());
+        }
+
+        let result = points.handle_transaction(transaction.clone(), coordinator, &wal, &versions);
+        drop(points);
+
+        let mut record = record_ref.lock().map_err(|_| "Failed to lock record")?;
+        match result {
+            Ok(TransactionState::Proceed) => {
+                record.settle(&transaction);
+                record.version = versions.next();
+                outcomes.record(&transaction, true);
+                Ok(())
+            }
+            Ok(_) => {
+                if approve {
+                    record.restore_reservation(&transaction);
+                }
+                outcomes.record(&transaction, false);
+                Err("Aborted Transaction".to_string())
+            }
+            Err(e) => {
+                if approve {
+                    // The coordinator may have decided to commit, it sends the decision again
+                    // or this server asks for it. Until then the reservation and the turn stay taken
+                    in_doubt.add(transaction, turn);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Takes again the turn and the reservation of the transactions that were in doubt
+    /// when the server stopped.
+    fn hold_in_doubt(
+        points: &PointMap,
+        in_doubt: Vec<Transaction>,
+    ) -> Vec<(Transaction, Option<Turn>)> {
+        in_doubt
+            .into_iter()
+            .map(|transaction| {
+                let turn = points.get(&transaction.client_id).and_then(|record| {
+                    let mut record = record.0.lock().unwrap_or_else(|e| e.into_inner());
+                    if record.take_reservation(&transaction).is_err() {
+                        warn!("Could not take the reservation of {:?}", transaction);
+                    }
+                    record.queue.wait_turn(&transaction, Duration::ZERO).ok()
+                });
+                (transaction, turn)
+            })
+            .collect()
+    }
+
+    /// Applies the decision of a transaction in doubt, sent again by its coordinator
+    /// or answered by a server asked for it.
+    /// A committed transaction is always applied, as the other participants applied it too.
+    /// Decisions of transactions that are not in doubt were already received, so they are ignored.
+    pub fn apply_decision(
+        storage: Arc<PointStorage>,
+        decision: DecisionRequest,
+    ) -> Result<(), String> {
+        let transaction = decision.transaction;
+        let in_doubt = storage.in_doubt.clone();
+        if !in_doubt.take(&transaction) {
+            debug!("Already received the decision of {:?}", transaction);
+            return Ok(());
+        }
+        storage.outcomes.record(&transaction, decision.commit);
+        let record_ref = storage.get_point_record(transaction.client_id);
+        if !decision.commit {
+            info!("Coordinator aborted {:?}", transaction);
+            Self::restore_reservation(&record_ref, &transaction);
+            in_doubt.resolved(&transaction);
+            return Ok(());
+        }
+
+        let wal = storage.wal.clone();
+        let versions = storage.versions.clone();
+        let mut record = record_ref.lock().map_err(|_| "Failed to lock record")?;
+
+        // The turn was held since the vote, so only a sync may have changed the points
+        let points = record.points.clone();
+        let mut points = points.lock().map_err(|_| "Failed to lock points")?;
+        if let Err(e) = points.can_perform(&transaction) {
+            warn!("Applying the committed {:?} anyway: {:?}", transaction, e);
+        }
+        if let Err(e) = points.apply_logged(transaction.clone(), &wal, &versions) {
+            Wal::stop(&e);
+        }
+        drop(points);
+
+        record.settle(&transaction);
+        record.version = versions.next();
+        in_doubt.resolved(&transaction);
+        Ok(())
+    }
+
+    /// Answers the outcome of a transaction, if this server decided it or was told about it.
+    /// A transaction without a known outcome is not presumed aborted,
+    /// its coordinator may have committed it.
+    pub fn tx_status(&self, req: TxStatusRequest) -> Result<String, String> {
+        let status = match self.outcomes.get(&req.transaction) {
+            Some(true) => TxStatus::Committed,
+            Some(false) => TxStatus::Aborted,
+            None => TxStatus::Unknown,
         };
-        coordinator.write_all(&[state]).map_err(|e| e.to_string())?;
+        serde_json::to_string(&TxStatusResponse { status }).map_err(|e| e.to_string())
+    }
+
+    /// Asks for the outcome of the transactions in doubt and applies the known ones.
+    /// The coordinator of each transaction is asked first, then the other servers.
+    pub fn resolve_in_doubt(storage: Arc<PointStorage>) -> Result<(), String> {
+        if !storage.is_online() {
+            return Ok(());
+        }
+        let in_doubt = storage.in_doubt.clone();
+        let servers = storage.get_other_servers();
+
+        for transaction in in_doubt.snapshot() {
+            let mut asked = vec![&transaction.coordinator];
+            asked.extend(
+                servers
+                    .iter()
+                    .filter(|server| **server != transaction.coordinator),
+            );
+            let status = asked
+                .into_iter()
+                .map(|server| match query_tx_status_from(server, &transaction) {
+                    Ok(status) => status,
+                    Err(e) => {
+                        debug!("Could not ask {} for the outcome: {}", server, e);
+                        TxStatus::Unknown
+                    }
+                })
+                .find(|status| *status != TxStatus::Unknown);
+            let commit = match status {
+                Some(status) => status == TxStatus::Committed,
+                None => continue,
+            };
+            let decision = DecisionRequest {
+                transaction,
+                commit,
+            };
+            if let Err(e) = Self::apply_decision(storage.clone(), decision) {
+                error!("Failed to resolve transaction in doubt: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends the decisions of the coordinated transactions to the participants that may not know them.
+    pub fn send_decisions(storage: Arc<PointStorage>) -> Result<(), String> {
+        if !storage.is_online() {
+            return Ok(());
+        }
+        let decisions = storage.decisions.clone();
 
-        points.handle_transaction(transaction, coordinator)
+        for decision in decisions.snapshot() {
+            for participant in &decision.participants {
+                match send_decision_to(participant, &decision) {
+                    Ok(()) => decisions.delivered_to(&decision.transaction, participant),
+                    Err(e) => debug!("Could not send decision to {}: {}", participant, e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn restore_reservation(record: &Arc<Mutex<PointRecord>>, transaction: &Transaction) {
+        match record.lock() {
+            Ok(mut record) => record.restore_reservation(transaction),
+            Err(_) => error!("Failed to lock record to restore {:?}", transaction),
+        }
     }
 
     /// Makes the storage go offline.
     /// It wont send or receive any transactions.
-    pub fn disconnect(&mut self) {
+    pub fn disconnect(&self) {
         info!("[ DISCONNECTING ]");
-        self.online = false;
+        self.online.store(false, Ordering::SeqCst);
     }
 
     /// Makes the storage go online.
     /// It will send and receive transactions.
-    pub fn connect(&mut self) {
+    pub fn connect(&self) {
         info!("[ CONNECTING ]");
-        self.online = true;
+        self.online.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::SeqCst)
     }
 
     /// Checks if the storage is online.
     /// If it is not, it will return an error after a timeout.
     pub fn check_online(&self) -> Result<(), String> {
-        if self.online {
+        if self.is_online() {
             Ok(())
         } else {
             thread::sleep(Duration::from_millis(TIMEOUT + TIMEOUT / 10));
@@ -229,92 +770,1127 @@ impl PointStorage {
         }
     }
 
-    pub fn coordinate_msg(msg: Message, storage: Arc<Mutex<PointStorage>>) -> Result<TxOk, String> {
-        let mut storage = storage.lock().map_err(|_| "Failed to lock storage")?;
-        let transaction = Transaction::new(storage.self_address.clone(), &msg)?;
+    /// Coordinates the transaction for a message received from a client.
+    /// Returns the response for the client: the reservation of the points for a lock, `Ok` otherwise.
+    /// The error describes why the message could not be applied.
+    /// If the transaction dies by wait-die, it is retried with the same timestamp after a growing wait,
+    /// so it eventually becomes the oldest one for the record.
+    /// It fails with `Timeout`, without retrying, if it does not get its turn before the client stops waiting.
+    pub fn coordinate_msg(msg: Message, storage: Arc<PointStorage>) -> Result<Response, Response> {
+        let transaction = Transaction::new(storage.self_address.clone(), &msg)
+            .map_err(|_| Response::Malformed)?;
+        if let Some(raft) = &storage.raft {
+            Self::replicate(raft, &transaction)?;
+            return Ok(Self::response_for(&transaction));
+        }
+
+        let deadline = Instant::now() + CLIENT_WAIT;
+        let mut backoff = BUSY_BACKOFF;
+        let mut retries = 0;
+        let mut transaction = transaction;
+        loop {
+            let turn_timeout = deadline.saturating_duration_since(Instant::now());
+            match Self::coordinate_attempt(&transaction, storage.clone(), turn_timeout) {
+                Err(Response::WaitDieConflict)
+                    if retries < BUSY_RETRIES && Instant::now() + backoff < deadline =>
+                {
+                    retries += 1;
+                    let busy_retries = storage.busy_retries.fetch_add(1, Ordering::Relaxed) + 1;
+                    debug!(
+                        "Retrying transaction with timestamp {} after it died by wait-die ({}/{}, {} retries so far)",
+                        transaction.timestamp, retries, BUSY_RETRIES, busy_retries
+                    );
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(BUSY_MAX_BACKOFF);
+                    transaction = transaction.retry();
+                }
+                Err(Response::WaitDieConflict) => {
+                    warn!(
+                        "Transaction with timestamp {} died by wait-die after {} retries",
+                        transaction.timestamp, retries
+                    );
+                    return Err(Response::WaitDieConflict);
+                }
+                result => {
+                    if retries > 0 {
+                        info!(
+                            "Transaction with timestamp {} coordinated after {} retries",
+                            transaction.timestamp, retries
+                        );
+                    }
+                    return result;
+                }
+            }
+        }
+    }
+
+    /// Waits for the turn of the transaction to use the record, recording the wait for monitoring.
+    fn wait_turn(
+        record_ref: &Arc<Mutex<PointRecord>>,
+        transaction: &Transaction,
+        timeout: Duration,
+        stats: &QueueStats,
+    ) -> Result<Turn, Response> {
+        let queue = record_ref
+            .lock()
+            .map_err(|_| Response::Aborted)?
+            .queue
+            .clone();
+        match queue.wait_turn(transaction, timeout) {
+            Ok(turn) => {
+                stats.turn(turn.waited, turn.ahead);
+                if turn.ahead > 0 {
+                    debug!(
+                        "Transaction with timestamp {} waited {:?} behind {} transactions",
+                        transaction.timestamp, turn.waited, turn.ahead
+                    );
+                }
+                Ok(turn)
+            }
+            Err(e) => {
+                stats.timeout();
+                debug!(
+                    "Transaction with timestamp {} gave up waiting for its turn",
+                    transaction.timestamp
+                );
+                Err(e)
+            }
+        }
+    }
+
+    /// Coordinates the transaction once, failing if it does not get its turn to use the record in time.
+    fn coordinate_attempt(
+        transaction: &Transaction,
+        storage: Arc<PointStorage>,
+        turn_timeout: Duration,
+    ) -> Result<Response, Response> {
+        let transaction = transaction.clone();
 
         let servers = storage.get_other_servers();
-        let online = storage.online;
+        let online = storage.is_online();
         let pending = storage.pending.clone();
+        let wal = storage.wal.clone();
+        let versions = storage.versions.clone();
+        let decisions = storage.decisions.clone();
+        let self_address = storage.self_address.clone();
+        let quorum = storage.quorum.clone();
+        let queue_stats = storage.queue_stats.clone();
 
         let record_ref = storage.get_point_record(transaction.client_id);
-        drop(storage);
-        let record = record_ref.lock().map_err(|_| "Failed to lock record")?;
+        let _turn = Self::wait_turn(&record_ref, &transaction, turn_timeout, &queue_stats)?;
+        let mut record = record_ref.lock().map_err(|_| Response::Aborted)?;
 
-        record.wait_die(&transaction)?;
+        record.take_reservation(&transaction)?;
 
         let points = record.points.clone();
-        let mut points = points.lock().map_err(|_| "Failed to lock points")?;
         drop(record);
+        let result = match points.lock() {
+            Ok(mut points) => points.coordinate(
+                transaction.clone(),
+                servers,
+                online,
+                &quorum,
+                pending,
+                &wal,
+                &versions,
+                &decisions,
+            ),
+            Err(_) => Err(Response::Aborted),
+        };
 
-        let result = points.coordinate(transaction, servers, online, pending);
-        drop(points);
+        let mut record = record_ref.lock().map_err(|_| Response::Aborted)?;
+        match result {
+            Ok(TxOk::Finalized) => {
+                record.settle(&transaction);
+                record.version = versions.next();
+            }
+            Ok(TxOk::Offline) => {
+                record
+                    .earn_logged(&self_address, &transaction, &wal)
+                    .map_err(|_| Response::Aborted)?;
+                record.version = versions.next();
+            }
+            // The reservation stays taken until the pending transaction is applied
+            Ok(TxOk::Pending) => {}
+            Err(_) => record.restore_reservation(&transaction),
+        }
 
-        let mut record = record_ref.lock().map_err(|_| "Failed to lock record")?;
-        record.transaction = None;
+        result?;
+        Ok(Self::response_for(&transaction))
+    }
 
-        result
+    /// Response for the client once its transaction is applied:
+    /// the reservation of the points for a lock, `Ok` otherwise.
+    fn response_for(transaction: &Transaction) -> Response {
+        match (&transaction.action, transaction.reservation) {
+            (TransactionAction::Lock, Some(reservation)) => Response::Reserved(reservation),
+            _ => Response::Ok,
+        }
+    }
+
+    /// Appends the transaction to the replicated log, through the leader if this server is not,
+    /// and waits for this server to apply it once it is committed.
+    fn replicate(raft: &Raft, transaction: &Transaction) -> Result<TxOk, Response> {
+        match raft.propose(transaction.clone()) {
+            Ok(()) => {}
+            Err(Some(leader)) => {
+                if !propose_to(&leader, transaction).map_err(|_| Response::Offline)? {
+                    return Err(Response::Offline);
+                }
+            }
+            // No leader was elected yet, or this server can not reach it
+            Err(None) => return Err(Response::Offline),
+        }
+        match raft.wait_applied(transaction, COMMIT_TIMEOUT) {
+            Some(result) => result.map(|_| TxOk::Finalized),
+            None => Err(Response::Timeout),
+        }
+    }
+
+    /// Applies the committed entries of the replicated log to the points.
+    /// Every server applies the same transactions in the same order, so they reach the same outcome.
+    pub fn apply_replicated(storage: Arc<PointStorage>) -> Result<(), String> {
+        let raft = match storage.raft.clone() {
+            Some(raft) => raft,
+            None => return Ok(()),
+        };
+        let versions = storage.versions.clone();
+
+        raft.apply_committed(|transaction| {
+            let record_ref = storage.get_point_record(transaction.client_id);
+            let mut record = record_ref.lock().map_err(|_| Response::Aborted)?;
+            let points = record.points.clone();
+            let mut points = points.lock().map_err(|_| Response::Aborted)?;
+            points.can_perform(transaction)?;
+            record.take_reservation(transaction)?;
+            points.apply(transaction.clone());
+            drop(points);
+
+            record.settle(transaction);
+            record.version = versions.next();
+            versions.commit();
+            Ok(())
+        });
+        Ok(())
+    }
+
+    /// Runs a round of the replicated log with the other servers and applies the committed entries.
+    pub fn replication_round(storage: Arc<PointStorage>) -> Result<(), String> {
+        let raft = match storage.raft.clone() {
+            Some(raft) if storage.is_online() => raft,
+            _ => return Ok(()),
+        };
+        let mut peers: Vec<String> = storage.get_other_servers().into_iter().collect();
+        peers.sort();
+
+        raft.tick(&peers);
+        Self::apply_replicated(storage)
     }
 
+    /// Coordinates an already created transaction, such as a pending one.
+    /// A pending free or consume keeps its reservation taken while it is queued again,
+    /// and gives it back if it fails for good.
     pub fn coordinate_tx(
         transaction: Transaction,
-        storage: Arc<Mutex<PointStorage>>,
-    ) -> Result<TxOk, String> {
-        let mut storage = storage.lock().map_err(|_| "Failed to lock storage")?;
+        storage: Arc<PointStorage>,
+    ) -> Result<TxOk, Response> {
+        if let Some(raft) = &storage.raft {
+            return Self::replicate(raft, &transaction);
+        }
 
         let servers = storage.get_other_servers();
-        let online = storage.online;
+        let online = storage.is_online();
         let pending = storage.pending.clone();
+        let wal = storage.wal.clone();
+        let versions = storage.versions.clone();
+        let decisions = storage.decisions.clone();
+        let self_address = storage.self_address.clone();
+        let quorum = storage.quorum.clone();
+        let queue_stats = storage.queue_stats.clone();
 
         let record_ref = storage.get_point_record(transaction.client_id);
-        drop(storage);
-        let record = record_ref.lock().map_err(|_| "Failed to lock record")?;
-
-        record.wait_die(&transaction)?;
+        let _turn = Self::wait_turn(&record_ref, &transaction, TURN_TIMEOUT, &queue_stats)?;
+        let record = record_ref.lock().map_err(|_| Response::Aborted)?;
 
         let points = record.points.clone();
-        let mut points = points.lock().map_err(|_| "Failed to lock points")?;
+        let mut points = points.lock().map_err(|_| Response::Aborted)?;
         drop(record);
 
-        let result = points.coordinate(transaction, servers, online, pending);
+        let result = points.coordinate(
+            transaction.clone(),
+            servers,
+            online,
+            &quorum,
+            pending,
+            &wal,
+            &versions,
+            &decisions,
+        );
         drop(points);
 
-        let mut record = record_ref.lock().map_err(|_| "Failed to lock record")?;
-        record.transaction = None;
+        let mut record = record_ref.lock().map_err(|_| Response::Aborted)?;
+        match result {
+            Ok(TxOk::Finalized) => {
+                record.settle(&transaction);
+                record.version = versions.next();
+            }
+            Ok(TxOk::Offline) => {
+                record
+                    .earn_logged(&self_address, &transaction, &wal)
+                    .map_err(|_| Response::Aborted)?;
+                record.version = versions.next();
+            }
+            // The reservation stays taken until the pending transaction is applied
+            Ok(TxOk::Pending) | Err(Response::Timeout) => {}
+            Err(_) => record.restore_reservation(&transaction),
+        }
 
         result
     }
 
-    pub fn set_on_connect(storage: Arc<Mutex<Self>>) {
-        let lock = storage.clone();
-        let lock = lock.lock().unwrap();
+    /// Frees the reservations whose lease expired, coordinating a free transaction for each one.
+    /// Returns the amount of reservations that were freed.
+    pub fn release_expired_leases(
+        storage: Arc<PointStorage>,
+        lease: Duration,
+    ) -> Result<u64, String> {
+        let self_address = storage.self_address.clone();
+        let records: Vec<(u64, Arc<Mutex<PointRecord>>)> = storage
+            .points()
+            .iter()
+            .map(|(client_id, record)| (*client_id, record.0.clone()))
+            .collect();
+
+        let now = generate_timestamp();
+        let mut expired = vec![];
+        for (client_id, record) in records {
+            let record = record.lock().map_err(|_| "Failed to lock record")?;
+            for (id, reservation) in &record.reservations {
+                if reservation.should_release(&self_address, now, lease) {
+                    expired.push((client_id, *id, reservation.clone()));
+                }
+            }
+        }
+
+        let mut released = 0;
+        for (client_id, id, reservation) in expired {
+            match Self::free_reservation(storage.clone(), client_id, id, reservation.points) {
+                Ok(_) => {
+                    released += 1;
+                    let expired_leases = storage.expired_leases.fetch_add(1, Ordering::Relaxed) + 1;
+                    warn!(
+                        "Lease of reservation {} expired: freed {} points of client {} locked by {} ({} expired so far)",
+                        id, reservation.points, client_id, reservation.coordinator, expired_leases
+                    );
+                }
+                Err(e) => debug!("Could not free expired reservation {}: {}", id, e),
+            }
+        }
+        Ok(released)
+    }
+
+    /// Frees the reservations held by a client connection that dropped.
+    pub fn release_held(storage: Arc<PointStorage>, held: Vec<HeldReservation>) {
+        for held in held {
+            match Self::free_reservation(
+                storage.clone(),
+                held.client_id,
+                held.reservation,
+                held.points,
+            ) {
+                Ok(_) => info!(
+                    "Freed reservation {} of a dropped connection: {} points of client {}",
+                    held.reservation, held.points, held.client_id
+                ),
+                // It may have been settled over another connection, or its lease expired
+                Err(e) => debug!("Could not free reservation {}: {}", held.reservation, e),
+            }
+        }
+    }
+
+    /// Frees the locked points of a reservation through a distributed free transaction.
+    fn free_reservation(
+        storage: Arc<PointStorage>,
+        client_id: u64,
+        reservation: u64,
+        points: u64,
+    ) -> Result<Response, Response> {
+        let order =
+            Order::new(client_id, OrderAction::UsePoints(points)).with_reservation(reservation);
+        Self::coordinate_msg(Message::FreeOrder(order), storage)
+    }
+
+    /// Reads the balance of the given client from the local point map.
+    /// Clients without a record have no points.
+    pub fn read_balance(storage: Arc<PointStorage>, client_id: u64) -> Result<Balance, String> {
+        let record = match storage.points().get(&client_id) {
+            Some(record) => record.0.clone(),
+            None => return Ok(Balance::default()),
+        };
+
+        let record = record.lock().map_err(|_| "Failed to lock record")?;
+        let points = record.points.clone();
+        drop(record);
+
+        let points = points.lock().map_err(|_| "Failed to lock points")?;
+        Ok(Balance::new(points.0, points.1))
+    }
+
+    /// Answers a balance query from a client.
+    /// A local read returns the balance stored by this server.
+    /// A quorum read also asks the other servers and returns the balance reported by a majority of them
+    /// (this one included). It fails with `Offline` if less than a majority answered,
+    /// and with `Aborted` if no balance was reported by a majority.
+    pub fn query_balance(
+        storage: Arc<PointStorage>,
+        client_id: u64,
+        read: BalanceRead,
+    ) -> Result<Balance, Response> {
+        let local = Self::read_balance(storage.clone(), client_id).map_err(|e| {
+            error!("{}", e);
+            Response::Aborted
+        })?;
+        if read == BalanceRead::Local {
+            return Ok(local);
+        }
+
+        let servers = storage.get_other_servers();
+        let online = storage.is_online();
+
+        if !online && !servers.is_empty() {
+            return Err(Response::Offline);
+        }
+
+        let mut balances: Vec<Balance> = servers
+            .par_iter()
+            .filter_map(|server| query_balance_from(server, client_id).ok())
+            .map(|points| Balance::new(points.0, points.1))
+            .collect();
+        balances.push(local);
+
+        let cluster_size = servers.len() + 1;
+        let quorum = cluster_size / 2 + 1;
+        if balances.len() < quorum {
+            debug!(
+                "Balance quorum not reached for client {}: {} of {} answers",
+                client_id,
+                balances.len(),
+                quorum
+            );
+            return Err(Response::Offline);
+        }
+
+        let mut votes: Vec<(Balance, usize)> = vec![];
+        for balance in balances {
+            match votes.iter_mut().find(|(b, _)| *b == balance) {
+                Some((_, count)) => *count += 1,
+                None => votes.push((balance, 1)),
+            }
+        }
+        if votes.len() > 1 {
+            warn!(
+                "Servers disagree on balance of client {}: {:?}",
+                client_id, votes
+            );
+        }
+
+        Self::majority_balance(votes, cluster_size).ok_or(Response::Aborted)
+    }
+
+    /// Returns the balance reported by more than half of the servers of the cluster, if any.
+    fn majority_balance(votes: Vec<(Balance, usize)>, cluster_size: usize) -> Option<Balance> {
+        votes
+            .into_iter()
+            .find(|(_, count)| count * 2 > cluster_size)
+            .map(|(balance, _)| balance)
+    }
+
+    /// Captures the accounts and the pending transactions for a checkpoint of the log.
+    /// The records are shared with the storage and read while the snapshot is written,
+    /// so it may include changes logged after the checkpoint started, which the log replays again.
+    pub fn snapshot(storage: &Arc<Self>) -> Result<Snapshot, String> {
+        Ok(Snapshot::new(
+            storage.points().clone(),
+            storage.pending.snapshot(),
+            storage.decisions.snapshot(),
+            storage.in_doubt.snapshot(),
+            storage.versions.committed(),
+            storage
+                .raft
+                .as_ref()
+                .map(|raft| raft.snapshot())
+                .unwrap_or_default(),
+        ))
+    }
 
-        let pending = lock.pending.clone();
+    pub fn set_on_connect(storage: Arc<Self>) {
+        let pending = storage.pending.clone();
         pending.set_on_connect(Box::new(move || {
             let storage = storage.clone();
             Self::on_connect(storage)
         }))
     }
-    pub fn on_connect(storage: Arc<Mutex<Self>>) {
-        let mut storage = storage.lock().unwrap();
-        let servers = storage.get_other_servers();
-
-        // At least half the servers must be online
-        for addr in servers {
-            let points = sync_with(&addr);
-            if let Ok(points) = points {
-                storage.points = points;
-                return;
-            }
+    /// Syncs with the freshest of the other servers after a reconnection.
+    /// In the Raft replication mode the leader sends the missing entries instead.
+    pub fn on_connect(storage: Arc<Self>) {
+        if storage.raft.is_some() {
+            return;
+        }
+        if let Err(e) = storage.sync_with_freshest() {
+            error!("{} on connect. This should not happen!", e);
         }
-        error!("Failed to sync with any server on connect. This should not happen!");
     }
 }
 
-/*#[cfg(test)]
+#[cfg(test)]
 mod test {
+    use std::{
+        io::{Read, Write},
+        sync::mpsc,
+    };
+
     use super::*;
+    use crate::server::{
+        link::{Link, LINKS},
+        message::{receive_from, receive_response, receive_timestamp},
+        point_record::Points,
+    };
+
+    /// Creates a storage with the given amount of clients, all of them changed once.
+    fn storage_with_clients(clients: u64) -> Arc<PointStorage> {
+        let storage = PointStorage::new(
+            "127.0.0.1:9000".to_string(),
+            None,
+            Wal::disabled(),
+            Snapshot::default(),
+            &Config::default(),
+        )
+        .unwrap();
+        for client_id in 0..clients {
+            change(&storage, client_id);
+        }
+        storage
+    }
+
+    fn change(storage: &PointStorage, client_id: u64) {
+        let version = storage.versions.next();
+        storage.get_point_record(client_id).lock().unwrap().version = version;
+    }
+
+    fn sync(storage: &PointStorage, since: Option<SyncMark>) -> SyncResponse {
+        let res = storage
+            .sync(SyncRequest {
+                since,
+                ..Default::default()
+            })
+            .unwrap();
+        serde_json::from_str(&res).unwrap()
+    }
+
+    #[test]
+    fn test_sync_only_the_changed_records() {
+        let storage = storage_with_clients(4);
+        let mark = sync(&storage, None).mark;
+
+        change(&storage, 1);
+        let res = sync(&storage, Some(mark));
+        assert!(!res.full);
+        assert_eq!(res.points.keys().collect::<Vec<_>>(), vec![&1]);
+        assert_eq!(res.mark.version, mark.version + 1);
+
+        let res = sync(&storage, Some(res.mark));
+        assert!(!res.full);
+        assert!(res.points.is_empty());
+    }
+
+    #[test]
+    fn test_full_sync_when_the_gap_is_too_large() {
+        let storage = storage_with_clients(4);
+        let mark = sync(&storage, None).mark;
+
+        // A mark of a previous run of the server
+        let old_run = SyncMark {
+            epoch: mark.epoch - 1,
+            version: mark.version,
+        };
+        let res = sync(&storage, Some(old_run));
+        assert!(res.full);
+        assert_eq!(res.points.len(), 4);
+
+        for client_id in 0..3 {
+            change(&storage, client_id);
+        }
+        let res = sync(&storage, Some(mark));
+        assert!(res.full);
+        assert_eq!(res.points.len(), 4);
+    }
+
+    #[test]
+    fn test_apply_synced_changes() {
+        let storage = storage_with_clients(4);
+        let mark = storage.versions.mark();
+
+        let mut changed = PointMap::new();
+        let record = SafePointRecord::new();
+        *record.0.lock().unwrap().points.lock().unwrap() = Points(10, 0);
+        changed.insert(1, record);
+        changed.insert(5, SafePointRecord::new());
+        let peer_mark = SyncMark {
+            epoch: 1,
+            version: 7,
+        };
+        storage
+            .apply_sync(
+                "127.0.0.1:9001",
+                SyncResponse {
+                    points: changed,
+                    full: false,
+                    mark: peer_mark,
+                    committed: 0,
+                },
+            )
+            .unwrap();
+
+        assert_eq!(storage.points().len(), 5);
+        assert_eq!(storage.sync_marks()["127.0.0.1:9001"], peer_mark);
+        // The synced records are sent to the servers that sync with this one
+        let res = sync(&storage, Some(mark));
+        let mut synced: Vec<_> = res.points.keys().collect();
+        synced.sort();
+        assert_eq!(synced, vec![&1, &5]);
+    }
+
+    fn available(storage: &PointStorage, client_id: u64) -> u64 {
+        let record = storage.get_point_record(client_id);
+        let record = record.lock().unwrap();
+        let points = record.points.lock().unwrap();
+        points.0
+    }
+
+    #[test]
+    fn test_merge_points_earned_offline() {
+        let storage = storage_with_clients(4);
+        let mut earned = Earned::default();
+        earned.add("127.0.0.1:9000", 30);
+        for client_id in [1, 9] {
+            let record = storage.get_point_record(client_id);
+            let gained = record.lock().unwrap().merge_earned(&earned).unwrap();
+            assert_eq!(gained, 30);
+        }
+
+        // The peer added 5 points offline and knows 15 more, but not the ones earned here
+        let mut peer_earned = Earned::default();
+        peer_earned.add("127.0.0.1:9001", 5);
+        let record = SafePointRecord::new();
+        *record.0.lock().unwrap().points.lock().unwrap() = Points(20, 0);
+        record.0.lock().unwrap().earned = peer_earned.clone();
+        storage
+            .apply_sync(
+                "127.0.0.1:9001",
+                SyncResponse {
+                    points: PointMap::from([(1, record)]),
+                    full: true,
+                    mark: SyncMark {
+                        epoch: 1,
+                        version: 1,
+                    },
+                    committed: 0,
+                },
+            )
+            .unwrap();
+        assert_eq!(available(&storage, 1), 50);
+        assert_eq!(available(&storage, 9), 30);
+
+        // A requester that knows some of the same points
+        peer_earned.add("127.0.0.1:9001", 2);
+        earned.merge(&peer_earned);
+        storage
+            .sync(SyncRequest {
+                since: None,
+                earned: HashMap::from([(1, earned)]),
+            })
+            .unwrap();
+        assert_eq!(available(&storage, 1), 52);
+    }
 
     #[test]
-    fn todo() {}
-}*/
+    fn test_freshest_sync() {
+        let response = |committed| SyncResponse {
+            points: PointMap::new(),
+            full: true,
+            mark: SyncMark {
+                epoch: 1,
+                version: 1,
+            },
+            committed,
+        };
+        let synced = vec![
+            ("127.0.0.1:9001".to_string(), response(5)),
+            ("127.0.0.1:9002".to_string(), response(9)),
+            ("127.0.0.1:9003".to_string(), response(7)),
+        ];
+        assert_eq!(PointStorage::freshest(&synced), Some(1));
+        assert_eq!(PointStorage::freshest(&[]), None);
+    }
+
+    #[test]
+    fn test_apply_sync_adopts_the_synced_state() {
+        let storage = storage_with_clients(1);
+        storage.versions.adopt(10);
+
+        storage
+            .apply_sync(
+                "127.0.0.1:9001",
+                SyncResponse {
+                    points: PointMap::new(),
+                    full: true,
+                    mark: SyncMark {
+                        epoch: 1,
+                        version: 1,
+                    },
+                    committed: 20,
+                },
+            )
+            .unwrap();
+        assert_eq!(storage.versions.committed(), 20);
+        assert_eq!(PointStorage::snapshot(&storage).unwrap().committed, 20);
+    }
+
+    /// Applies a fill of client 1 as a participant does, with the clock of its coordinator skewed.
+    fn apply_fill(storage: &Arc<PointStorage>, points: u64, skew: u64) {
+        let message = Message::CommitOrder(Order::new(1, OrderAction::FillPoints(points)));
+        let mut transaction = Transaction::new("127.0.0.1:9001".to_string(), &message).unwrap();
+        transaction.timestamp.physical += skew;
+        storage.in_doubt.add(transaction.clone(), None);
+        let decision = DecisionRequest {
+            transaction,
+            commit: true,
+        };
+        PointStorage::apply_decision(storage.clone(), decision).unwrap();
+    }
+
+    #[test]
+    fn test_adopt_the_state_that_applied_the_most() {
+        // The stale server missed the second fill, and its clock is an hour ahead
+        let stale = storage_with_clients(0);
+        apply_fill(&stale, 10, 3_600_000);
+        let fresh = storage_with_clients(0);
+        apply_fill(&fresh, 10, 0);
+        apply_fill(&fresh, 20, 0);
+
+        let restarted = storage_with_clients(0);
+        let synced = vec![
+            ("127.0.0.1:9001".to_string(), sync(&stale, None)),
+            ("127.0.0.1:9002".to_string(), sync(&fresh, None)),
+        ];
+        restarted.adopt_freshest(synced).unwrap();
+        assert_eq!(available(&restarted, 1), 30);
+        assert_eq!(restarted.versions.committed(), 2);
+
+        // The fresh server keeps its state
+        let synced = vec![("127.0.0.1:9001".to_string(), sync(&stale, None))];
+        fresh.adopt_freshest(synced).unwrap();
+        assert_eq!(available(&fresh, 1), 30);
+    }
+
+    #[test]
+    fn test_keep_the_state_when_a_minority_answered() {
+        let fresh = storage_with_clients(0);
+        apply_fill(&fresh, 10, 0);
+
+        // Only one of the other three servers answered
+        let storage = storage_with_clients(0);
+        storage.servers.write().unwrap().extend([
+            "127.0.0.1:9001".to_string(),
+            "127.0.0.1:9002".to_string(),
+            "127.0.0.1:9003".to_string(),
+        ]);
+        let synced = vec![("127.0.0.1:9001".to_string(), sync(&fresh, None))];
+        storage.adopt_freshest(synced).unwrap();
+        assert_eq!(available(&storage, 1), 0);
+        assert_eq!(storage.versions.committed(), 0);
+        assert_eq!(
+            storage.adopt_freshest(vec![]),
+            Err("Failed to sync with any server".to_string())
+        );
+    }
+
+    #[test]
+    fn test_apply_the_decision_of_a_transaction_in_doubt() {
+        let storage = storage_with_clients(0);
+        let fill = |client_id, points| {
+            let message =
+                Message::CommitOrder(Order::new(client_id, OrderAction::FillPoints(points)));
+            Transaction::new("127.0.0.1:9001".to_string(), &message).unwrap()
+        };
+        let decide = |transaction: &Transaction, commit| DecisionRequest {
+            transaction: transaction.clone(),
+            commit,
+        };
+        let committed = fill(1, 10);
+        let aborted = fill(2, 20);
+        let in_doubt = storage.in_doubt.clone();
+        in_doubt.add(committed.clone(), None);
+        in_doubt.add(aborted.clone(), None);
+
+        PointStorage::apply_decision(storage.clone(), decide(&aborted, false)).unwrap();
+        PointStorage::apply_decision(storage.clone(), decide(&committed, true)).unwrap();
+        // A decision received again is not applied twice
+        PointStorage::apply_decision(storage.clone(), decide(&committed, true)).unwrap();
+
+        assert_eq!(available(&storage, 1), 10);
+        assert_eq!(available(&storage, 2), 0);
+        assert_eq!(storage.versions.committed(), 1);
+    }
+
+    #[test]
+    fn test_answer_the_known_outcomes() {
+        let storage = storage_with_clients(0);
+        let fill = |client_id| {
+            let message = Message::CommitOrder(Order::new(client_id, OrderAction::FillPoints(10)));
+            Transaction::new("127.0.0.1:9001".to_string(), &message).unwrap()
+        };
+        let status = |storage: &Arc<PointStorage>, transaction: &Transaction| {
+            let req = TxStatusRequest {
+                transaction: transaction.clone(),
+            };
+            let res = storage.tx_status(req).unwrap();
+            serde_json::from_str::<TxStatusResponse>(&res)
+                .unwrap()
+                .status
+        };
+        let committed = fill(1);
+        let aborted = fill(2);
+        let in_doubt = storage.in_doubt.clone();
+        in_doubt.add(committed.clone(), None);
+        in_doubt.add(aborted.clone(), None);
+        // Being in doubt does not mean being aborted
+        assert_eq!(status(&storage, &committed), TxStatus::Unknown);
+
+        let decide = |transaction: &Transaction, commit| DecisionRequest {
+            transaction: transaction.clone(),
+            commit,
+        };
+        PointStorage::apply_decision(storage.clone(), decide(&committed, true)).unwrap();
+        PointStorage::apply_decision(storage.clone(), decide(&aborted, false)).unwrap();
+
+        assert_eq!(status(&storage, &committed), TxStatus::Committed);
+        assert_eq!(status(&storage, &aborted), TxStatus::Aborted);
+        assert!(in_doubt.snapshot().is_empty());
+    }
+
+    /// Opens a link to a listener that hands out the participant side of each exchange.
+    fn coordinator_link() -> (Exchange, Exchange) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (sender, received) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.read_exact(&mut [0; 1]).unwrap();
+            Link::serve(stream, |mut exchange| {
+                exchange.read_exact(&mut [0; 1]).unwrap();
+                sender.send(exchange).unwrap();
+            })
+        });
+        let mut coordinator = LINKS.open(&addr).unwrap();
+        coordinator.write_all(&[0]).unwrap();
+        (coordinator, received.recv().unwrap())
+    }
+
+    #[test]
+    fn test_hold_the_record_while_in_doubt() {
+        let storage = storage_with_clients(0);
+        let record = storage.get_point_record(1);
+        let commit = |order: Order| {
+            Transaction::new("127.0.0.1:9001".to_string(), &Message::CommitOrder(order)).unwrap()
+        };
+        let order = Order::new(1, OrderAction::UsePoints(10));
+        let lock = Transaction::new(
+            "127.0.0.1:9001".to_string(),
+            &Message::LockOrder(order.clone()),
+        )
+        .unwrap();
+        let reservation = lock.reservation.unwrap();
+        {
+            let mut record = record.lock().unwrap();
+            *record.points.lock().unwrap() = Points(0, 10);
+            record.settle(&lock);
+        }
+        let consume = commit(order.clone().with_reservation(reservation));
+
+        // The coordinator disappears after the participant votes to commit
+        let (mut coordinator, mut participant) = coordinator_link();
+        let handler = {
+            let storage = storage.clone();
+            let consume = consume.clone();
+            thread::spawn(move || {
+                PointStorage::handle_transaction(storage, consume, &mut participant)
+            })
+        };
+        let vote: PrepareResponse =
+            serde_json::from_slice(&receive_response(&mut coordinator).unwrap()).unwrap();
+        assert!(vote.proceed);
+        drop(coordinator);
+        assert!(handler.join().unwrap().is_err());
+        assert_eq!(storage.in_doubt.snapshot().len(), 1);
+
+        // Neither the turn nor the reservation can be taken by another transaction
+        let free = commit(order.with_reservation(reservation));
+        let queue = record.lock().unwrap().queue.clone();
+        assert!(queue.holder().unwrap().same_as(&consume));
+        assert!(queue.wait_turn(&free, Duration::ZERO).is_err());
+        assert_eq!(
+            record.lock().unwrap().take_reservation(&free),
+            Err(Response::UnknownReservation)
+        );
+
+        let decision = DecisionRequest {
+            transaction: consume,
+            commit: true,
+        };
+        PointStorage::apply_decision(storage.clone(), decision).unwrap();
+
+        let record = record.lock().unwrap();
+        let points = record.points.lock().unwrap();
+        assert_eq!((points.0, points.1), (0, 0));
+        drop(points);
+        assert!(record.reservations.is_empty());
+        assert!(record.queue.holder().is_none());
+        assert!(storage.in_doubt.snapshot().is_empty());
+    }
+
+    #[test]
+    fn test_give_back_the_reservation_of_a_failed_pending_transaction() {
+        let storage = storage_with_clients(0);
+        let record = storage.get_point_record(1);
+        let order = Order::new(1, OrderAction::UsePoints(10));
+        let lock = Transaction::new(
+            "127.0.0.1:9000".to_string(),
+            &Message::LockOrder(order.clone()),
+        )
+        .unwrap();
+        let reservation = lock.reservation.unwrap();
+        record.lock().unwrap().settle(&lock);
+
+        // Queued while offline, the consume took the reservation, but the locked points are gone
+        let consume = Transaction::new(
+            "127.0.0.1:9000".to_string(),
+            &Message::CommitOrder(order.with_reservation(reservation)),
+        )
+        .unwrap();
+        record.lock().unwrap().take_reservation(&consume).unwrap();
+        assert!(PointStorage::coordinate_tx(consume.clone(), storage.clone()).is_err());
+
+        let record = record.lock().unwrap();
+        assert!(!record.reservations[&reservation].settling);
+        assert!(record.reservations[&reservation].should_release(
+            "127.0.0.1:9000",
+            u128::MAX,
+            Duration::ZERO
+        ));
+    }
+
+    #[test]
+    fn test_balance_of_a_majority() {
+        let (a, b, c) = (
+            Balance::new(10, 0),
+            Balance::new(20, 0),
+            Balance::new(30, 0),
+        );
+
+        assert_eq!(
+            PointStorage::majority_balance(vec![(a, 3), (b, 1)], 4),
+            Some(a)
+        );
+        // The most reported balance is not enough if the servers split
+        assert_eq!(
+            PointStorage::majority_balance(vec![(a, 2), (b, 1), (c, 1)], 4),
+            None
+        );
+        assert_eq!(
+            PointStorage::majority_balance(vec![(a, 2), (b, 2)], 4),
+            None
+        );
+        assert_eq!(
+            PointStorage::majority_balance(vec![(a, 2), (b, 1)], 3),
+            Some(a)
+        );
+    }
+
+    #[test]
+    fn test_wait_for_the_turn_of_the_record() {
+        let storage = storage_with_clients(0);
+        let fill = |points| Message::CommitOrder(Order::new(1, OrderAction::FillPoints(points)));
+        let older = Transaction::new("127.0.0.1:9001".to_string(), &fill(10)).unwrap();
+        let record = storage.get_point_record(1);
+
+        // The older transaction releases the record while the younger one waits for its turn
+        let queue = record.lock().unwrap().queue.clone();
+        let turn = queue.wait_turn(&older, Duration::ZERO).unwrap();
+        let releaser = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(turn);
+        });
+        let response = PointStorage::coordinate_msg(fill(20), storage.clone());
+        releaser.join().unwrap();
+
+        assert_eq!(response, Ok(Response::Ok));
+        assert_eq!(available(&storage, 1), 20);
+        assert_eq!(storage.busy_retries.load(Ordering::Relaxed), 0);
+        assert!(storage.queue_stats.average_wait_ms() >= 50);
+        assert_eq!(storage.queue_stats.max_depth(), 1);
+    }
+
+    #[test]
+    fn test_refuse_to_start_with_a_quorum_larger_than_the_cluster() {
+        let config = Config {
+            quorum: Quorum::AtLeast(2),
+            ..Config::default()
+        };
+        let storage = PointStorage::new(
+            "127.0.0.1:9000".to_string(),
+            None,
+            Wal::disabled(),
+            Snapshot::default(),
+            &config,
+        );
+        assert!(storage.is_err());
+    }
+
+    #[test]
+    fn test_refuse_the_changes_the_log_can_not_keep() {
+        let storage = PointStorage::new(
+            "127.0.0.1:9000".to_string(),
+            None,
+            Wal::failing(),
+            Snapshot::default(),
+            &Config::default(),
+        )
+        .unwrap();
+        let fill = Message::CommitOrder(Order::new(1, OrderAction::FillPoints(10)));
+        assert_eq!(
+            PointStorage::coordinate_msg(fill, storage.clone()),
+            Err(Response::Aborted)
+        );
+        assert_eq!(available(&storage, 1), 0);
+
+        let peer = storage_with_clients(0);
+        apply_fill(&peer, 10, 0);
+        assert!(storage
+            .apply_sync("127.0.0.1:9001", sync(&peer, None))
+            .is_err());
+        assert_eq!(available(&storage, 1), 0);
+        assert_eq!(storage.versions.committed(), 0);
+    }
+
+    #[test]
+    fn test_busy_record_times_out_before_the_client() {
+        let storage = storage_with_clients(0);
+        let fill = |points| Message::CommitOrder(Order::new(1, OrderAction::FillPoints(points)));
+        let older = Transaction::new("127.0.0.1:9001".to_string(), &fill(10)).unwrap();
+        let record = storage.get_point_record(1);
+
+        // The record stays busy for longer than the coffee maker waits
+        let queue = record.lock().unwrap().queue.clone();
+        let _turn = queue.wait_turn(&older, Duration::ZERO).unwrap();
+        let started = Instant::now();
+        let response = PointStorage::coordinate_msg(fill(20), storage.clone());
+
+        assert_eq!(response, Err(Response::Timeout));
+        assert!(started.elapsed() < Duration::from_millis(1000));
+        assert_eq!(storage.busy_retries.load(Ordering::Relaxed), 0);
+        assert_eq!(available(&storage, 1), 0);
+    }
+
+    /// Serves links on a new port as a participant that kills the first attempt of each transaction
+    /// by wait-die and approves the next one, sending the transactions it receives.
+    fn dying_participant() -> (String, mpsc::Receiver<Transaction>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (sender, received) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                stream.read_exact(&mut [0; 1]).unwrap();
+                let sender = sender.clone();
+                thread::spawn(move || {
+                    Link::serve(stream, |mut exchange| {
+                        let sender = sender.clone();
+                        thread::spawn(move || {
+                            exchange.read_exact(&mut [0; 1]).unwrap();
+                            receive_timestamp(&mut exchange).unwrap();
+                            let tx: Transaction =
+                                serde_json::from_slice(&receive_from(&mut exchange).unwrap())
+                                    .unwrap();
+                            let proceed = tx.attempt > 0;
+                            sender.send(tx).unwrap();
+                            let vote = PrepareResponse {
+                                proceed,
+                                reason: (!proceed).then_some(AbortReason::WaitDie),
+                            };
+                            respond_to(&mut exchange, serde_json::to_string(&vote).unwrap())
+                                .unwrap();
+                            // Waits for the decision
+                            let _ = exchange.read_exact(&mut [0; 1]);
+                        });
+                    })
+                });
+            }
+        });
+        (addr, received)
+    }
+
+    #[test]
+    fn test_retry_a_transaction_killed_by_a_participant() {
+        let storage = storage_with_clients(0);
+        let (participant, received) = dying_participant();
+        storage
+            .add_connection(ConnectRequest {
+                addr: participant,
+                copy: true,
+            })
+            .unwrap();
+        let record = storage.get_point_record(1);
+        record.lock().unwrap().points.lock().unwrap().0 = 100;
+
+        let lock = Message::LockOrder(Order::new(1, OrderAction::UsePoints(10)));
+        let response = PointStorage::coordinate_msg(lock, storage.clone());
+
+        assert!(matches!(response, Ok(Response::Reserved(_))));
+        assert_eq!(storage.busy_retries.load(Ordering::Relaxed), 1);
+        let first = received.recv().unwrap();
+        let retried = received.recv().unwrap();
+        assert_eq!((first.attempt, retried.attempt), (0, 1));
+        // The retry keeps its priority
+        assert_eq!(first.timestamp, retried.timestamp);
+        assert_eq!(record.lock().unwrap().points.lock().unwrap().1, 10);
+    }
+
+    #[test]
+    fn test_storage_is_usable_while_syncing() {
+        let storage = storage_with_clients(1);
+        // A server that accepts the sync but never answers it
+        let silent = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let silent_addr = silent.local_addr().unwrap().to_string();
+        storage
+            .add_connection(ConnectRequest {
+                addr: silent_addr,
+                copy: true,
+            })
+            .unwrap();
+
+        let syncing = storage.clone();
+        let sync = thread::spawn(move || PointStorage::on_connect(syncing));
+        thread::sleep(Duration::from_millis(100));
+
+        let started = std::time::Instant::now();
+        PointStorage::read_balance(storage.clone(), 0).unwrap();
+        storage.get_point_record(7);
+        storage.disconnect();
+        storage.connect();
+        assert!(started.elapsed() < Duration::from_millis(TIMEOUT / 2));
+
+        sync.join().unwrap();
+        assert!(storage.is_online());
+    }
+}
@@ -0,0 +1,91 @@
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Waits of the transactions for their turn to use a record, for monitoring.
+#[derive(Debug, Default)]
+pub struct QueueStats {
+    /// Transactions that got their turn.
+    turns: AtomicU64,
+    /// Transactions that gave up waiting.
+    timeouts: AtomicU64,
+    total_wait_ms: AtomicU64,
+    max_wait_ms: AtomicU64,
+    /// Most transactions found ahead by a transaction when it started waiting.
+    max_depth: AtomicU64,
+}
+
+impl QueueStats {
+    pub fn new() -> Arc<Self> {
+        Arc::new(QueueStats::default())
+    }
+
+    /// Records that a transaction got its turn after the given wait, behind the given amount of transactions.
+    pub fn turn(&self, waited: Duration, ahead: usize) {
+        let waited = waited.as_millis() as u64;
+        self.turns.fetch_add(1, Ordering::Relaxed);
+        self.total_wait_ms.fetch_add(waited, Ordering::Relaxed);
+        self.max_wait_ms.fetch_max(waited, Ordering::Relaxed);
+        self.max_depth.fetch_max(ahead as u64, Ordering::Relaxed);
+    }
+
+    /// Records that a transaction gave up waiting for its turn.
+    pub fn timeout(&self) {
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Average wait for a turn, in milliseconds.
+    pub fn average_wait_ms(&self) -> u64 {
+        let turns = self.turns.load(Ordering::Relaxed);
+        self.total_wait_ms
+            .load(Ordering::Relaxed)
+            .checked_div(turns)
+            .unwrap_or(0)
+    }
+
+    pub fn timeouts(&self) -> u64 {
+        self.timeouts.load(Ordering::Relaxed)
+    }
+
+    pub fn max_depth(&self) -> u64 {
+        self.max_depth.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Display for QueueStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} turns, {} ms average wait, {} ms max wait, {} max depth, {} timeouts",
+            self.turns.load(Ordering::Relaxed),
+            self.average_wait_ms(),
+            self.max_wait_ms.load(Ordering::Relaxed),
+            self.max_depth(),
+            self.timeouts()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queue_stats() {
+        let stats = QueueStats::new();
+        assert_eq!(stats.average_wait_ms(), 0);
+
+        stats.turn(Duration::from_millis(10), 0);
+        stats.turn(Duration::from_millis(30), 3);
+        stats.timeout();
+
+        assert_eq!(stats.average_wait_ms(), 20);
+        assert_eq!(stats.max_depth(), 3);
+        assert_eq!(stats.timeouts(), 1);
+    }
+}
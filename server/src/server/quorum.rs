@@ -0,0 +1,174 @@
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
+
+use points::parse_addr;
+
+/// Servers that must approve a transaction for its coordinator to commit it.
+/// The coordinator counts as approving, and the cluster includes it.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Quorum {
+    /// More than half of the servers of the cluster.
+    #[default]
+    Majority,
+    /// Every server of the cluster.
+    All,
+    /// At least the given amount of servers.
+    AtLeast(usize),
+    /// More than half of the servers of each region, by the region of each server.
+    /// Servers without a region are grouped together, as if they shared one more region.
+    RegionMajority(HashMap<String, String>),
+}
+
+impl Quorum {
+    /// Returns whether the servers that approved are enough among the servers of the cluster.
+    pub fn is_met(&self, approved: &HashSet<String>, cluster: &HashSet<String>) -> bool {
+        let approved: HashSet<&String> = approved.intersection(cluster).collect();
+        match self {
+            Quorum::Majority => approved.len() * 2 > cluster.len(),
+            Quorum::All => approved.len() == cluster.len(),
+            Quorum::AtLeast(count) => approved.len() >= *count,
+            Quorum::RegionMajority(regions) => {
+                let region_of = |server: &String| regions.get(server).cloned().unwrap_or_default();
+                let mut votes: HashMap<String, (usize, usize)> = HashMap::new();
+                for server in cluster {
+                    let (approving, total) = votes.entry(region_of(server)).or_default();
+                    *total += 1;
+                    if approved.contains(server) {
+                        *approving += 1;
+                    }
+                }
+                votes
+                    .values()
+                    .all(|(approving, total)| approving * 2 > *total)
+            }
+        }
+    }
+
+    /// Returns an error if the quorum asks for more servers than the cluster has,
+    /// since no transaction could ever be approved.
+    pub fn check(&self, cluster: usize) -> Result<(), String> {
+        match self {
+            Quorum::AtLeast(count) if *count > cluster => Err(format!(
+                "A quorum of {} servers is larger than the cluster of {}",
+                count, cluster
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl FromStr for Quorum {
+    type Err = String;
+
+    /// Parses `majority`, `all`, an amount of servers,
+    /// or `regions:<address>=<region>,<address>=<region>,...`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "majority" => return Ok(Quorum::Majority),
+            "all" => return Ok(Quorum::All),
+            _ => {}
+        }
+        if let Ok(count) = s.parse::<usize>() {
+            return match count {
+                0 => Err("A quorum needs at least one server".to_string()),
+                _ => Ok(Quorum::AtLeast(count)),
+            };
+        }
+        let regions = s
+            .strip_prefix("regions:")
+            .ok_or_else(|| format!("Unknown quorum {}", s))?;
+        regions
+            .split(',')
+            .map(|server| match server.split_once('=') {
+                Some((address, region)) if !address.is_empty() && !region.is_empty() => {
+                    Ok((parse_addr(address.to_string()), region.to_string()))
+                }
+                _ => Err(format!("Invalid region of a server: {}", server)),
+            })
+            .collect::<Result<_, _>>()
+            .map(Quorum::RegionMajority)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn servers(addresses: &[&str]) -> HashSet<String> {
+        addresses
+            .iter()
+            .map(|address| address.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_quorum_of_the_whole_cluster() {
+        let cluster = servers(&["a", "b", "c", "d"]);
+        let half = servers(&["a", "b"]);
+        let three = servers(&["a", "b", "c"]);
+
+        assert!(!Quorum::Majority.is_met(&half, &cluster));
+        assert!(Quorum::Majority.is_met(&three, &cluster));
+        assert!(!Quorum::All.is_met(&three, &cluster));
+        assert!(Quorum::All.is_met(&cluster, &cluster));
+        assert!(Quorum::AtLeast(2).is_met(&half, &cluster));
+        assert!(!Quorum::AtLeast(4).is_met(&three, &cluster));
+        // Servers outside the cluster do not count
+        assert!(!Quorum::AtLeast(3).is_met(&servers(&["a", "b", "e"]), &cluster));
+    }
+
+    #[test]
+    fn test_quorum_of_each_region() {
+        let quorum: Quorum = "regions:9000=north,9001=north,9002=north,9003=south"
+            .parse()
+            .unwrap();
+        let cluster = servers(&[
+            "localhost:9000",
+            "localhost:9001",
+            "localhost:9002",
+            "localhost:9003",
+        ]);
+
+        let north = servers(&["localhost:9000", "localhost:9001"]);
+        assert!(!quorum.is_met(&north, &cluster));
+        let both = servers(&["localhost:9000", "localhost:9001", "localhost:9003"]);
+        assert!(quorum.is_met(&both, &cluster));
+    }
+
+    #[test]
+    fn test_servers_without_region_share_one() {
+        let quorum: Quorum = "regions:9000=north".parse().unwrap();
+        let cluster = servers(&[
+            "localhost:9000",
+            "localhost:9001",
+            "localhost:9002",
+            "localhost:9003",
+        ]);
+
+        // One of the three servers without a region is not a majority of them
+        let one = servers(&["localhost:9000", "localhost:9001"]);
+        assert!(!quorum.is_met(&one, &cluster));
+        let two = servers(&["localhost:9000", "localhost:9001", "localhost:9002"]);
+        assert!(quorum.is_met(&two, &cluster));
+    }
+
+    #[test]
+    fn test_parse_quorum() {
+        assert_eq!("majority".parse(), Ok(Quorum::Majority));
+        assert_eq!("all".parse(), Ok(Quorum::All));
+        assert_eq!("2".parse(), Ok(Quorum::AtLeast(2)));
+        assert!("0".parse::<Quorum>().is_err());
+        assert!("regions:9000".parse::<Quorum>().is_err());
+        assert!("most".parse::<Quorum>().is_err());
+    }
+
+    #[test]
+    fn test_refuse_a_quorum_larger_than_the_cluster() {
+        assert!(Quorum::AtLeast(3).check(3).is_ok());
+        assert!(Quorum::AtLeast(4).check(3).is_err());
+        assert!(Quorum::Majority.check(1).is_ok());
+        assert!(Quorum::All.check(1).is_ok());
+    }
+}
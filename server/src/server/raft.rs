@@ -0,0 +1,563 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use points::Response;
+use rayon::prelude::*;
+use tracing::{info, trace};
+
+use super::{
+    message::{
+        append_entries_to, request_vote_from, AppendEntriesRequest, AppendEntriesResponse,
+        VoteRequest, VoteResponse,
+    },
+    raft_log::{LogEntry, RaftLog},
+    transaction::{generate_timestamp, Transaction},
+    wal::{Wal, WalEntry},
+};
+
+/// Time between the rounds of replication of the leader, which are also its heartbeats.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Minimum time without hearing from a leader before a server starts an election.
+/// Each server waits a different time up to twice as long, so they rarely start at once.
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Amount of entries sent to a follower in a single message.
+const MAX_ENTRIES: usize = 100;
+
+/// Amount of outcomes of applied transactions kept for the proposers that did not take them.
+const MAX_RESULTS: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Debug)]
+struct RaftState {
+    log: RaftLog,
+    role: Role,
+    leader: Option<String>,
+    /// Index of the last entry stored by a majority of the servers.
+    commit_index: u64,
+    /// Index of the last entry applied to the points.
+    last_applied: u64,
+    /// Index of the next entry to send to each follower, only used by the leader.
+    next_index: HashMap<String, u64>,
+    /// Index of the last entry each follower has in common with the leader, only used by the leader.
+    match_index: HashMap<String, u64>,
+    /// Last time this server heard from the leader, granted a vote or started an election.
+    heard_at: Instant,
+    election_timeout: Duration,
+    /// Outcome of the applied transactions proposed by this server, until the proposer takes it.
+    results: VecDeque<(Transaction, Result<(), Response>)>,
+}
+
+/// Replicated log of the transactions, as an alternative to coordinating each one with a two-phase commit.
+/// The servers elect a leader that appends the transactions to its log and replicates it to the others.
+/// An entry stored by a majority of the servers is committed, and every server applies
+/// the committed transactions to its points in the same order.
+#[derive(Debug)]
+pub struct Raft {
+    address: String,
+    state: Mutex<RaftState>,
+    /// Notified when a transaction proposed by this server is applied.
+    applied: Condvar,
+    /// Held while applying the committed entries, so they are applied in order.
+    applying: Mutex<()>,
+    wal: Arc<Wal>,
+}
+
+impl Raft {
+    /// Creates the replication of the server with the given address as a follower,
+    /// from the term, vote and entries recovered from its log.
+    pub fn new(address: String, wal: Arc<Wal>, log: RaftLog) -> Arc<Self> {
+        let election_timeout = Self::election_timeout(&address, log.term);
+        Arc::new(Raft {
+            address,
+            state: Mutex::new(RaftState {
+                log,
+                role: Role::Follower,
+                leader: None,
+                commit_index: 0,
+                last_applied: 0,
+                next_index: HashMap::new(),
+                match_index: HashMap::new(),
+                heard_at: Instant::now(),
+                election_timeout,
+                results: VecDeque::new(),
+            }),
+            applied: Condvar::new(),
+            applying: Mutex::new(()),
+            wal,
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, RaftState> {
+        self.state.lock().expect("Could not lock raft state")
+    }
+
+    /// Election timeout of the given server in the given term,
+    /// between one and two times `ELECTION_TIMEOUT`.
+    fn election_timeout(address: &str, term: u64) -> Duration {
+        let mut hasher = DefaultHasher::new();
+        (address, term, generate_timestamp()).hash(&mut hasher);
+        let jitter = hasher.finish() % ELECTION_TIMEOUT.as_millis() as u64;
+        ELECTION_TIMEOUT + Duration::from_millis(jitter)
+    }
+
+    /// Moves to the given term as a follower, if it is newer than the current one.
+    fn observe_term(&self, state: &mut RaftState, term: u64) {
+        if term > state.log.term {
+            state.log.term = term;
+            state.log.voted_for = None;
+            state.role = Role::Follower;
+            state.leader = None;
+            self.log_term(state);
+        }
+    }
+
+    /// Writes the current term and vote to the log.
+    fn log_term(&self, state: &RaftState) {
+        self.wal.append_or_stop(&WalEntry::RaftTerm {
+            term: state.log.term,
+            voted_for: state.log.voted_for.clone(),
+        });
+    }
+
+    /// Writes the given entries to the log before replacing the ones from the given index on.
+    fn append(&self, state: &mut RaftState, index: u64, entries: Vec<LogEntry>) {
+        self.wal.append_or_stop(&WalEntry::RaftAppend {
+            index,
+            entries: entries.clone(),
+        });
+        state.log.replace_from(index, entries);
+    }
+
+    /// Handles the request of a candidate for the vote of this server.
+    /// The vote is granted once per term, to a candidate whose log is at least as up to date as this one.
+    pub fn vote(&self, req: VoteRequest) -> VoteResponse {
+        let mut state = self.lock();
+        self.observe_term(&mut state, req.term);
+
+        let up_to_date = (req.last_log_term, req.last_log_index)
+            >= (state.log.last_term(), state.log.last_index());
+        let granted = req.term == state.log.term
+            && up_to_date
+            && state
+                .log
+                .voted_for
+                .iter()
+                .all(|voted| *voted == req.candidate);
+        if granted {
+            if state.log.voted_for.is_none() {
+                state.log.voted_for = Some(req.candidate);
+                self.log_term(&state);
+            }
+            state.heard_at = Instant::now();
+        }
+        VoteResponse {
+            term: state.log.term,
+            granted,
+        }
+    }
+
+    /// Handles the entries replicated by the leader.
+    /// Entries in conflict with the ones of the leader are replaced, the ones already stored are kept.
+    pub fn append_entries(&self, req: AppendEntriesRequest) -> AppendEntriesResponse {
+        let mut state = self.lock();
+        self.observe_term(&mut state, req.term);
+        let term = state.log.term;
+        let rejected = AppendEntriesResponse {
+            term,
+            success: false,
+            match_index: 0,
+        };
+        if req.term < term {
+            return rejected;
+        }
+        state.role = Role::Follower;
+        state.leader = Some(req.leader);
+        state.heard_at = Instant::now();
+
+        if state.log.term_at(req.prev_log_index) != Some(req.prev_log_term) {
+            return rejected;
+        }
+        let first = req.prev_log_index + 1;
+        let match_index = req.prev_log_index + req.entries.len() as u64;
+        let conflict = req
+            .entries
+            .iter()
+            .enumerate()
+            .position(|(i, entry)| state.log.term_at(first + i as u64) != Some(entry.term));
+        if let Some(conflict) = conflict {
+            let entries = req.entries[conflict..].to_vec();
+            self.append(&mut state, first + conflict as u64, entries);
+        }
+        if req.leader_commit > state.commit_index {
+            state.commit_index = state.commit_index.max(req.leader_commit.min(match_index));
+        }
+        AppendEntriesResponse {
+            term,
+            success: true,
+            match_index,
+        }
+    }
+
+    /// Appends a transaction to the log if this server is the leader.
+    /// Otherwise returns the leader known by this server, if any.
+    pub fn propose(&self, transaction: Transaction) -> Result<(), Option<String>> {
+        let mut state = self.lock();
+        if state.role != Role::Leader {
+            return Err(state.leader.clone());
+        }
+        let entry = LogEntry {
+            term: state.log.term,
+            transaction: Some(transaction),
+        };
+        let index = state.log.last_index() + 1;
+        self.append(&mut state, index, vec![entry]);
+        Ok(())
+    }
+
+    /// Runs a round of the replication with the other servers:
+    /// the leader sends the entries each follower is missing, or a heartbeat,
+    /// and a server that did not hear from a leader in time starts an election.
+    pub fn tick(&self, peers: &[String]) {
+        let (leader, election_due) = {
+            let state = self.lock();
+            (
+                state.role == Role::Leader,
+                state.heard_at.elapsed() >= state.election_timeout,
+            )
+        };
+        if leader {
+            self.replicate(peers);
+        } else if election_due {
+            self.elect(peers);
+        }
+    }
+
+    /// Starts an election for the next term, becoming the leader with the votes of a majority.
+    fn elect(&self, peers: &[String]) {
+        let req = {
+            let mut state = self.lock();
+            state.log.term += 1;
+            state.log.voted_for = Some(self.address.clone());
+            state.role = Role::Candidate;
+            state.leader = None;
+            state.heard_at = Instant::now();
+            state.election_timeout = Self::election_timeout(&self.address, state.log.term);
+            self.log_term(&state);
+            info!("Starting election for term {}", state.log.term);
+            VoteRequest {
+                term: state.log.term,
+                candidate: self.address.clone(),
+                last_log_index: state.log.last_index(),
+                last_log_term: state.log.last_term(),
+            }
+        };
+
+        let responses: Vec<VoteResponse> = peers
+            .par_iter()
+            .filter_map(|peer| request_vote_from(peer, &req).ok())
+            .collect();
+
+        let mut state = self.lock();
+        let mut votes = 1;
+        for res in responses {
+            self.observe_term(&mut state, res.term);
+            if res.granted && res.term == req.term {
+                votes += 1;
+            }
+        }
+        if state.role == Role::Candidate
+            && state.log.term == req.term
+            && Self::is_majority(votes, peers)
+        {
+            self.lead(&mut state, peers);
+        }
+    }
+
+    /// Returns whether the given amount of servers, including this one, is a majority.
+    fn is_majority(count: usize, peers: &[String]) -> bool {
+        count * 2 > peers.len() + 1
+    }
+
+    /// Becomes the leader of the current term.
+    /// It appends an entry without a transaction, so the entries of previous terms are committed with it.
+    fn lead(&self, state: &mut RaftState, peers: &[String]) {
+        info!("Elected leader of term {}", state.log.term);
+        state.role = Role::Leader;
+        state.leader = Some(self.address.clone());
+        let next = state.log.last_index() + 1;
+        state.next_index = peers.iter().map(|peer| (peer.clone(), next)).collect();
+        state.match_index = peers.iter().map(|peer| (peer.clone(), 0)).collect();
+        let entry = LogEntry {
+            term: state.log.term,
+            transaction: None,
+        };
+        self.append(state, next, vec![entry]);
+    }
+
+    /// Sends the entries each follower is missing, and advances the commit index
+    /// with the ones stored by a majority.
+    fn replicate(&self, peers: &[String]) {
+        let requests: Vec<(String, AppendEntriesRequest)> = {
+            let state = self.lock();
+            peers
+                .iter()
+                .map(|peer| {
+                    let next = state
+                        .next_index
+                        .get(peer)
+                        .copied()
+                        .unwrap_or(state.log.last_index() + 1);
+                    let mut entries = state.log.entries_from(next);
+                    entries.truncate(MAX_ENTRIES);
+                    let req = AppendEntriesRequest {
+                        term: state.log.term,
+                        leader: self.address.clone(),
+                        prev_log_index: next - 1,
+                        prev_log_term: state.log.term_at(next - 1).unwrap_or(0),
+                        entries,
+                        leader_commit: state.commit_index,
+                    };
+                    (peer.clone(), req)
+                })
+                .collect()
+        };
+
+        let responses: Vec<(&String, &AppendEntriesRequest, AppendEntriesResponse)> = requests
+            .par_iter()
+            .filter_map(|(peer, req)| match append_entries_to(peer, req) {
+                Ok(res) => Some((peer, req, res)),
+                Err(e) => {
+                    trace!("Could not replicate to {}: {}", peer, e);
+                    None
+                }
+            })
+            .collect();
+
+        let mut state = self.lock();
+        for (peer, req, res) in responses {
+            self.observe_term(&mut state, res.term);
+            if state.role != Role::Leader || state.log.term != req.term {
+                return;
+            }
+            if res.success {
+                state.match_index.insert(peer.clone(), res.match_index);
+                state.next_index.insert(peer.clone(), res.match_index + 1);
+            } else {
+                // The follower does not have the previous entry, the next round sends an earlier one
+                state
+                    .next_index
+                    .insert(peer.clone(), req.prev_log_index.max(1));
+            }
+        }
+        Self::advance_commit(&mut state, peers);
+    }
+
+    /// Commits the last entry of the current term stored by a majority, along with the ones before it.
+    /// Entries of previous terms are only committed this way, as Raft requires.
+    fn advance_commit(state: &mut RaftState, peers: &[String]) {
+        for index in (state.commit_index + 1..=state.log.last_index()).rev() {
+            if state.log.term_at(index) != Some(state.log.term) {
+                break;
+            }
+            let stored = 1 + peers
+                .iter()
+                .filter(|peer| state.match_index.get(*peer).copied().unwrap_or(0) >= index)
+                .count();
+            if Self::is_majority(stored, peers) {
+                state.commit_index = index;
+                break;
+            }
+        }
+    }
+
+    /// Applies the committed entries that were not applied yet, in order, with the given function.
+    /// The outcome of the transactions proposed by this server is kept for `wait_applied`.
+    pub fn apply_committed(&self, mut apply: impl FnMut(&Transaction) -> Result<(), Response>) {
+        let _applying = self.applying.lock().expect("Could not lock raft applier");
+        let entries = {
+            let mut state = self.lock();
+            let entries = state.log.entries
+                [state.last_applied as usize..state.commit_index as usize]
+                .to_vec();
+            state.last_applied = state.commit_index;
+            entries
+        };
+
+        for entry in entries {
+            let transaction = match entry.transaction {
+                Some(transaction) => transaction,
+                None => continue,
+            };
+            let result = apply(&transaction);
+            if transaction.coordinator == self.address {
+                let mut state = self.lock();
+                state.results.push_back((transaction, result));
+                if state.results.len() > MAX_RESULTS {
+                    state.results.pop_front();
+                }
+                self.applied.notify_all();
+            }
+        }
+    }
+
+    /// Waits for a transaction proposed by this server to be applied, returning its outcome.
+    /// Returns `None` if it was not applied in time.
+    pub fn wait_applied(
+        &self,
+        transaction: &Transaction,
+        timeout: Duration,
+    ) -> Option<Result<(), Response>> {
+        let state = self.lock();
+        let (mut state, _) = self
+            .applied
+            .wait_timeout_while(state, timeout, |state| {
+                !state.results.iter().any(|(tx, _)| tx.same_as(transaction))
+            })
+            .expect("Could not lock raft state");
+        let position = state
+            .results
+            .iter()
+            .position(|(tx, _)| tx.same_as(transaction))?;
+        state.results.remove(position).map(|(_, result)| result)
+    }
+
+    /// Returns the term, vote and entries to keep in a snapshot.
+    pub fn snapshot(&self) -> RaftLog {
+        self.lock().log.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use points::{Message, Order, OrderAction};
+
+    use super::*;
+
+    const ADDRESS: &str = "127.0.0.1:9000";
+    const LEADER: &str = "127.0.0.1:9001";
+
+    fn fill(points: u64) -> Transaction {
+        let message = Message::CommitOrder(Order::new(1, OrderAction::FillPoints(points)));
+        Transaction::new(ADDRESS.to_string(), &message).unwrap()
+    }
+
+    fn entry(term: u64, points: u64) -> LogEntry {
+        LogEntry {
+            term,
+            transaction: Some(fill(points)),
+        }
+    }
+
+    fn append(
+        term: u64,
+        prev: (u64, u64),
+        entries: Vec<LogEntry>,
+        commit: u64,
+    ) -> AppendEntriesRequest {
+        AppendEntriesRequest {
+            term,
+            leader: LEADER.to_string(),
+            prev_log_index: prev.0,
+            prev_log_term: prev.1,
+            entries,
+            leader_commit: commit,
+        }
+    }
+
+    fn vote(term: u64, candidate: &str, last_log_index: u64, last_log_term: u64) -> VoteRequest {
+        VoteRequest {
+            term,
+            candidate: candidate.to_string(),
+            last_log_index,
+            last_log_term,
+        }
+    }
+
+    #[test]
+    fn test_vote_once_per_term_for_an_up_to_date_candidate() {
+        let raft = Raft::new(ADDRESS.to_string(), Wal::disabled(), RaftLog::default());
+        raft.append_entries(append(1, (0, 0), vec![entry(1, 10)], 0));
+
+        // A candidate missing the entry of term 1 is not up to date
+        assert!(!raft.vote(vote(2, "127.0.0.1:9002", 0, 0)).granted);
+        assert!(raft.vote(vote(2, "127.0.0.1:9003", 1, 1)).granted);
+        assert!(raft.vote(vote(2, "127.0.0.1:9003", 1, 1)).granted);
+        assert!(!raft.vote(vote(2, "127.0.0.1:9004", 1, 1)).granted);
+        // A new term allows a new vote
+        let res = raft.vote(vote(3, "127.0.0.1:9004", 1, 1));
+        assert!(res.granted);
+        assert_eq!(res.term, 3);
+    }
+
+    #[test]
+    fn test_replace_the_entries_in_conflict_with_the_leader() {
+        let raft = Raft::new(ADDRESS.to_string(), Wal::disabled(), RaftLog::default());
+        raft.append_entries(append(1, (0, 0), vec![entry(1, 1), entry(1, 2)], 0));
+
+        // The previous entry is missing, the leader has to send an earlier one
+        assert!(!raft.append_entries(append(2, (3, 1), vec![], 0)).success);
+
+        let res = raft.append_entries(append(2, (1, 1), vec![entry(2, 3)], 2));
+        assert!(res.success);
+        assert_eq!(res.match_index, 2);
+        let log = raft.snapshot();
+        assert_eq!(log.term, 2);
+        assert_eq!(
+            log.entries
+                .iter()
+                .map(|entry| entry.term)
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        assert_eq!(raft.lock().leader, Some(LEADER.to_string()));
+
+        // An old leader is rejected
+        assert!(!raft.append_entries(append(1, (2, 2), vec![], 2)).success);
+
+        let mut applied = vec![];
+        raft.apply_committed(|transaction| {
+            applied.push(transaction.points);
+            Ok(())
+        });
+        assert_eq!(applied, vec![1, 3]);
+    }
+
+    #[test]
+    fn test_commit_the_entries_stored_by_a_majority() {
+        let peers = vec!["127.0.0.1:9001".to_string(), "127.0.0.1:9002".to_string()];
+        let raft = Raft::new(ADDRESS.to_string(), Wal::disabled(), RaftLog::default());
+        {
+            let mut state = raft.lock();
+            state.log.term = 1;
+            raft.lead(&mut state, &peers);
+        }
+        let transaction = fill(10);
+        raft.propose(transaction.clone()).unwrap();
+
+        let mut state = raft.lock();
+        Raft::advance_commit(&mut state, &peers);
+        assert_eq!(state.commit_index, 0);
+        state.match_index.insert(peers[0].clone(), 2);
+        Raft::advance_commit(&mut state, &peers);
+        assert_eq!(state.commit_index, 2);
+        drop(state);
+
+        raft.apply_committed(|_| Err(Response::InsufficientPoints));
+        assert_eq!(
+            raft.wait_applied(&transaction, Duration::ZERO),
+            Some(Err(Response::InsufficientPoints))
+        );
+    }
+}
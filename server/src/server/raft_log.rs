@@ -0,0 +1,55 @@
+use serde::{Deserialize, Serialize};
+
+use super::transaction::Transaction;
+
+/// Entry of the replicated log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    /// Term of the leader that appended the entry.
+    pub term: u64,
+    /// Transaction to apply to the points once the entry is committed.
+    /// A new leader appends an entry without one to commit the entries of the previous terms.
+    pub transaction: Option<Transaction>,
+}
+
+/// State of the replication that must survive a restart: the current term,
+/// the server voted for in it and the replicated log.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RaftLog {
+    pub term: u64,
+    pub voted_for: Option<String>,
+    pub entries: Vec<LogEntry>,
+}
+
+impl RaftLog {
+    /// Index of the last entry, starting at 1. An empty log has index 0.
+    pub fn last_index(&self) -> u64 {
+        self.entries.len() as u64
+    }
+
+    /// Term of the entry at the given index, 0 for the index before the first entry.
+    pub fn term_at(&self, index: u64) -> Option<u64> {
+        if index == 0 {
+            return Some(0);
+        }
+        self.entries.get(index as usize - 1).map(|entry| entry.term)
+    }
+
+    pub fn last_term(&self) -> u64 {
+        self.term_at(self.last_index()).unwrap_or(0)
+    }
+
+    /// Replaces the entries from the given index on with the given ones.
+    pub fn replace_from(&mut self, index: u64, entries: Vec<LogEntry>) {
+        self.entries.truncate(index as usize - 1);
+        self.entries.extend(entries);
+    }
+
+    /// Returns the entries from the given index on.
+    pub fn entries_from(&self, index: u64) -> Vec<LogEntry> {
+        self.entries
+            .get(index as usize - 1..)
+            .map(|entries| entries.to_vec())
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,230 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    mem::{discriminant, Discriminant},
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use points::{Message, Response};
+use tracing::{debug, warn};
+
+/// Amount of finished orders remembered before the oldest ones are forgotten.
+const CAPACITY: usize = 1024;
+/// Longest a retry waits for the original message to finish,
+/// so the coffee maker, which waits for 1 second, gets an answer.
+const RETRY_WAIT: Duration = Duration::from_millis(800);
+
+/// A message is identified by the id of its order and its kind,
+/// as the lock and the commit of an order share the same id.
+type OrderKey = (u64, Discriminant<Message>);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    InProgress,
+    Done(Response),
+}
+
+#[derive(Debug, Default)]
+struct Table {
+    outcomes: HashMap<OrderKey, Outcome>,
+    finished: VecDeque<OrderKey>,
+}
+
+/// Remembers the outcome of the recent client messages,
+/// so a message retried by a coffee maker is answered with the original result
+/// instead of being applied twice.
+#[derive(Debug, Default)]
+pub struct RecentOrders {
+    table: Mutex<Table>,
+    finished: Condvar,
+}
+
+impl RecentOrders {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    /// Registers the given message as in progress, until the returned guard finishes it.
+    /// If the message was already received, the original outcome is returned instead.
+    /// When the original message is still in progress, the thread waits for it to finish,
+    /// failing with `Timeout` if it does not finish in time.
+    /// Messages without an order id are never remembered.
+    pub fn begin(&self, msg: &Message) -> Result<InProgress<'_>, Response> {
+        let key = match Self::key(msg) {
+            Some(key) => key,
+            None => {
+                return Ok(InProgress {
+                    recent: self,
+                    key: None,
+                })
+            }
+        };
+        let deadline = Instant::now() + RETRY_WAIT;
+        let mut table = self.table.lock().expect("Could not lock recent orders");
+
+        loop {
+            match table.outcomes.get(&key) {
+                Some(Outcome::Done(response)) => {
+                    debug!("Order {} already handled: {:?}", key.0, response);
+                    return Err(*response);
+                }
+                Some(Outcome::InProgress) => {
+                    let wait = deadline.saturating_duration_since(Instant::now());
+                    if wait.is_zero() {
+                        debug!("Order {} is still in progress", key.0);
+                        return Err(Response::Timeout);
+                    }
+                    debug!("Order {} is in progress, waiting", key.0);
+                    table = self
+                        .finished
+                        .wait_timeout(table, wait)
+                        .expect("Could not lock recent orders")
+                        .0;
+                }
+                None => {
+                    table.outcomes.insert(key, Outcome::InProgress);
+                    return Ok(InProgress {
+                        recent: self,
+                        key: Some(key),
+                    });
+                }
+            }
+        }
+    }
+
+    /// Stores the outcome of a message registered with `begin`
+    /// and wakes up the retries waiting for it.
+    fn finish(&self, key: OrderKey, response: Response) {
+        let mut table = self.table.lock().expect("Could not lock recent orders");
+
+        table.outcomes.insert(key, Outcome::Done(response));
+        table.finished.push_back(key);
+        while table.finished.len() > CAPACITY {
+            if let Some(oldest) = table.finished.pop_front() {
+                table.outcomes.remove(&oldest);
+            }
+        }
+
+        self.finished.notify_all();
+    }
+
+    /// Forgets a message registered with `begin` that was not finished,
+    /// so the retries waiting for it handle it themselves.
+    fn abandon(&self, key: OrderKey) {
+        let mut table = self.table.lock().expect("Could not lock recent orders");
+        if table.outcomes.get(&key) == Some(&Outcome::InProgress) {
+            table.outcomes.remove(&key);
+        }
+        self.finished.notify_all();
+    }
+
+    fn key(msg: &Message) -> Option<OrderKey> {
+        msg.order_id().map(|id| (id, discriminant(msg)))
+    }
+}
+
+/// A message being handled, registered by `RecentOrders::begin`.
+/// If it is dropped without finishing, as when its handler panics, the message is forgotten.
+pub struct InProgress<'a> {
+    recent: &'a RecentOrders,
+    key: Option<OrderKey>,
+}
+
+impl InProgress<'_> {
+    /// Stores the outcome of the message, for its retries to get it.
+    pub fn finish(mut self, response: Response) {
+        if let Some(key) = self.key.take() {
+            self.recent.finish(key, response);
+        }
+    }
+}
+
+impl Drop for InProgress<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            warn!("Order {} was not finished, forgetting it", key.0);
+            self.recent.abandon(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use points::{Order, OrderAction};
+
+    use super::*;
+
+    #[test]
+    fn test_retried_order_gets_original_outcome() {
+        let recent = RecentOrders::new();
+        let order = Order::new(1, OrderAction::UsePoints(10)).with_id(7);
+        let lock = Message::LockOrder(order.clone());
+        let commit = Message::CommitOrder(order);
+
+        recent
+            .begin(&lock)
+            .unwrap()
+            .finish(Response::InsufficientPoints);
+
+        assert_eq!(
+            recent.begin(&lock).err(),
+            Some(Response::InsufficientPoints)
+        );
+        assert!(recent.begin(&commit).is_ok());
+    }
+
+    #[test]
+    fn test_orders_without_id_are_not_remembered() {
+        let recent = RecentOrders::new();
+        let lock = Message::LockOrder(Order::new(1, OrderAction::UsePoints(10)));
+
+        recent.begin(&lock).unwrap().finish(Response::Ok);
+        assert!(recent.begin(&lock).is_ok());
+    }
+
+    #[test]
+    fn test_retry_waits_for_order_in_progress() {
+        let recent = RecentOrders::new();
+        let lock = Message::LockOrder(Order::new(1, OrderAction::UsePoints(10)).with_id(7));
+        let original = recent.begin(&lock).unwrap();
+
+        let retry = {
+            let recent = recent.clone();
+            let lock = lock.clone();
+            thread::spawn(move || recent.begin(&lock).err())
+        };
+        thread::sleep(Duration::from_millis(50));
+        original.finish(Response::Ok);
+
+        assert_eq!(retry.join().unwrap(), Some(Response::Ok));
+    }
+
+    #[test]
+    fn test_retry_stops_waiting_for_order_in_progress() {
+        let recent = RecentOrders::new();
+        let lock = Message::LockOrder(Order::new(1, OrderAction::UsePoints(10)).with_id(7));
+        let _original = recent.begin(&lock).unwrap();
+
+        assert_eq!(recent.begin(&lock).err(), Some(Response::Timeout));
+    }
+
+    #[test]
+    fn test_order_not_finished_is_forgotten() {
+        let recent = RecentOrders::new();
+        let lock = Message::LockOrder(Order::new(1, OrderAction::UsePoints(10)).with_id(7));
+        let original = recent.begin(&lock).unwrap();
+
+        let retry = {
+            let recent = recent.clone();
+            let lock = lock.clone();
+            thread::spawn(move || recent.begin(&lock).is_ok())
+        };
+        thread::sleep(Duration::from_millis(50));
+        // As if the handler of the original message panicked
+        drop(original);
+
+        assert!(retry.join().unwrap());
+    }
+}
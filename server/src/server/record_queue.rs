@@ -0,0 +1,182 @@
+use std::{
+    collections::BTreeSet,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use points::Response;
+
+use super::{hlc::Timestamp, transaction::Transaction};
+
+/// Transactions waiting for their turn to use a record of points.
+/// The transaction holding the record goes first, then the waiting ones from the oldest to the youngest.
+/// Cloning the queue shares it.
+#[derive(Debug, Clone, Default)]
+pub struct RecordQueue(Arc<Shared>);
+
+#[derive(Debug, Default)]
+struct Shared {
+    state: Mutex<QueueState>,
+    /// Notified when the record is released, or the front of the queue changes.
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    holder: Option<Transaction>,
+    /// Waiting transactions, ordered as `Transaction::older_than` does.
+    waiting: BTreeSet<(Timestamp, String)>,
+}
+
+/// Turn of a transaction to use a record, released when dropped.
+#[derive(Debug)]
+pub struct Turn {
+    queue: RecordQueue,
+    transaction: Transaction,
+    /// How long the transaction waited for its turn.
+    pub waited: Duration,
+    /// Amount of transactions ahead of this one when it started waiting.
+    pub ahead: usize,
+}
+
+impl RecordQueue {
+    fn state(&self) -> MutexGuard<'_, QueueState> {
+        self.0.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Waits until the record is free and no older transaction is waiting for it, then takes it.
+    /// Fails with `Timeout` if the record stays busy for longer than the given timeout.
+    pub fn wait_turn(
+        &self,
+        transaction: &Transaction,
+        timeout: Duration,
+    ) -> Result<Turn, Response> {
+        let key = (transaction.timestamp, transaction.coordinator.clone());
+        let started = Instant::now();
+        let mut state = self.state();
+        let ahead = state.waiting.len() + state.holder.iter().count();
+        state.waiting.insert(key.clone());
+
+        loop {
+            if state.holder.is_none() && state.waiting.first() == Some(&key) {
+                state.waiting.remove(&key);
+                state.holder = Some(transaction.clone());
+                return Ok(Turn {
+                    queue: self.clone(),
+                    transaction: transaction.clone(),
+                    waited: started.elapsed(),
+                    ahead,
+                });
+            }
+            let left = match timeout.checked_sub(started.elapsed()) {
+                Some(left) if !left.is_zero() => left,
+                _ => {
+                    state.waiting.remove(&key);
+                    // The next one may be at the front now
+                    self.0.changed.notify_all();
+                    return Err(Response::Timeout);
+                }
+            };
+            state = self
+                .0
+                .changed
+                .wait_timeout(state, left)
+                .unwrap_or_else(|e| e.into_inner())
+                .0;
+        }
+    }
+
+    /// Transaction holding the record, if any.
+    pub fn holder(&self) -> Option<Transaction> {
+        self.state().holder.clone()
+    }
+
+    /// Amount of transactions waiting for the record.
+    pub fn depth(&self) -> usize {
+        self.state().waiting.len()
+    }
+
+    fn release(&self, transaction: &Transaction) {
+        let mut state = self.state();
+        if let Some(holder) = &state.holder {
+            if holder.same_as(transaction) {
+                state.holder = None;
+                self.0.changed.notify_all();
+            }
+        }
+    }
+}
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        self.queue.release(&self.transaction);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use points::{Message, Order, OrderAction};
+
+    use super::*;
+
+    fn lock(coordinator: &str) -> Transaction {
+        let order = Order::new(1, OrderAction::UsePoints(10));
+        Transaction::new(coordinator.to_string(), &Message::LockOrder(order)).unwrap()
+    }
+
+    #[test]
+    fn test_turns_go_from_the_oldest_transaction() {
+        let queue = RecordQueue::default();
+        let first = lock("127.0.0.1:9000");
+        let older = lock("127.0.0.1:9001");
+        let younger = lock("127.0.0.1:9002");
+
+        let turn = queue.wait_turn(&first, Duration::ZERO).unwrap();
+        assert!(queue.holder().unwrap().same_as(&first));
+
+        // Both wait for the first one, the younger arrives first but goes last
+        let waiters: Vec<_> = [younger, older]
+            .into_iter()
+            .map(|transaction| {
+                let queue = queue.clone();
+                let waiter = thread::spawn(move || {
+                    let turn = queue.wait_turn(&transaction, Duration::from_secs(5));
+                    let ahead = turn.as_ref().map(|turn| turn.ahead).unwrap_or_default();
+                    thread::sleep(Duration::from_millis(20));
+                    (transaction, ahead, Instant::now())
+                });
+                thread::sleep(Duration::from_millis(20));
+                waiter
+            })
+            .collect();
+        assert_eq!(queue.depth(), 2);
+        drop(turn);
+
+        let mut served: Vec<_> = waiters
+            .into_iter()
+            .map(|waiter| waiter.join().unwrap())
+            .collect();
+        served.sort_by_key(|(_, _, at)| *at);
+        assert_eq!(served[0].0.coordinator, "127.0.0.1:9001");
+        assert_eq!(served[1].0.coordinator, "127.0.0.1:9002");
+        assert_eq!(served[0].1, 2);
+        assert!(queue.holder().is_none());
+    }
+
+    #[test]
+    fn test_waits_are_bounded() {
+        let queue = RecordQueue::default();
+        let holder = lock("127.0.0.1:9000");
+        let waiting = lock("127.0.0.1:9001");
+
+        let _turn = queue.wait_turn(&holder, Duration::ZERO).unwrap();
+        let started = Instant::now();
+        let res = queue.wait_turn(&waiting, Duration::from_millis(50));
+
+        assert_eq!(res.unwrap_err(), Response::Timeout);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(queue.depth(), 0);
+    }
+}
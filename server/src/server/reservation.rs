@@ -0,0 +1,73 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::transaction::Transaction;
+
+/// Points locked by a transaction, until they are freed or consumed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reservation {
+    pub points: u64,
+    /// Server that coordinated the lock, in charge of releasing it when the lease expires.
+    pub coordinator: String,
+    /// Timestamp of the lock, in milliseconds since the epoch.
+    pub locked_at: u128,
+    /// Set while a free or a consume of the reservation is in progress.
+    #[serde(skip)]
+    pub settling: bool,
+}
+
+impl Reservation {
+    /// Creates the reservation for the given lock transaction.
+    pub fn new(lock: &Transaction) -> Self {
+        Reservation {
+            points: lock.points,
+            coordinator: lock.coordinator.clone(),
+            locked_at: lock.timestamp.physical as u128,
+            settling: false,
+        }
+    }
+
+    /// Returns true if the lease of the reservation should be released by the given server.
+    /// The coordinator of the lock releases it once the lease expires. Any other server
+    /// releases it after twice the lease, in case the coordinator never came back.
+    pub fn should_release(&self, server: &str, now: u128, lease: Duration) -> bool {
+        if self.settling {
+            return false;
+        }
+        let lease = lease.as_millis();
+        let age = now.saturating_sub(self.locked_at);
+        if self.coordinator == server {
+            age >= lease
+        } else {
+            age >= 2 * lease
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_should_release() {
+        let reservation = Reservation {
+            points: 10,
+            coordinator: "127.0.0.1:9000".to_string(),
+            locked_at: 1_000,
+            settling: false,
+        };
+        let lease = Duration::from_millis(500);
+
+        assert!(!reservation.should_release("127.0.0.1:9000", 1_400, lease));
+        assert!(reservation.should_release("127.0.0.1:9000", 1_500, lease));
+        assert!(!reservation.should_release("127.0.0.1:9001", 1_500, lease));
+        assert!(reservation.should_release("127.0.0.1:9001", 2_000, lease));
+
+        let settling = Reservation {
+            settling: true,
+            ..reservation
+        };
+        assert!(!settling.should_release("127.0.0.1:9000", 2_000, lease));
+    }
+}
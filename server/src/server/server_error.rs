@@ -0,0 +1,40 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Reason why another server did not answer a message.
+/// It is sent as the payload of an error response, except for `Unreachable`, found by the sender.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum ServerError {
+    /// The server is offline and does not handle the messages it receives.
+    Offline,
+    /// The message could not be read or parsed.
+    Malformed(String),
+    /// The server could not handle the message.
+    Failed(String),
+    /// No response was received: the server could not be reached, it timed out or the link dropped.
+    Unreachable(String),
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::Offline => write!(f, "Server is offline"),
+            ServerError::Malformed(e) => write!(f, "Malformed message: {}", e),
+            ServerError::Failed(e) => write!(f, "{}", e),
+            ServerError::Unreachable(e) => write!(f, "No response: {}", e),
+        }
+    }
+}
+
+impl From<String> for ServerError {
+    fn from(error: String) -> Self {
+        ServerError::Failed(error)
+    }
+}
+
+impl From<ServerError> for String {
+    fn from(error: ServerError) -> Self {
+        error.to_string()
+    }
+}
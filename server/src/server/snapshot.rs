@@ -0,0 +1,73 @@
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::{
+    decision::Decision, point_storage::PointMap, raft_log::RaftLog, transaction::Transaction,
+};
+
+/// State of the points of a server: the accounts, the pending transactions,
+/// the decisions of the coordinated transactions that some participant may not know,
+/// the transactions whose decision this server does not know and the replicated log.
+/// A snapshot taken by a checkpoint includes every log entry before `lsn`.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Sequence number of the first log entry that may not be included in the snapshot.
+    pub lsn: u64,
+    pub points: PointMap,
+    pub pending: Vec<Transaction>,
+    #[serde(default)]
+    pub decisions: Vec<Decision>,
+    #[serde(default)]
+    pub in_doubt: Vec<Transaction>,
+    /// Amount of transactions applied, including the ones of the states adopted from other servers.
+    #[serde(default)]
+    pub committed: u64,
+    /// Replicated log of the transactions, only used in the Raft replication mode.
+    #[serde(default)]
+    pub raft: RaftLog,
+}
+
+impl Snapshot {
+    pub fn new(
+        points: PointMap,
+        pending: Vec<Transaction>,
+        decisions: Vec<Decision>,
+        in_doubt: Vec<Transaction>,
+        committed: u64,
+        raft: RaftLog,
+    ) -> Self {
+        Snapshot {
+            lsn: 0,
+            points,
+            pending,
+            decisions,
+            in_doubt,
+            committed,
+            raft,
+        }
+    }
+
+    /// Reads a snapshot from the given file.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| e.to_string())?;
+        serde_json::from_reader(BufReader::new(file)).map_err(|e| e.to_string())
+    }
+
+    /// Writes the snapshot to the given file.
+    /// It is written to a temporary file first and then renamed,
+    /// so a crash never leaves a partially written snapshot in its place.
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let tmp = path.with_extension("tmp");
+        let file = File::create(&tmp).map_err(|e| e.to_string())?;
+        let mut writer = BufWriter::new(file);
+        serde_json::to_writer(&mut writer, self).map_err(|e| e.to_string())?;
+        writer.flush().map_err(|e| e.to_string())?;
+        writer.get_ref().sync_all().map_err(|e| e.to_string())?;
+        fs::rename(&tmp, path).map_err(|e| e.to_string())
+    }
+}
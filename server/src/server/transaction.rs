@@ -1,6 +1,8 @@
 use std::{
-    io::{Read, Write},
-    net::TcpStream,
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    io::Write,
+    sync::atomic::{AtomicU64, Ordering},
     time::Duration,
 };
 
@@ -8,7 +10,12 @@ use points::{Message, OrderAction};
 use serde::{Deserialize, Serialize};
 use tracing::debug;
 
-use super::message::{write_message_to, TRANSACTION};
+use super::{
+    hlc::{Timestamp, CLOCK},
+    link::Exchange,
+    message::{receive_response, write_message_to, AbortReason, PrepareResponse, TRANSACTION},
+    server_error::ServerError,
+};
 
 pub const PREPARE_TIMEOUT: Duration = Duration::from_millis(1000);
 pub const COMMIT_TIMEOUT: Duration = Duration::from_millis(3000);
@@ -32,15 +39,25 @@ pub enum TransactionAction {
 pub enum TxOk {
     Finalized,
     Pending,
+    /// Points added while offline, to be earned by the record and merged on reconnect.
+    Offline,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction {
     pub coordinator: String,
-    pub timestamp: u128,
-    pub client_id: u16,
+    /// Timestamp of the hybrid logical clock of the coordinator when it created the transaction.
+    pub timestamp: Timestamp,
+    pub client_id: u64,
     pub action: TransactionAction,
-    pub points: usize,
+    pub points: u64,
+    /// Reservation created by a lock, or settled by a free or a consume.
+    #[serde(default)]
+    pub reservation: Option<u64>,
+    /// Times the coordinator retried the transaction after it died by wait-die.
+    /// Each attempt is decided on its own, but all of them keep the timestamp, and so the priority.
+    #[serde(default)]
+    pub attempt: u32,
 }
 
 impl Transaction {
@@ -90,28 +107,67 @@ impl Transaction {
                     Ok(TransactionAction::Consume)
                 }
             },
+            Message::QueryBalance(_, _) | Message::Identify(_) => err,
         }?;
 
-        let order = msg.order();
+        let order = msg
+            .order()
+            .ok_or_else(|| "Invalid message for transaction".to_string())?;
         let client_id = order.client_id;
         let points = order.action.points();
 
-        let timestamp = generate_timestamp();
+        let timestamp = CLOCK.now();
         debug!(
             "Coordinator '{}' creating new transaction with timestamp {}.",
             coordinator, timestamp
         );
+        let reservation = match action {
+            TransactionAction::Lock => {
+                Some(generate_reservation(&coordinator, timestamp, client_id))
+            }
+            TransactionAction::Add => None,
+            TransactionAction::Free | TransactionAction::Consume => order.reservation,
+        };
         Ok(Transaction {
             coordinator,
             timestamp,
             client_id,
             action,
             points,
+            reservation,
+            attempt: 0,
         })
     }
 
+    /// Returns the next attempt of the transaction, with the same timestamp.
+    pub fn retry(&self) -> Transaction {
+        Transaction {
+            attempt: self.attempt + 1,
+            ..self.clone()
+        }
+    }
+
+    /// Returns true if the transaction settles a reservation (a free or a consume).
+    pub fn settles_reservation(&self) -> bool {
+        matches!(
+            self.action,
+            TransactionAction::Free | TransactionAction::Consume
+        )
+    }
+
+    /// Returns true if both are the same attempt of the same transaction,
+    /// created by the same coordinator at the same time for the same client.
+    pub fn same_as(&self, other: &Transaction) -> bool {
+        self.coordinator == other.coordinator
+            && self.timestamp == other.timestamp
+            && self.client_id == other.client_id
+            && self.attempt == other.attempt
+    }
+
     /// Compares the given transaction's timestamp with this transaction's timestamp.
     /// Returns true if the given transaction's timestamp is greater than this transaction's timestamp.
+    /// As the timestamps come from hybrid logical clocks, a transaction created after the coordinator
+    /// heard of another one is always younger, no matter how skewed the clocks of the machines are.
     /// In case of a tie, the transaction with the lower coordinator is considered greater.
     pub fn older_than(&self, other: &Transaction) -> bool {
         if self.timestamp == other.timestamp {
@@ -122,48 +178,82 @@ impl Transaction {
     }
 
     /// Sends a transaction message to the given server address.
+    /// Returns the vote of the server, why it did not approve the transaction, and the exchange with it.
     pub fn prepare(
         transaction: &Transaction,
         server: &String,
-    ) -> Result<(TransactionState, TcpStream), String> {
+    ) -> Result<(TransactionState, Option<AbortReason>, Exchange), String> {
         let mut stream = write_message_to(TRANSACTION, transaction, server)?;
         stream
             .set_read_timeout(Some(PREPARE_TIMEOUT))
             .map_err(|e| e.to_string())?;
 
-        let mut buf = [0u8; 1];
-        let read = stream.read_exact(&mut buf);
-        if read.is_err() {
-            return Ok((TransactionState::Timeout, stream));
-        }
-        if buf[0] == TransactionState::Proceed as u8 {
-            Ok((TransactionState::Proceed, stream))
-        } else {
-            Ok((TransactionState::Abort, stream))
-        }
+        let (state, reason) = match receive_response(&mut stream) {
+            Ok(res) => match serde_json::from_slice(&res) {
+                Ok(PrepareResponse { proceed: true, .. }) => (TransactionState::Proceed, None),
+                Ok(PrepareResponse { reason, .. }) => (TransactionState::Abort, reason),
+                Err(_) => (TransactionState::Abort, None),
+            },
+            // An offline participant is taken as one that did not answer
+            Err(ServerError::Unreachable(_)) | Err(ServerError::Offline) => {
+                (TransactionState::Timeout, None)
+            }
+            Err(e) => {
+                debug!("{} refused to prepare the transaction: {}", server, e);
+                (TransactionState::Abort, None)
+            }
+        };
+        Ok((state, reason, stream))
     }
 
-    /// Sends a transaction state message to the given stream.
-    pub fn finalize(stream: &mut TcpStream, state: TransactionState) -> Result<(), String> {
-        let addr = stream.local_addr().unwrap();
-        match state {
-            TransactionState::Abort => debug!("Sending message ABORT through socket {}", addr),
-            TransactionState::Proceed => debug!("Sending message COMMIT through socket {}", addr),
-            TransactionState::Timeout => todo!(),
-            TransactionState::Disconnected => todo!(),
-        }
+    /// Sends a transaction state message over the given exchange.
+    /// A transaction that was not decided is not committed,
+    /// so a participant that prepared it after the coordinator stopped waiting is told to abort.
+    pub fn finalize(stream: &mut Exchange, state: TransactionState) -> Result<(), String> {
+        let addr = stream.peer().to_string();
+        let state = match state {
+            TransactionState::Proceed => {
+                debug!("Sending message COMMIT to {}", addr);
+                TransactionState::Proceed
+            }
+            TransactionState::Abort => {
+                debug!("Sending message ABORT to {}", addr);
+                TransactionState::Abort
+            }
+            TransactionState::Timeout | TransactionState::Disconnected => {
+                debug!(
+                    "Sending message ABORT to {}, the transaction was not decided",
+                    addr
+                );
+                TransactionState::Abort
+            }
+        };
 
         stream.write_all(&[state as u8]).map_err(|e| e.to_string())
     }
 }
 
-fn generate_timestamp() -> u128 {
+pub fn generate_timestamp() -> u128 {
     use std::time::{SystemTime, UNIX_EPOCH};
     let now = SystemTime::now();
     let since_the_epoch = now.duration_since(UNIX_EPOCH).expect("Time went backwards");
     since_the_epoch.as_millis()
 }
 
+/// Generates the id of the reservation created by a lock.
+/// The coordinator is part of the hash so two servers never generate the same id,
+/// and the counter tells apart locks created in the same millisecond.
+fn generate_reservation(coordinator: &str, timestamp: Timestamp, client_id: u64) -> u64 {
+    static LOCKS: AtomicU64 = AtomicU64::new(0);
+
+    let mut hasher = DefaultHasher::new();
+    coordinator.hash(&mut hasher);
+    timestamp.hash(&mut hasher);
+    client_id.hash(&mut hasher);
+    LOCKS.fetch_add(1, Ordering::Relaxed).hash(&mut hasher);
+    hasher.finish()
+}
+
 #[cfg(test)]
 mod tests {
     use points::Order;
@@ -180,7 +270,29 @@ mod tests {
         let other_transaction =
             Transaction::new("127.0.0.1:9002".to_string(), &other_message).unwrap();
 
-        assert_eq!(true, transaction.older_than(&other_transaction));
+        assert!(transaction.older_than(&other_transaction));
+    }
+
+    #[test]
+    fn test_transaction_reservations() {
+        let order = Order::new(1, OrderAction::UsePoints(123));
+        let lock = Transaction::new(
+            "127.0.0.1:9001".to_string(),
+            &Message::LockOrder(order.clone()),
+        )
+        .unwrap();
+        let other_lock = Transaction::new(
+            "127.0.0.1:9001".to_string(),
+            &Message::LockOrder(order.clone()),
+        )
+        .unwrap();
+        assert!(lock.reservation.is_some());
+        assert_ne!(lock.reservation, other_lock.reservation);
+
+        let commit = Message::CommitOrder(order.with_reservation(7));
+        let consume = Transaction::new("127.0.0.1:9001".to_string(), &commit).unwrap();
+        assert_eq!(consume.reservation, Some(7));
+        assert!(consume.settles_reservation());
     }
 
     #[test]
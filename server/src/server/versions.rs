@@ -0,0 +1,61 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use super::{message::SyncMark, transaction::generate_timestamp};
+
+/// Source of the versions stamped on the records when they change.
+/// Versions only increase during a run of the server, the epoch tells runs apart.
+/// It also keeps how fresh the state of the server is, to compare it with the other servers.
+#[derive(Debug)]
+pub struct Versions {
+    epoch: u64,
+    last: AtomicU64,
+    /// Amount of transactions applied, by this server or by the ones whose state it adopted.
+    committed: AtomicU64,
+}
+
+impl Versions {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Versions {
+            epoch: generate_timestamp() as u64,
+            last: AtomicU64::new(0),
+            committed: AtomicU64::new(0),
+        })
+    }
+
+    /// Returns a new version, greater than every previous one.
+    pub fn next(&self) -> u64 {
+        self.last.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Records that a transaction was applied, returning the amount of transactions applied with it.
+    pub fn commit(&self) -> u64 {
+        self.committed.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Takes back a transaction recorded as applied that was not applied after all.
+    pub fn revert_commit(&self) {
+        self.committed.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Takes the amount of transactions applied in an adopted or recovered state, if greater.
+    pub fn adopt(&self, committed: u64) {
+        self.committed.fetch_max(committed, Ordering::SeqCst);
+    }
+
+    /// Returns the amount of transactions applied.
+    /// The server that applied the most has the freshest state, whatever the clocks of the servers say.
+    pub fn committed(&self) -> u64 {
+        self.committed.load(Ordering::SeqCst)
+    }
+
+    /// Returns the mark of the changes made up to now.
+    pub fn mark(&self) -> SyncMark {
+        SyncMark {
+            epoch: self.epoch,
+            version: self.last.load(Ordering::SeqCst),
+        }
+    }
+}
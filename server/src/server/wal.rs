@@ -0,0 +1,736 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::{Arc, Mutex},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, warn};
+
+use super::{
+    decision::Decision,
+    point_record::{Points, SafePointRecord},
+    point_storage::PointMap,
+    raft_log::LogEntry,
+    snapshot::Snapshot,
+    transaction::Transaction,
+};
+
+/// Entry of the write-ahead log.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum WalEntry {
+    /// A transaction is about to be applied to the points of its client, leaving the given ones,
+    /// along with the amount of transactions the server applied with it.
+    /// Replaying it sets the points and the amount instead of applying the transaction again,
+    /// so a transaction applied to a record replaced by a sync, or already in the snapshot, is not counted twice.
+    Apply {
+        transaction: Transaction,
+        points: Points,
+        #[serde(default)]
+        committed: u64,
+    },
+    /// A transaction was added to the pending queue.
+    Enqueue(Transaction),
+    /// A pending transaction left the queue, it was applied or discarded.
+    Dequeue(Transaction),
+    /// The points were replaced by the ones synced from another server.
+    Reset(PointMap),
+    /// Some records were replaced by the ones that changed in another server.
+    Merge(PointMap),
+    /// The state synced from another server was adopted, with the amount of transactions it applied.
+    Adopt(u64),
+    /// This server decided the outcome of a transaction it coordinates, before sending it.
+    /// A commit also keeps the points of the client and the amount of transactions applied, as `Apply` does.
+    Decide {
+        decision: Decision,
+        points: Option<Points>,
+        #[serde(default)]
+        committed: u64,
+    },
+    /// Every participant of a transaction coordinated by this server received its decision.
+    Delivered(Transaction),
+    /// This server did not receive the decision of a transaction it voted to commit.
+    InDoubt(Transaction),
+    /// The decision of a transaction in doubt was received and applied.
+    Resolved(Transaction),
+    /// The current term of the replicated log, and the server voted for in it.
+    RaftTerm {
+        term: u64,
+        voted_for: Option<String>,
+    },
+    /// Entries of the replicated log that replace the ones from the given index on.
+    RaftAppend { index: u64, entries: Vec<LogEntry> },
+}
+
+/// Line of the log: an entry and its sequence number.
+#[derive(Serialize)]
+struct RecordRef<'a> {
+    lsn: u64,
+    entry: &'a WalEntry,
+}
+
+#[derive(Deserialize)]
+struct Record {
+    lsn: u64,
+    entry: WalEntry,
+}
+
+/// Durable append-only log of the changes to the points of a server, one JSON entry per line.
+/// Checkpoints write a snapshot of the points and drop the entries it includes from the log.
+/// A disabled log writes nothing, for servers started without a data directory.
+#[derive(Debug)]
+pub struct Wal {
+    log: Option<Log>,
+}
+
+#[derive(Debug)]
+struct Log {
+    path: PathBuf,
+    snapshot_path: PathBuf,
+    previous_snapshot_path: PathBuf,
+    file: Mutex<LogFile>,
+}
+
+#[derive(Debug)]
+struct LogFile {
+    file: File,
+    next_lsn: u64,
+    /// Sequence number of the latest snapshot.
+    snapshot_lsn: u64,
+}
+
+impl Wal {
+    pub fn disabled() -> Arc<Self> {
+        Arc::new(Wal { log: None })
+    }
+
+    /// Log on a full disk, every entry fails to be written.
+    #[cfg(test)]
+    pub fn failing() -> Arc<Self> {
+        let path = PathBuf::from("/dev/full");
+        let file = OpenOptions::new().append(true).open(&path).unwrap();
+        Arc::new(Wal {
+            log: Some(Log {
+                snapshot_path: path.clone(),
+                previous_snapshot_path: path.clone(),
+                path,
+                file: Mutex::new(LogFile {
+                    file,
+                    next_lsn: 0,
+                    snapshot_lsn: 0,
+                }),
+            }),
+        })
+    }
+
+    /// Opens the log of the server with the given address inside the data directory,
+    /// and rebuilds the state it recorded from the latest valid snapshot and the entries after it.
+    pub fn open(data_dir: &Path, address: &str) -> Result<(Arc<Self>, Snapshot), String> {
+        fs::create_dir_all(data_dir).map_err(|e| e.to_string())?;
+        let name: String = address
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        let path = data_dir.join(format!("{}.wal", name));
+        let snapshot_path = data_dir.join(format!("{}.snapshot", name));
+        let previous_snapshot_path = data_dir.join(format!("{}.prev.snapshot", name));
+
+        let mut recovered = Self::load_snapshot(&snapshot_path, &previous_snapshot_path)?;
+        let snapshot_lsn = recovered.lsn;
+        let next_lsn = if path.exists() {
+            Self::recover(&path, &mut recovered)?
+        } else {
+            snapshot_lsn
+        };
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| e.to_string())?;
+        info!("Write-ahead log at {}", path.display());
+
+        let log = Log {
+            path,
+            snapshot_path,
+            previous_snapshot_path,
+            file: Mutex::new(LogFile {
+                file,
+                next_lsn,
+                snapshot_lsn,
+            }),
+        };
+        Ok((Arc::new(Wal { log: Some(log) }), recovered))
+    }
+
+    /// Loads the latest snapshot that can be read.
+    /// Without snapshots, the state is rebuilt from the whole log.
+    fn load_snapshot(latest: &Path, previous: &Path) -> Result<Snapshot, String> {
+        let mut found = false;
+        for path in [latest, previous] {
+            if !path.exists() {
+                continue;
+            }
+            found = true;
+            match Snapshot::load(path) {
+                Ok(snapshot) => {
+                    info!(
+                        "Loaded snapshot {} at log entry {}",
+                        path.display(),
+                        snapshot.lsn
+                    );
+                    return Ok(snapshot);
+                }
+                Err(e) => warn!("Ignoring invalid snapshot {}: {}", path.display(), e),
+            }
+        }
+        if found {
+            // The log was compacted behind the snapshots, it is not enough to rebuild the state
+            return Err("No valid snapshot to recover from".to_string());
+        }
+        Ok(Snapshot::default())
+    }
+
+    /// Appends an entry to the log and waits for it to reach the disk.
+    /// Fails if it could not be written, so the change it records must not be made.
+    pub fn append(&self, entry: &WalEntry) -> Result<(), String> {
+        let log = match &self.log {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+        log.file
+            .lock()
+            .map_err(|_| "Failed to lock log".to_string())
+            .and_then(|mut log_file| {
+                let record = RecordRef {
+                    lsn: log_file.next_lsn,
+                    entry,
+                };
+                let line = serde_json::to_string(&record).map_err(|e| e.to_string())?;
+                log_file.next_lsn += 1;
+                writeln!(log_file.file, "{}", line).map_err(|e| e.to_string())?;
+                log_file.file.sync_data().map_err(|e| e.to_string())
+            })
+            .map_err(|e| format!("Failed to write to the log: {}", e))
+    }
+
+    /// Appends an entry for a change this server can not refuse anymore,
+    /// such as the decision of a transaction it voted for.
+    /// Without it the log would recover a different state, so the server stops if it could not be written.
+    pub fn append_or_stop(&self, entry: &WalEntry) {
+        if let Err(e) = self.append(entry) {
+            Self::stop(&e);
+        }
+    }
+
+    /// Stops the server after failing to write a change it can not refuse to the log.
+    pub fn stop(error: &str) -> ! {
+        error!("{}. Stopping the server", error);
+        process::exit(1);
+    }
+
+    /// Writes a snapshot of the state given by `capture` and compacts the log.
+    /// Every entry appended before the checkpoint started must be included in the captured state.
+    /// The previous snapshot is kept, along with the log entries after it,
+    /// in case the new one can not be read on startup.
+    pub fn checkpoint(
+        &self,
+        capture: impl FnOnce() -> Result<Snapshot, String>,
+    ) -> Result<(), String> {
+        let log = match &self.log {
+            Some(log) => log,
+            None => return Ok(()),
+        };
+        let lsn = log.file.lock().map_err(|_| "Failed to lock log")?.next_lsn;
+
+        let mut snapshot = capture()?;
+        snapshot.lsn = lsn;
+        if log.snapshot_path.exists() {
+            fs::rename(&log.snapshot_path, &log.previous_snapshot_path)
+                .map_err(|e| e.to_string())?;
+        }
+        snapshot.save(&log.snapshot_path)?;
+
+        let mut log_file = log.file.lock().map_err(|_| "Failed to lock log")?;
+        let keep_from = log_file.snapshot_lsn;
+        log_file.snapshot_lsn = lsn;
+        let dropped = Self::compact(log, &mut log_file, keep_from)?;
+        debug!(
+            "Checkpoint at log entry {}, dropped {} log entries",
+            lsn, dropped
+        );
+        Ok(())
+    }
+
+    /// Rewrites the log without the entries before the given sequence number.
+    /// Returns the amount of dropped entries.
+    fn compact(log: &Log, log_file: &mut LogFile, keep_from: u64) -> Result<usize, String> {
+        let file = File::open(&log.path).map_err(|e| e.to_string())?;
+        let tmp = log.path.with_extension("wal.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp).map_err(|e| e.to_string())?);
+        let mut dropped = 0;
+
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|e| e.to_string())?;
+            match serde_json::from_str::<Record>(&line) {
+                Ok(record) if record.lsn >= keep_from => {
+                    writeln!(writer, "{}", line).map_err(|e| e.to_string())?;
+                }
+                _ => dropped += 1,
+            }
+        }
+        writer.flush().map_err(|e| e.to_string())?;
+        writer.get_ref().sync_all().map_err(|e| e.to_string())?;
+        fs::rename(&tmp, &log.path).map_err(|e| e.to_string())?;
+
+        log_file.file = OpenOptions::new()
+            .append(true)
+            .open(&log.path)
+            .map_err(|e| e.to_string())?;
+        Ok(dropped)
+    }
+
+    /// Replays the log entries not included in the snapshot, rebuilding the points and the pending queue.
+    /// A line cut by a crash is ignored.
+    /// Returns the sequence number for the next entry.
+    fn recover(path: &Path, state: &mut Snapshot) -> Result<u64, String> {
+        let file = File::open(path).map_err(|e| e.to_string())?;
+        let mut next_lsn = state.lsn;
+        let mut entries = 0;
+
+        for (n, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| e.to_string())?;
+            let record: Record = match serde_json::from_str(&line) {
+                Ok(record) => record,
+                Err(e) => {
+                    warn!("Ignoring invalid log entry at line {}: {}", n + 1, e);
+                    continue;
+                }
+            };
+            next_lsn = next_lsn.max(record.lsn + 1);
+            if record.lsn < state.lsn {
+                continue;
+            }
+            record.entry.replay(state)?;
+            entries += 1;
+        }
+
+        info!(
+            "Recovered {} log entries: {} clients, {} pending transactions",
+            entries,
+            state.points.len(),
+            state.pending.len()
+        );
+        Ok(next_lsn)
+    }
+}
+
+impl WalEntry {
+    fn replay(self, state: &mut Snapshot) -> Result<(), String> {
+        match self {
+            WalEntry::Apply {
+                transaction,
+                points,
+                committed,
+            } => Self::apply(state, &transaction, points, committed)?,
+            WalEntry::Enqueue(transaction) => {
+                // A transaction enqueued again goes to the back of the queue
+                state.pending.retain(|tx| !tx.same_as(&transaction));
+                state.pending.push(transaction);
+            }
+            WalEntry::Dequeue(transaction) => {
+                state.pending.retain(|tx| !tx.same_as(&transaction));
+            }
+            WalEntry::Reset(points) => {
+                state.points = points;
+            }
+            WalEntry::Merge(points) => {
+                state.points.extend(points);
+            }
+            WalEntry::Adopt(committed) => {
+                state.committed = state.committed.max(committed);
+            }
+            // An entry appended while a checkpoint captured the state may be in the snapshot already
+            WalEntry::Decide { decision, .. }
+                if state
+                    .decisions
+                    .iter()
+                    .any(|known| known.transaction.same_as(&decision.transaction)) => {}
+            WalEntry::Decide {
+                decision,
+                points,
+                committed,
+            } => {
+                if let Some(points) = points {
+                    Self::apply(state, &decision.transaction, points, committed)?;
+                }
+                state.decisions.push(decision);
+            }
+            WalEntry::Delivered(transaction) => {
+                state
+                    .decisions
+                    .retain(|decision| !decision.transaction.same_as(&transaction));
+            }
+            WalEntry::InDoubt(transaction) => {
+                state.in_doubt.push(transaction);
+            }
+            WalEntry::Resolved(transaction) => {
+                state.in_doubt.retain(|tx| !tx.same_as(&transaction));
+            }
+            WalEntry::RaftTerm { term, voted_for } => {
+                state.raft.term = term;
+                state.raft.voted_for = voted_for;
+            }
+            WalEntry::RaftAppend { index, entries } => {
+                state.raft.replace_from(index, entries);
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the points a transaction left to its client, and the amount of transactions applied with it.
+    fn apply(
+        state: &mut Snapshot,
+        transaction: &Transaction,
+        points: Points,
+        committed: u64,
+    ) -> Result<(), String> {
+        // A pending transaction applied right before a crash is not in the queue anymore,
+        // and a transaction in doubt applied right before a crash is not in doubt anymore
+        state.pending.retain(|tx| !tx.same_as(transaction));
+        state.in_doubt.retain(|tx| !tx.same_as(transaction));
+        let record = state
+            .points
+            .entry(transaction.client_id)
+            .or_insert_with(SafePointRecord::new);
+        let mut record = record.0.lock().map_err(|_| "Failed to lock record")?;
+        record.settle(transaction);
+        *record.points.lock().map_err(|_| "Failed to lock points")? = points;
+        state.committed = state.committed.max(committed);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use points::{Message, Order, OrderAction};
+
+    use super::*;
+    use crate::server::transaction::TransactionState;
+
+    const ADDRESS: &str = "127.0.0.1:9000";
+
+    fn transaction(msg: Message) -> Transaction {
+        Transaction::new(ADDRESS.to_string(), &msg).unwrap()
+    }
+
+    fn fill(client_id: u64, points: u64) -> Transaction {
+        transaction(Message::CommitOrder(Order::new(
+            client_id,
+            OrderAction::FillPoints(points),
+        )))
+    }
+
+    fn apply_fill(available: u64) -> WalEntry {
+        WalEntry::Apply {
+            transaction: fill(1, 10),
+            points: Points(available, 0),
+            committed: 1,
+        }
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn balance(state: &Snapshot, client_id: u64) -> (u64, u64) {
+        let record = state.points[&client_id].0.lock().unwrap();
+        let points = record.points.lock().unwrap();
+        (points.0, points.1)
+    }
+
+    #[test]
+    fn test_recover_points_and_pending_transactions() {
+        let dir = test_dir("wal-test");
+
+        let filled = apply_fill(50);
+        let lock = transaction(Message::LockOrder(Order::new(
+            1,
+            OrderAction::UsePoints(20),
+        )));
+        let offline_fill = fill(2, 10);
+        let dequeued = fill(3, 5);
+
+        {
+            let (wal, recovered) = Wal::open(&dir, ADDRESS).unwrap();
+            assert!(recovered.points.is_empty());
+            wal.append(&filled).unwrap();
+            wal.append(&WalEntry::Reset(PointMap::new())).unwrap();
+            wal.append(&WalEntry::Adopt(7)).unwrap();
+            wal.append(&WalEntry::Apply {
+                transaction: lock.clone(),
+                points: Points(30, 20),
+                committed: 8,
+            })
+            .unwrap();
+            wal.append(&WalEntry::Enqueue(offline_fill.clone()))
+                .unwrap();
+            wal.append(&WalEntry::Enqueue(dequeued.clone())).unwrap();
+            wal.append(&WalEntry::Dequeue(dequeued)).unwrap();
+        }
+        // A crash in the middle of a write leaves a cut line
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.join("127_0_0_1_9000.wal"))
+            .unwrap();
+        write!(file, "{{\"lsn\":6,\"entry\":{{").unwrap();
+
+        let (_, recovered) = Wal::open(&dir, ADDRESS).unwrap();
+        assert_eq!(balance(&recovered, 1), (30, 20));
+        let record = recovered.points[&1].0.lock().unwrap();
+        assert!(record.reservations.contains_key(&lock.reservation.unwrap()));
+        assert_eq!(recovered.pending.len(), 1);
+        assert!(recovered.pending[0].same_as(&offline_fill));
+        // The adopted state applied 7 transactions, then the lock was applied
+        assert_eq!(recovered.committed, 8);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_recover_decisions_not_delivered() {
+        let dir = test_dir("wal-decisions-test");
+        let participants = vec!["127.0.0.1:9001".to_string()];
+        let committed = fill(1, 10);
+        let aborted = fill(2, 5);
+        let delivered = fill(3, 5);
+
+        {
+            let (wal, _) = Wal::open(&dir, ADDRESS).unwrap();
+            let decide = |transaction: &Transaction, state, points, committed| WalEntry::Decide {
+                decision: Decision::new(transaction.clone(), &state, participants.clone()),
+                points,
+                committed,
+            };
+            wal.append(&decide(
+                &committed,
+                TransactionState::Proceed,
+                Some(Points(10, 0)),
+                1,
+            ))
+            .unwrap();
+            wal.append(&decide(&aborted, TransactionState::Abort, None, 0))
+                .unwrap();
+            wal.append(&decide(
+                &delivered,
+                TransactionState::Proceed,
+                Some(Points(5, 0)),
+                2,
+            ))
+            .unwrap();
+            wal.append(&WalEntry::Delivered(delivered.clone())).unwrap();
+        }
+
+        let (_, recovered) = Wal::open(&dir, ADDRESS).unwrap();
+        // The coordinator applies a commit when it decides it
+        assert_eq!(balance(&recovered, 1), (10, 0));
+        assert_eq!(balance(&recovered, 3), (5, 0));
+        assert!(!recovered.points.contains_key(&2));
+        assert_eq!(recovered.decisions.len(), 2);
+        assert!(recovered.decisions[0].transaction.same_as(&committed));
+        assert!(recovered.decisions[0].commit);
+        assert!(recovered.decisions[1].transaction.same_as(&aborted));
+        assert!(!recovered.decisions[1].commit);
+        assert_eq!(recovered.committed, 2);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_replay_a_decision_in_the_snapshot() {
+        let dir = test_dir("wal-decision-snapshot-test");
+        let committed = fill(1, 10);
+        let decide = || WalEntry::Decide {
+            decision: Decision::new(
+                committed.clone(),
+                &TransactionState::Proceed,
+                vec!["127.0.0.1:9001".to_string()],
+            ),
+            points: Some(Points(10, 0)),
+            committed: 1,
+        };
+
+        {
+            let (wal, _) = Wal::open(&dir, ADDRESS).unwrap();
+            // Decided while the checkpoint captured the state, so both the snapshot and the log have it
+            wal.checkpoint(|| {
+                wal.append(&decide()).unwrap();
+                let mut captured = Snapshot::default();
+                WalEntry::replay(decide(), &mut captured)?;
+                Ok(captured)
+            })
+            .unwrap();
+        }
+
+        let (_, recovered) = Wal::open(&dir, ADDRESS).unwrap();
+        assert_eq!(balance(&recovered, 1), (10, 0));
+        assert_eq!(recovered.decisions.len(), 1);
+        assert!(recovered.decisions[0].transaction.same_as(&committed));
+        assert_eq!(recovered.committed, 1);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_replay_an_apply_in_the_snapshot() {
+        let dir = test_dir("wal-apply-snapshot-test");
+
+        {
+            let (wal, _) = Wal::open(&dir, ADDRESS).unwrap();
+            // Applied while the checkpoint captured the state, so both the snapshot and the log have it
+            wal.checkpoint(|| {
+                wal.append(&apply_fill(10)).unwrap();
+                let mut captured = Snapshot::default();
+                apply_fill(10).replay(&mut captured)?;
+                Ok(captured)
+            })
+            .unwrap();
+        }
+
+        let (_, recovered) = Wal::open(&dir, ADDRESS).unwrap();
+        assert_eq!(balance(&recovered, 1), (10, 0));
+        assert_eq!(recovered.committed, 1);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_recover_transactions_in_doubt() {
+        let dir = test_dir("wal-in-doubt-test");
+        let unknown = fill(1, 10);
+        let resolved = fill(2, 5);
+        let applied = fill(3, 5);
+
+        {
+            let (wal, _) = Wal::open(&dir, ADDRESS).unwrap();
+            wal.append(&WalEntry::InDoubt(unknown.clone())).unwrap();
+            wal.append(&WalEntry::InDoubt(resolved.clone())).unwrap();
+            wal.append(&WalEntry::InDoubt(applied.clone())).unwrap();
+            wal.append(&WalEntry::Resolved(resolved.clone())).unwrap();
+            // Crashed after applying the decision, before resolving it
+            wal.append(&WalEntry::Apply {
+                transaction: applied.clone(),
+                points: Points(5, 0),
+                committed: 1,
+            })
+            .unwrap();
+        }
+
+        let (_, recovered) = Wal::open(&dir, ADDRESS).unwrap();
+        assert_eq!(recovered.in_doubt.len(), 1);
+        assert!(recovered.in_doubt[0].same_as(&unknown));
+        assert_eq!(balance(&recovered, 3), (5, 0));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_recover_the_replicated_log() {
+        let dir = test_dir("wal-raft-test");
+        let entry = |term, points| LogEntry {
+            term,
+            transaction: Some(fill(1, points)),
+        };
+
+        {
+            let (wal, _) = Wal::open(&dir, ADDRESS).unwrap();
+            wal.append(&WalEntry::RaftTerm {
+                term: 1,
+                voted_for: Some(ADDRESS.to_string()),
+            })
+            .unwrap();
+            wal.append(&WalEntry::RaftAppend {
+                index: 1,
+                entries: vec![entry(1, 10), entry(1, 20)],
+            })
+            .unwrap();
+            // A new leader replaced the second entry
+            wal.append(&WalEntry::RaftTerm {
+                term: 2,
+                voted_for: None,
+            })
+            .unwrap();
+            wal.append(&WalEntry::RaftAppend {
+                index: 2,
+                entries: vec![entry(2, 30)],
+            })
+            .unwrap();
+        }
+
+        let (_, recovered) = Wal::open(&dir, ADDRESS).unwrap();
+        assert_eq!(recovered.raft.term, 2);
+        assert_eq!(recovered.raft.voted_for, None);
+        let entries: Vec<(u64, u64)> = recovered
+            .raft
+            .entries
+            .iter()
+            .map(|entry| (entry.term, entry.transaction.as_ref().unwrap().points))
+            .collect();
+        assert_eq!(entries, vec![(1, 10), (2, 30)]);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_fail_an_entry_that_can_not_be_written() {
+        assert!(Wal::failing().append(&apply_fill(10)).is_err());
+    }
+
+    #[test]
+    fn test_checkpoint_compacts_the_log() {
+        let dir = test_dir("wal-checkpoint-test");
+        let log_lines = || {
+            fs::read_to_string(dir.join("127_0_0_1_9000.wal"))
+                .unwrap()
+                .lines()
+                .count()
+        };
+        let snapshot_of = |available| {
+            let mut state = Snapshot::default();
+            apply_fill(available).replay(&mut state).unwrap();
+            Ok(state)
+        };
+
+        {
+            let (wal, _) = Wal::open(&dir, ADDRESS).unwrap();
+            wal.append(&apply_fill(10)).unwrap();
+            wal.checkpoint(|| snapshot_of(10)).unwrap();
+            assert_eq!(log_lines(), 1);
+
+            // Only the entries after the previous snapshot are kept
+            wal.append(&apply_fill(20)).unwrap();
+            wal.checkpoint(|| snapshot_of(20)).unwrap();
+            assert_eq!(log_lines(), 1);
+
+            wal.append(&apply_fill(30)).unwrap();
+        }
+
+        let (_, recovered) = Wal::open(&dir, ADDRESS).unwrap();
+        assert_eq!(recovered.lsn, 2);
+        assert_eq!(balance(&recovered, 1), (30, 0));
+
+        // If the latest snapshot can not be read, the previous one and the log are enough
+        fs::write(dir.join("127_0_0_1_9000.snapshot"), "{").unwrap();
+        let (_, recovered) = Wal::open(&dir, ADDRESS).unwrap();
+        assert_eq!(recovered.lsn, 1);
+        assert_eq!(balance(&recovered, 1), (30, 0));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}